@@ -1,24 +1,32 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, net::IpAddr, path::Path};
 
 use futures_util::stream::TryStreamExt;
-use maxminddb_writer::paths::IpAddrWithMask;
+use maxminddb_writer::{paths::IpAddrWithMask, Database};
 use tokio::{io::AsyncBufReadExt, sync::mpsc};
 use tokio_util::io::StreamReader;
 
 const OUTPUT_PATH: &str = "ip2country.mmdb";
 
+/// Sources used when no URLs are given on the command line.
+const DEFAULT_SOURCE_URLS: &[&str] = &[
+    "http://localhost:8080/list/afrinic.txt",
+    "http://localhost:8080/list/apnic.txt",
+    "http://localhost:8080/list/arin.txt",
+    "http://localhost:8080/list/lacnic.txt",
+    "http://localhost:8080/list/ripencc.txt",
+];
+
+/// Sends the networks allocated in the list at `url` to `sender`, returning how many there
+/// were.
 async fn load_entries_from_url(
-    url: &str,
+    url: String,
     sender: mpsc::Sender<(IpAddrWithMask, String)>,
-) -> anyhow::Result<()> {
-    let response = reqwest::get(url).await?;
-    let mut reader = StreamReader::new(
-        response
-            .bytes_stream()
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
-    );
+) -> anyhow::Result<usize> {
+    let response = reqwest::get(&url).await?.error_for_status()?;
+    let mut reader = StreamReader::new(response.bytes_stream().map_err(std::io::Error::other));
 
     let mut line = String::new();
+    let mut count = 0;
     loop {
         // read a line
         line.clear();
@@ -26,37 +34,75 @@ async fn load_entries_from_url(
             break;
         }
 
-        // skip comments
-        if line.starts_with('#') {
+        let Some((networks, country_code)) = parse_line(&line) else {
             continue;
+        };
+        count += networks.len();
+        for ip_with_mask in networks {
+            sender.send((ip_with_mask, country_code.clone())).await?;
         }
+    }
 
-        let parts = line.split('|').collect::<Vec<_>>();
+    Ok(count)
+}
 
-        // skip lines that are not IP allocations
-        if parts.len() != 8
-            || parts[1] == "*"
-            || parts[3] == "*"
-            || (parts[2] != "ipv4" && parts[2] != "ipv6")
-        {
-            continue;
-        }
+/// Parses an allocation line of an RIR statistics file into the allocated networks and their
+/// country code. Returns `None` for comments, summaries and other lines to skip.
+fn parse_line(line: &str) -> Option<(Vec<IpAddrWithMask>, String)> {
+    // skip comments
+    if line.starts_with('#') {
+        return None;
+    }
 
-        // extract country code
-        let country_code = parts[1].to_string();
-        if country_code == "ZZ" {
-            continue;
-        }
+    let parts = line.trim_end().split('|').collect::<Vec<_>>();
 
-        // extract IP address and mask
-        let Ok(ip) = parts[3].parse::<std::net::IpAddr>() else { continue; };
-        let Ok(count) = parts[4].parse::<usize>() else { continue; };
-        for ip_with_mask in IpAddrWithMask::from_count(ip, count) {
-            sender.send((ip_with_mask, country_code.clone())).await?;
-        }
+    // skip lines that are not IP allocations
+    if parts.len() != 8
+        || parts[1] == "*"
+        || parts[3] == "*"
+        || (parts[2] != "ipv4" && parts[2] != "ipv6")
+    {
+        return None;
     }
 
-    Ok(())
+    // extract country code
+    let country_code = parts[1].to_string();
+    if country_code == "ZZ" {
+        return None;
+    }
+
+    // extract IP address and mask: IPv4 lines have the number of addresses, IPv6 lines the
+    // prefix length
+    let ip = parts[3].parse::<IpAddr>().ok()?;
+    let networks = match ip {
+        IpAddr::V4(_) => IpAddrWithMask::from_count(ip, parts[4].parse().ok()?),
+        IpAddr::V6(_) => {
+            let mask = parts[4].parse::<u8>().ok().filter(|mask| *mask <= 128)?;
+            vec![IpAddrWithMask::new(ip, mask)]
+        }
+    };
+    Some((networks, country_code))
+}
+
+/// Builds an IPv6 database (with IPv4 networks mapped into it) from the allocations.
+fn build_database(
+    entries: impl IntoIterator<Item = (IpAddrWithMask, String)>,
+) -> anyhow::Result<Database> {
+    let mut db = Database::new_v6();
+    db.metadata.database_type = "ip2country".to_string();
+    let mut country_refs = HashMap::new();
+    for (ip_with_mask, country_code) in entries {
+        let country_code_ref = match country_refs.get(&country_code) {
+            Some(&country_code_ref) => country_code_ref,
+            None => {
+                let country_code_ref = db.insert_value(&country_code)?;
+                country_refs.insert(country_code, country_code_ref);
+                country_code_ref
+            }
+        };
+        db.insert_node(ip_with_mask, country_code_ref);
+    }
+    Ok(db)
 }
 
 fn validate(path: impl AsRef<Path>, entries: &[(IpAddrWithMask, String)]) -> anyhow::Result<()> {
@@ -72,45 +118,134 @@ fn validate(path: impl AsRef<Path>, entries: &[(IpAddrWithMask, String)]) -> any
     Ok(())
 }
 
+/// Downloads the allocation lists from the URLs given as arguments (or the default ones) and
+/// writes the database to `OUTPUT_PATH`.
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (tx, mut rx) = mpsc::channel(100);
-
-    for url in [
-        "http://localhost:8080/list/afrinic.txt",
-        "http://localhost:8080/list/apnic.txt",
-        "http://localhost:8080/list/arin.txt",
-        "http://localhost:8080/list/lacnic.txt",
-        "http://localhost:8080/list/ripencc.txt",
-    ] {
-        tokio::spawn(load_entries_from_url(url, tx.clone()));
+    let mut urls = std::env::args().skip(1).collect::<Vec<_>>();
+    if urls.is_empty() {
+        urls = DEFAULT_SOURCE_URLS
+            .iter()
+            .map(|url| url.to_string())
+            .collect();
     }
+
+    let (tx, mut rx) = mpsc::channel(100);
+    let tasks = urls
+        .into_iter()
+        .map(|url| {
+            let task = tokio::spawn(load_entries_from_url(url.clone(), tx.clone()));
+            (url, task)
+        })
+        .collect::<Vec<_>>();
     drop(tx);
 
-    let mut db = maxminddb_writer::Database::default();
-    let mut country_refs = HashMap::new();
     let mut validation_data = Vec::new();
+    while let Some(entry) = rx.recv().await {
+        validation_data.push(entry);
+    }
 
-    while let Some((ip_with_mask, country_code)) = rx.recv().await {
-        match ip_with_mask.addr {
-            std::net::IpAddr::V4(_) => {
-                validation_data.push((ip_with_mask, country_code.clone()));
-                let country_code_ref =
-                    *country_refs.entry(country_code.clone()).or_insert_with(|| {
-                        db.insert_value(country_code.clone())
-                            .expect("failed to insert country code")
-                    });
-                db.insert_node(ip_with_mask, country_code_ref);
-            }
-            std::net::IpAddr::V6(addr) => {
-                log::info!("skipping IPv6 address {}", addr);
+    // a database built from only some of the sources would be silently wrong
+    let mut failed = Vec::new();
+    for (url, task) in tasks {
+        match task
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result)
+        {
+            Ok(count) => log::info!("loaded {} networks from {}", count, url),
+            Err(err) => {
+                log::error!("failed to load {}: {:#}", url, err);
+                failed.push(url);
             }
         }
     }
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!("failed to load {}", failed.join(", ")).into());
+    }
+
+    let db = build_database(validation_data.iter().cloned())?;
 
-    db.write_to(std::fs::File::create(OUTPUT_PATH)?)?;
+    db.write_to_file(OUTPUT_PATH)?;
 
     validate(OUTPUT_PATH, &validation_data)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("# comment\n"), None);
+        assert_eq!(
+            parse_line("2|apnic|20240101|1|19830613|20240101|+1000\n"),
+            None
+        );
+        assert_eq!(parse_line("apnic|*|ipv4|*|1|summary\n"), None);
+        assert_eq!(parse_line("apnic|JP|asn|173|1|20020801|allocated\n"), None);
+        assert_eq!(
+            parse_line("ripencc|ZZ|ipv4|2.56.0.0|1024|20190624|reserved|\n"),
+            None
+        );
+
+        let (networks, country_code) =
+            parse_line("apnic|AU|ipv4|1.0.0.0|768|20110811|assigned|A91872ED\n").unwrap();
+        assert_eq!(country_code, "AU");
+        assert_eq!(
+            networks,
+            ["1.0.0.0/23", "1.0.2.0/24"].map(|network| network.parse::<IpAddrWithMask>().unwrap())
+        );
+
+        let (networks, country_code) =
+            parse_line("apnic|JP|ipv6|2001:200::|35|19990813|allocated|A916D3F2\n").unwrap();
+        assert_eq!(country_code, "JP");
+        assert_eq!(
+            networks,
+            ["2001:200::/35".parse::<IpAddrWithMask>().unwrap()]
+        );
+        assert_eq!(
+            parse_line("apnic|JP|ipv6|2001:200::|129|19990813|allocated|A916D3F2\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_build_database() {
+        let lines = [
+            "apnic|AU|ipv4|1.0.0.0|256|20110811|assigned|A91872ED",
+            "apnic|JP|ipv6|2001:200::|35|19990813|allocated|A916D3F2",
+            "ripencc|PL|ipv4|5.172.160.0|8192|20120529|allocated|",
+            "ripencc|PL|ipv6|2a00:f40::|32|20080523|allocated|",
+        ];
+        let entries = lines
+            .iter()
+            .filter_map(|line| parse_line(line))
+            .flat_map(|(networks, country_code)| {
+                networks
+                    .into_iter()
+                    .map(move |network| (network, country_code.clone()))
+            })
+            .collect::<Vec<_>>();
+        let db = build_database(entries).unwrap();
+        let mut raw_db = Vec::new();
+        db.write_to(&mut raw_db).unwrap();
+
+        let reader = maxminddb::Reader::from_source(raw_db).unwrap();
+        for (addr, expected) in [
+            ("1.0.0.1", "AU"),
+            ("5.172.161.1", "PL"),
+            ("2001:200::1", "JP"),
+            ("2a00:f40:1::1", "PL"),
+        ] {
+            let country_code: String = reader.lookup(addr.parse().unwrap()).unwrap();
+            assert_eq!(country_code, expected);
+        }
+        assert!(reader.lookup::<String>("1.0.1.1".parse().unwrap()).is_err());
+        assert!(reader
+            .lookup::<String>("2001:300::1".parse().unwrap())
+            .is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use maxminddb_writer::{paths::IpAddrWithMask, Database};
+
+const ENTRIES: u32 = 1_000_000;
+
+fn networks() -> impl Iterator<Item = IpAddrWithMask> {
+    // spread the /32s over the whole address space
+    (0..ENTRIES).map(|i| {
+        let addr = Ipv4Addr::from(i.wrapping_mul(2_654_435_761));
+        IpAddrWithMask::new(IpAddr::V4(addr), 32)
+    })
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("1M entries");
+    group.sample_size(10);
+    group.bench_function("insert_node", |b| {
+        b.iter(|| {
+            let mut db = Database::default();
+            let data = db.insert_value("data").unwrap();
+            for network in networks() {
+                db.insert_node(network, data);
+            }
+            black_box(db)
+        })
+    });
+    group.bench_function("insert_node with_capacity", |b| {
+        b.iter(|| {
+            // the top ~20 levels are full, each /32 adds ~12 nodes below them
+            let mut db = Database::with_capacity(ENTRIES as usize * 14, 16);
+            let data = db.insert_value("data").unwrap();
+            for network in networks() {
+                db.insert_node(network, data);
+            }
+            black_box(db)
+        })
+    });
+    group.bench_function("insert_nodes", |b| {
+        b.iter(|| {
+            let mut db = Database::default();
+            let data = db.insert_value("data").unwrap();
+            db.insert_nodes(networks().map(|network| (network, data)));
+            black_box(db)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, insert);
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use maxminddb_writer::Database;
+
+const COUNTRY_CODES: [&str; 8] = ["US", "PL", "DE", "FR", "GB", "JP", "CN", "BR"];
+
+fn insert_str(c: &mut Criterion) {
+    c.bench_function("insert_value &str", |b| {
+        let mut db = Database::default();
+        b.iter(|| {
+            for code in COUNTRY_CODES {
+                black_box(db.insert_value(black_box(code)).unwrap());
+            }
+        })
+    });
+    c.bench_function("insert_value String", |b| {
+        let mut db = Database::default();
+        b.iter(|| {
+            for code in COUNTRY_CODES {
+                black_box(db.insert_value(black_box(code.to_string())).unwrap());
+            }
+        })
+    });
+}
+
+fn insert_map(c: &mut Criterion) {
+    c.bench_function("insert_value HashMap<&str, &str>", |b| {
+        let mut db = Database::default();
+        b.iter(|| {
+            for code in COUNTRY_CODES {
+                let mut map = HashMap::new();
+                map.insert("iso_code", code);
+                black_box(db.insert_value(black_box(&map)).unwrap());
+            }
+        })
+    });
+    c.bench_function("insert_value HashMap<String, String>", |b| {
+        let mut db = Database::default();
+        b.iter(|| {
+            for code in COUNTRY_CODES {
+                let mut map = HashMap::new();
+                map.insert("iso_code".to_string(), code.to_string());
+                black_box(db.insert_value(black_box(&map)).unwrap());
+            }
+        })
+    });
+}
+
+criterion_group!(benches, insert_str, insert_map);
+criterion_main!(benches);
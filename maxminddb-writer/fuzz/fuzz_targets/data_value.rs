@@ -0,0 +1,84 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use maxminddb_writer::{paths::IpAddrWithMask, value::DataValue, Database};
+
+/// `DataValue` with a derived `Arbitrary`, converted to the real thing before writing.
+#[derive(Arbitrary, Debug)]
+enum Value {
+    String(String),
+    Double(f64),
+    Bytes(Vec<u8>),
+    // lengths around the control byte size encoding boundaries, which short inputs can't reach
+    LongString(u8, u32),
+    Uint16(u16),
+    Uint32(u32),
+    Map(Vec<(String, Value)>),
+    Int32(i32),
+    Uint64(u64),
+    Uint128(u128),
+    Array(Vec<Value>),
+    Boolean(bool),
+    Float(f32),
+}
+
+impl From<Value> for DataValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::String(value) => DataValue::String(value),
+            Value::Double(value) => DataValue::Double(value),
+            Value::Bytes(value) => DataValue::Bytes(value),
+            Value::LongString(byte, len) => DataValue::String(
+                char::from(byte % 128)
+                    .to_string()
+                    .repeat(len as usize % 70_000),
+            ),
+            Value::Uint16(value) => DataValue::Uint16(value),
+            Value::Uint32(value) => DataValue::Uint32(value),
+            Value::Map(entries) => DataValue::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect(),
+            ),
+            Value::Int32(value) => DataValue::Int32(value),
+            Value::Uint64(value) => DataValue::Uint64(value),
+            Value::Uint128(value) => DataValue::Uint128(value),
+            Value::Array(values) => DataValue::Array(values.into_iter().map(Into::into).collect()),
+            Value::Boolean(value) => DataValue::Boolean(value),
+            Value::Float(value) => DataValue::Float(value),
+        }
+    }
+}
+
+/// NaN never equals itself, compare floats by their bits instead.
+fn same(a: &DataValue, b: &DataValue) -> bool {
+    match (a, b) {
+        (DataValue::Double(a), DataValue::Double(b)) => a.to_bits() == b.to_bits(),
+        (DataValue::Float(a), DataValue::Float(b)) => a.to_bits() == b.to_bits(),
+        (DataValue::Map(a), DataValue::Map(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|((a_key, a), (b_key, b))| a_key == b_key && same(a, b))
+        }
+        (DataValue::Array(a), DataValue::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b))
+        }
+        _ => a == b,
+    }
+}
+
+fuzz_target!(|value: Value| {
+    let value = DataValue::from(value);
+    let mut db = Database::default();
+    let data = db.insert_value(&value).unwrap();
+    db.insert_node("0.0.0.0/0".parse::<IpAddrWithMask>().unwrap(), data);
+    let mut raw_db = Vec::new();
+    db.write_to(&mut raw_db).unwrap();
+
+    let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+    let read: DataValue = reader.lookup([1, 2, 3, 4].into()).unwrap();
+    assert!(same(&read, &value), "wrote {:?}, read {:?}", value, read);
+});
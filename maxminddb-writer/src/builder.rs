@@ -0,0 +1,130 @@
+use serde::Serialize;
+
+use crate::{
+    metadata::IpVersion,
+    paths::IntoBitPath,
+    serializer::{self, Error},
+    Database,
+};
+
+/// Builds a `Database` from networks and their values, validating the metadata at the end.
+///
+/// The IP version has to be set before adding networks as it decides where they're placed,
+/// see `Database::insert_node`.
+///
+/// ```
+/// # use maxminddb_writer::{metadata::IpVersion, paths::IpAddrWithMask, DatabaseBuilder};
+/// let mut builder = DatabaseBuilder::new()
+///     .ip_version(IpVersion::V6)
+///     .database_type("Country");
+/// builder
+///     .add("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), "AU")?
+///     .add("2001:db8::/32".parse::<IpAddrWithMask>().unwrap(), "PL")?;
+/// let mut bytes = Vec::new();
+/// builder.write_to(&mut bytes)?;
+/// # Ok::<(), maxminddb_writer::serializer::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct DatabaseBuilder {
+    db: Database,
+}
+
+impl DatabaseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ip_version(mut self, ip_version: IpVersion) -> Self {
+        self.db.metadata.ip_version = ip_version;
+        self
+    }
+
+    pub fn database_type(mut self, database_type: impl Into<String>) -> Self {
+        self.db.metadata.database_type = database_type.into();
+        self
+    }
+
+    /// Makes addresses in the network described by `path` resolve to `value`. Identical
+    /// values are stored once.
+    pub fn add(
+        &mut self,
+        path: impl IntoBitPath,
+        value: impl Serialize,
+    ) -> Result<&mut Self, Error> {
+        let data = self.db.insert_value(value)?;
+        self.db.insert_node(path, data);
+        Ok(self)
+    }
+
+    /// Validates the metadata (see `Metadata::validate`) and returns the database.
+    pub fn build(self) -> Result<Database, Error> {
+        self.db.metadata.validate()?;
+        Ok(self.db)
+    }
+
+    /// Builds the database and writes it to `writer`, see `Database::write_to`.
+    pub fn write_to<W: std::io::Write>(self, writer: W) -> Result<W, serializer::Error> {
+        self.build()?.write_to(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{metadata::MetadataError, paths::IpAddrWithMask};
+
+    #[derive(Serialize)]
+    struct Country {
+        iso_code: &'static str,
+    }
+
+    #[test]
+    fn test_builder() {
+        let network = |s: &str| s.parse::<IpAddrWithMask>().unwrap();
+        let mut builder = DatabaseBuilder::new()
+            .ip_version(IpVersion::V6)
+            .database_type("Test-Country");
+        builder
+            .add(network("1.0.0.0/8"), Country { iso_code: "AU" })
+            .unwrap()
+            .add(network("1.2.0.0/16"), Country { iso_code: "CN" })
+            .unwrap()
+            .add(network("2001:db8::/32"), Country { iso_code: "AU" })
+            .unwrap();
+        let raw_db = builder.write_to(Vec::new()).unwrap();
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.database_type, "Test-Country");
+        assert_eq!(reader.metadata.ip_version, 6);
+        let iso_code = |addr: &str| {
+            reader
+                .lookup::<std::collections::BTreeMap<String, String>>(addr.parse().unwrap())
+                .ok()
+                .map(|country| country["iso_code"].clone())
+        };
+        assert_eq!(iso_code("1.1.1.1").as_deref(), Some("AU"));
+        assert_eq!(iso_code("1.2.3.4").as_deref(), Some("CN"));
+        assert_eq!(iso_code("2001:db8::1").as_deref(), Some("AU"));
+        assert_eq!(iso_code("3.0.0.0"), None);
+    }
+
+    #[test]
+    fn test_builder_dedup() {
+        let mut builder = DatabaseBuilder::new().database_type("Test");
+        for network in ["1.0.0.0/8", "2.0.0.0/8", "3.0.0.0/8"] {
+            builder
+                .add(network.parse::<IpAddrWithMask>().unwrap(), "same")
+                .unwrap();
+        }
+        let db = builder.build().unwrap();
+        assert_eq!(db.stats().unwrap().data_section_size, "same".len() + 1);
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        assert_eq!(
+            DatabaseBuilder::new().build().unwrap_err(),
+            Error::InvalidMetadata(MetadataError::EmptyDatabaseType)
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::{
+    paths::{IpAddrWithMask, IpAddrWithMaskParseError},
+    serializer, DataRef, Database,
+};
+
+#[derive(Debug, Error)]
+pub enum CsvImportError {
+    #[error("CSV error: {0}")]
+    Csv(#[from] ::csv::Error),
+    #[error("missing column {0}")]
+    MissingColumn(&'static str),
+    #[error("invalid network on line {line}: {source}")]
+    InvalidNetwork {
+        line: u64,
+        source: IpAddrWithMaskParseError,
+    },
+    #[error("invalid geoname ID {value:?} on line {line}")]
+    InvalidGeonameId { line: u64, value: String },
+    #[error("failed to insert data: {0}")]
+    Insert(#[from] serializer::Error),
+}
+
+impl Database {
+    /// Inserts the networks of a GeoLite2 blocks CSV file (e.g.
+    /// `GeoLite2-Country-Blocks-IPv4.csv`), with the header row, read from `reader`.
+    ///
+    /// `geoname_lookup` gives the value for a geoname ID, it's called once per ID. Rows
+    /// without a `geoname_id` use their `registered_country_geoname_id`; rows without either
+    /// or for which `geoname_lookup` returns `None` are skipped. Other columns are ignored.
+    pub fn load_geolite2_blocks_csv<R, T, F>(
+        &mut self,
+        reader: R,
+        mut geoname_lookup: F,
+    ) -> Result<(), CsvImportError>
+    where
+        R: std::io::Read,
+        T: serde::Serialize,
+        F: FnMut(u32) -> Option<T>,
+    {
+        let mut reader = ::csv::Reader::from_reader(reader);
+        let headers = reader.headers()?;
+        let column = |name: &'static str| {
+            headers
+                .iter()
+                .position(|header| header == name)
+                .ok_or(CsvImportError::MissingColumn(name))
+        };
+        let network_column = column("network")?;
+        let geoname_columns = [
+            column("geoname_id")?,
+            column("registered_country_geoname_id")?,
+        ];
+
+        let mut values: HashMap<u32, Option<DataRef>> = HashMap::new();
+        let mut entries = Vec::new();
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |position| position.line());
+            let Some(geoname_id) = geoname_columns
+                .iter()
+                .filter_map(|&column| record.get(column))
+                .find(|value| !value.is_empty())
+            else {
+                continue;
+            };
+            let geoname_id = geoname_id
+                .parse()
+                .map_err(|_| CsvImportError::InvalidGeonameId {
+                    line,
+                    value: geoname_id.to_string(),
+                })?;
+            let data = match values.get(&geoname_id) {
+                Some(&data) => data,
+                None => {
+                    let data = geoname_lookup(geoname_id)
+                        .map(|value| self.insert_value(value))
+                        .transpose()?;
+                    values.insert(geoname_id, data);
+                    data
+                }
+            };
+            let Some(data) = data else {
+                continue;
+            };
+            let network = record
+                .get(network_column)
+                .unwrap_or_default()
+                .parse::<IpAddrWithMask>()
+                .map_err(|source| CsvImportError::InvalidNetwork { line, source })?;
+            entries.push((network, data));
+        }
+        self.insert_nodes(entries);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKS: &str = "\
+network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider
+1.0.0.0/24,2077456,2077456,,0,0
+1.0.1.0/24,1814991,1814991,,0,0
+1.0.2.0/23,,1814991,,0,0
+1.0.4.0/22,6252001,6252001,,0,0
+";
+
+    fn country(geoname_id: u32) -> Option<&'static str> {
+        match geoname_id {
+            2077456 => Some("AU"),
+            1814991 => Some("CN"),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_load_geolite2_blocks_csv() {
+        let mut db = Database::default();
+        db.load_geolite2_blocks_csv(BLOCKS.as_bytes(), country)
+            .unwrap();
+        let raw_db = db.to_vec().unwrap();
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let lookup = |addr: [u8; 4]| reader.lookup::<&str>(addr.into()).ok();
+        assert_eq!(lookup([1, 0, 0, 1]), Some("AU"));
+        assert_eq!(lookup([1, 0, 1, 1]), Some("CN"));
+        // only the registered country is known
+        assert_eq!(lookup([1, 0, 3, 1]), Some("CN"));
+        // no value for the geoname ID
+        assert_eq!(lookup([1, 0, 4, 1]), None);
+        assert_eq!(db.data.len(), 6);
+    }
+
+    #[test]
+    fn test_load_geolite2_blocks_csv_errors() {
+        let mut db = Database::default();
+        assert!(matches!(
+            db.load_geolite2_blocks_csv("network,geoname_id\n".as_bytes(), country),
+            Err(CsvImportError::MissingColumn(
+                "registered_country_geoname_id"
+            ))
+        ));
+
+        let invalid_network =
+            "network,geoname_id,registered_country_geoname_id\n1.0.0.0/33,2077456,\n";
+        assert!(matches!(
+            db.load_geolite2_blocks_csv(invalid_network.as_bytes(), country),
+            Err(CsvImportError::InvalidNetwork { line: 2, .. })
+        ));
+
+        let invalid_id = "network,geoname_id,registered_country_geoname_id\n1.0.0.0/24,AU,\n";
+        assert!(matches!(
+            db.load_geolite2_blocks_csv(invalid_id.as_bytes(), country),
+            Err(CsvImportError::InvalidGeonameId { line: 2, .. })
+        ));
+    }
+}
@@ -1,37 +1,623 @@
-use crate::serializer::{Error, Serializer};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::{File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
-// TODO: make sure it's possible to check if dataref points to selected datastore
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+use crate::serializer::{Error, Options, Serializer};
+
+/// Number of zero bytes between the search tree and the data section.
+pub(crate) const DATA_SECTION_SEPARATOR_LEN: usize = 16;
+
+/// Reference to a value inserted into a database, only valid for that database.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct DataRef {
-    pub(crate) index: usize,
+    // offset in the data section, pointers can't address more than 32 bits anyway
+    pub(crate) index: u32,
+    // id of the datastore the value was inserted into
+    pub(crate) store: u32,
 }
 
 impl DataRef {
     pub fn data_section_offset(&self, node_count: usize) -> usize {
-        node_count + 16 + self.index
+        node_count + DATA_SECTION_SEPARATOR_LEN + self.index as usize
+    }
+}
+
+static NEXT_STORE_ID: AtomicU32 = AtomicU32::new(0);
+
+/// A value which is written as a pointer to data inserted before instead of a copy of it, so
+/// records can share sub-structures (e.g. many records pointing at the same map).
+///
+/// The pointed to data has to be inserted into the same database (inserting a value with a
+/// `Pointer` to another database's data panics) and can't be a `Pointer` itself. Other
+/// serializers see an integer identifying the pointed to data.
+///
+/// ```
+/// # use maxminddb_writer::{Database, Pointer};
+/// # use std::collections::HashMap;
+/// let mut db = Database::default();
+/// let network = db.insert_value(HashMap::from([("name", "shared network")])).unwrap();
+/// let asn = db
+///     .insert_value(HashMap::from([("network", Pointer(network))]))
+///     .unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Pointer(pub DataRef);
+
+impl serde::Serialize for Pointer {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // the store id goes along so the serializer can check it, see `Options::store`
+        serializer.serialize_newtype_struct(
+            crate::serializer::POINTER_STRUCT_NAME,
+            &((self.0.store as u64) << 32 | self.0.index as u64),
+        )
+    }
+}
+
+// how much data is buffered in memory before it's written out to the spill file
+const SPILL_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+enum Storage {
+    Memory(Vec<u8>),
+    File {
+        file: File,
+        // number of bytes already written to the file
+        written: usize,
+        pending: Vec<u8>,
+    },
+}
+
+impl Storage {
+    fn len(&self) -> usize {
+        match self {
+            Storage::Memory(store) => store.len(),
+            Storage::File {
+                written, pending, ..
+            } => written + pending.len(),
+        }
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        match self {
+            Storage::Memory(store) => store.extend_from_slice(bytes),
+            Storage::File {
+                file,
+                written,
+                pending,
+            } => {
+                pending.extend_from_slice(bytes);
+                if pending.len() >= SPILL_CHUNK_SIZE {
+                    // not the end of the file, which can have leftovers from before `clear`
+                    file.seek(SeekFrom::Start(*written as u64))?;
+                    file.write_all(pending)?;
+                    *written += pending.len();
+                    pending.clear();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes everything stored, keeping the allocated memory (or the file).
+    fn clear(&mut self) {
+        match self {
+            Storage::Memory(store) => store.clear(),
+            Storage::File {
+                written, pending, ..
+            } => {
+                *written = 0;
+                pending.clear();
+            }
+        }
+    }
+
+    /// Number of bytes which can be stored without reallocating, unlimited for files.
+    fn capacity(&self) -> usize {
+        match self {
+            Storage::Memory(store) => store.capacity(),
+            Storage::File { .. } => usize::MAX,
+        }
+    }
+
+    fn contains_at(&self, index: usize, bytes: &[u8]) -> Result<bool, std::io::Error> {
+        match self {
+            Storage::Memory(store) => Ok(store.get(index..index + bytes.len()) == Some(bytes)),
+            Storage::File { .. } => {
+                if index + bytes.len() > self.len() {
+                    return Ok(false);
+                }
+                Ok(self.read_at(index, bytes.len())? == bytes)
+            }
+        }
+    }
+
+    /// Reads `len` stored bytes starting at `index`, which have to be within the storage.
+    fn read_at(&self, index: usize, len: usize) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Storage::Memory(store) => Ok(store[index..index + len].to_vec()),
+            Storage::File {
+                file,
+                written,
+                pending,
+            } => {
+                let mut stored = vec![0; len];
+                let from_file = len.min(written.saturating_sub(index));
+                if from_file > 0 {
+                    let mut file = file;
+                    file.seek(SeekFrom::Start(index as u64))?;
+                    file.read_exact(&mut stored[..from_file])?;
+                }
+                if from_file < len {
+                    let pending_start = index + from_file - written;
+                    stored[from_file..]
+                        .copy_from_slice(&pending[pending_start..pending_start + len - from_file]);
+                }
+                Ok(stored)
+            }
+        }
+    }
+
+    fn write_to<W: std::io::Write>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        match self {
+            Storage::Memory(store) => writer.write_all(store),
+            Storage::File {
+                file,
+                written,
+                pending,
+            } => {
+                let mut file = file;
+                file.seek(SeekFrom::Start(0))?;
+                std::io::copy(&mut file.take(*written as u64), writer)?;
+                writer.write_all(pending)
+            }
+        }
     }
 }
 
-#[derive(Debug, Default)]
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::Memory(Vec::new())
+    }
+}
+
+#[derive(Debug)]
 pub(crate) struct Datastore {
-    store: Vec<u8>,
+    // stamped on the `DataRef`s of this datastore to catch them being used with another one
+    id: u32,
+    storage: Storage,
+    // hash of serialized value -> locations (and lengths) of values with that hash,
+    // used to deduplicate values without keeping a second copy of them in memory
+    refs: HashMap<u64, Vec<(DataRef, usize)>>,
+    // offsets of the stored values in insertion (and so ascending) order, each value ends
+    // where the next one starts
+    offsets: Vec<u32>,
+    scratch: Vec<u8>,
+}
+
+impl Default for Datastore {
+    fn default() -> Self {
+        Self {
+            id: NEXT_STORE_ID.fetch_add(1, Ordering::Relaxed),
+            storage: Storage::default(),
+            refs: HashMap::new(),
+            offsets: Vec::new(),
+            scratch: Vec::new(),
+        }
+    }
 }
 
 impl Datastore {
+    /// Creates an in-memory datastore with room for `capacity` bytes of serialized values
+    /// before reallocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            storage: Storage::Memory(Vec::with_capacity(capacity)),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a datastore which keeps the serialized values in the file at `path`
+    /// (created or truncated) instead of in memory.
+    pub fn spilling_to(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            storage: Storage::File {
+                file,
+                written: 0,
+                pending: Vec::with_capacity(SPILL_CHUNK_SIZE),
+            },
+            ..Default::default()
+        })
+    }
+
     pub fn len(&self) -> usize {
-        self.store.len()
+        self.storage.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.storage.capacity()
+    }
+
+    /// Removes all values, keeping the allocated memory. `DataRef`s returned before are no
+    /// longer valid for this datastore.
+    pub fn clear(&mut self) {
+        self.id = NEXT_STORE_ID.fetch_add(1, Ordering::Relaxed);
+        self.storage.clear();
+        self.refs.clear();
+        self.offsets.clear();
+    }
+
+    /// Panics if `data_ref` wasn't returned by this datastore.
+    pub fn check_ref(&self, data_ref: DataRef) {
+        assert_eq!(
+            data_ref.store, self.id,
+            "DataRef used with a different database than the one it was inserted into"
+        );
+    }
+
+    pub fn insert<T: serde::Serialize>(
+        &mut self,
+        value: T,
+        options: Options,
+    ) -> Result<DataRef, Error> {
+        self.insert_tracked(value, options)
+            .map(|(data_ref, _)| data_ref)
+    }
+
+    /// Like `insert`, but also returns whether the value is new (`false` if an identical
+    /// value was stored before).
+    pub fn insert_tracked<T: serde::Serialize>(
+        &mut self,
+        value: T,
+        options: Options,
+    ) -> Result<(DataRef, bool), Error> {
+        // reuse the scratch buffer so inserting doesn't allocate unless the value is new
+        let mut buf = std::mem::take(&mut self.scratch);
+        buf.clear();
+        let result = value
+            .serialize(&mut Serializer::with_options(
+                &mut buf,
+                self.options(options),
+            ))
+            .and_then(|_| self.insert_serialized(&buf));
+        self.scratch = buf;
+        result
+    }
+
+    /// Inserts bytes that are already MaxMind DB encoded.
+    ///
+    /// The caller is responsible for `bytes` being exactly one valid data structure.
+    pub fn insert_raw(&mut self, bytes: &[u8]) -> Result<DataRef, Error> {
+        self.insert_serialized(bytes).map(|(data_ref, _)| data_ref)
+    }
+
+    /// Returns where `value` is stored if it was inserted before, without inserting it.
+    pub fn get_ref<T: serde::Serialize>(
+        &self,
+        value: T,
+        options: Options,
+    ) -> Result<Option<DataRef>, Error> {
+        let mut buf = Vec::new();
+        value.serialize(&mut Serializer::with_options(
+            &mut buf,
+            self.options(options),
+        ))?;
+        Ok(self.find_serialized(&buf, hash_serialized(&buf))?)
     }
 
-    pub fn insert<T: serde::Serialize>(&mut self, value: T) -> Result<DataRef, Error> {
+    /// `options` for serializing values into this datastore, with `Pointer`s checked to
+    /// point into it.
+    fn options(&self, options: Options) -> Options {
+        Options {
+            store: Some(self.id),
+            ..options
+        }
+    }
+
+    fn find_serialized(&self, bytes: &[u8], hash: u64) -> Result<Option<DataRef>, std::io::Error> {
+        if let Some(candidates) = self.refs.get(&hash) {
+            for &(data_ref, len) in candidates {
+                if len == bytes.len() && self.storage.contains_at(data_ref.index as usize, bytes)? {
+                    return Ok(Some(data_ref));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn insert_serialized(&mut self, bytes: &[u8]) -> Result<(DataRef, bool), Error> {
+        let hash = hash_serialized(bytes);
+        if let Some(data_ref) = self.find_serialized(bytes, hash)? {
+            return Ok((data_ref, false));
+        }
+
         let data_ref = DataRef {
-            index: self.store.len(),
+            index: self
+                .storage
+                .len()
+                .try_into()
+                .map_err(|_| Error::DatabaseTooLarge)?,
+            store: self.id,
         };
-        value
-            .serialize(&mut Serializer::new(&mut self.store))
-            .map(|_| data_ref)
+        self.storage.append(bytes)?;
+        self.offsets.push(data_ref.index);
+        self.refs
+            .entry(hash)
+            .or_default()
+            .push((data_ref, bytes.len()));
+        Ok((data_ref, true))
+    }
+
+    /// Serialized lengths of all stored values, for reading them back with `get`.
+    pub fn lengths(&self) -> HashMap<DataRef, usize> {
+        self.refs.values().flatten().copied().collect()
+    }
+
+    /// Reads back the serialized value at `data_ref`, `len` being its length from `lengths`.
+    pub fn get(&self, data_ref: DataRef, len: usize) -> Result<Vec<u8>, std::io::Error> {
+        self.storage.read_at(data_ref.index as usize, len)
+    }
+
+    /// Reads back the serialized value at `data_ref`. Fails with `ErrorKind::NotFound` if no
+    /// value starts there.
+    ///
+    /// # Panics
+    ///
+    /// If `data_ref` was inserted into another datastore.
+    pub fn bytes_for(&self, data_ref: DataRef) -> Result<Vec<u8>, std::io::Error> {
+        self.check_ref(data_ref);
+        let position = self.offsets.binary_search(&data_ref.index).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no value stored at the DataRef",
+            )
+        })?;
+        let end = self
+            .offsets
+            .get(position + 1)
+            .map_or(self.storage.len(), |&next| next as usize);
+        self.get(data_ref, end - data_ref.index as usize)
+    }
+
+    pub fn write_to<W: std::io::Write>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        self.storage.write_to(writer)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn insert_with<F>(&mut self, f: F) -> Result<DataRef, Error>
+    where
+        F: FnOnce(&mut Serializer<&mut Vec<u8>>) -> Result<(), Error>,
+    {
+        let mut buf = Vec::new();
+        f(&mut Serializer::new(&mut buf))?;
+        self.insert_serialized(&buf).map(|(data_ref, _)| data_ref)
+    }
+
+    /// Appends `len` zero bytes to a spilling datastore by extending its file (sparsely on most
+    /// file systems), for testing databases too large to build.
+    #[cfg(test)]
+    pub(crate) fn append_zeros(&mut self, len: usize) -> Result<(), std::io::Error> {
+        let Storage::File {
+            file,
+            written,
+            pending,
+        } = &mut self.storage
+        else {
+            panic!("only spilling datastores can be padded");
+        };
+        file.seek(SeekFrom::Start(*written as u64))?;
+        file.write_all(pending)?;
+        *written += pending.len() + len;
+        pending.clear();
+        file.set_len(*written as u64)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn to_vec(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        self.write_to(&mut result).unwrap();
+        result
+    }
+}
+
+fn hash_serialized(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{paths::IpAddrWithMask, Database};
+
+    use super::*;
+
+    #[test]
+    fn test_dedup() {
+        let mut store = Datastore::default();
+        let us = store.insert("US", Options::default()).unwrap();
+        let pl = store.insert("PL", Options::default()).unwrap();
+        assert_eq!(store.insert("US", Options::default()).unwrap(), us);
+        assert_ne!(us, pl);
+        assert_eq!(store.to_vec(), b"\x42US\x42PL");
+    }
+
+    #[test]
+    fn test_dedup_across_types() {
+        let mut db = Database::default();
+        let refs = [
+            db.insert_value("US").unwrap(),
+            db.insert_value("US".to_string()).unwrap(),
+            db.insert_value(std::borrow::Cow::Borrowed("US")).unwrap(),
+        ];
+        assert!(refs.iter().all(|&data| data == refs[0]));
+        assert_eq!(db.data.to_vec(), b"\x42US");
+
+        // structs are written as maps
+        #[derive(serde::Serialize)]
+        struct Country {
+            iso_code: &'static str,
+        }
+        let from_struct = db.insert_value(Country { iso_code: "US" }).unwrap();
+        let from_map = db
+            .insert_value(HashMap::from([("iso_code", "US")]))
+            .unwrap();
+        assert_eq!(from_struct, from_map);
+
+        // as do integers written with the same MaxMind type, but not with different ones
+        assert_eq!(
+            db.insert_value(42u8).unwrap(),
+            db.insert_value(42u16).unwrap()
+        );
+        assert_ne!(
+            db.insert_value(42u16).unwrap(),
+            db.insert_value(42u32).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bytes_for() {
+        let mut store = Datastore::default();
+        let us = store.insert("US", Options::default()).unwrap();
+        let numbers = store.insert([1u16, 300], Options::default()).unwrap();
+        let greeting = store.insert("hello", Options::default()).unwrap();
+        assert_eq!(store.bytes_for(us).unwrap(), b"\x42US");
+        assert_eq!(
+            store.bytes_for(numbers).unwrap(),
+            [0b00000010, 0b00000100, 0b10100001, 1, 0b10100010, 1, 44]
+        );
+        assert_eq!(store.bytes_for(greeting).unwrap(), b"\x45hello");
+        assert_eq!(
+            store.insert("US", Options::default()).unwrap(),
+            us,
+            "deduplicated"
+        );
+        assert_eq!(store.bytes_for(us).unwrap(), b"\x42US");
+
+        // inside of a value
+        let inside = DataRef {
+            index: us.index + 1,
+            ..us
+        };
+        assert_eq!(
+            store.bytes_for(inside).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_dedup_in_database() {
+        let mut db = Database::default();
+        let first = db.insert_value("US").unwrap();
+        let second = db.insert_value("US").unwrap();
+        assert_eq!(first, second);
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), first);
+        db.insert_node("2.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), second);
+        let raw_db = db.to_vec().unwrap();
+        assert_eq!(raw_db.windows(3).filter(|w| w == b"\x42US").count(), 1);
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let first: &str = reader.lookup([1, 0, 0, 0].into()).unwrap();
+        let second: &str = reader.lookup([2, 0, 0, 0].into()).unwrap();
+        assert_eq!(first, "US");
+        assert_eq!(second, "US");
+    }
+
+    #[test]
+    fn test_spilling_datastore() {
+        let path = std::env::temp_dir().join(format!(
+            "maxminddb-writer-test-spill-{}",
+            std::process::id()
+        ));
+        let mut spilled = Database::with_spilling_datastore(&path).unwrap();
+        let mut in_memory = Database::default();
+
+        for db in [&mut spilled, &mut in_memory] {
+            for i in 0..20_000u32 {
+                // every value is inserted twice to exercise dedup against spilled data
+                let data = db.insert_value(format!("value {}", i)).unwrap();
+                assert_eq!(db.insert_value(format!("value {}", i)).unwrap(), data);
+                let addr = std::net::Ipv4Addr::from(0x0A00_0000 + i);
+                db.insert_node(IpAddrWithMask::from(addr), data);
+            }
+        }
+        assert!(spilled.data.len() > SPILL_CHUNK_SIZE);
+
+        let spilled_db = spilled.to_vec().unwrap();
+        assert_eq!(spilled_db, in_memory.to_vec().unwrap());
+
+        // the file is reused, leftovers past the new values are ignored
+        for db in [&mut spilled, &mut in_memory] {
+            db.clear(true);
+            for i in 0..10_000u32 {
+                let data = db.insert_value(format!("new value {}", i)).unwrap();
+                db.insert_node(IpAddrWithMask::from(std::net::Ipv4Addr::from(i)), data);
+            }
+        }
+        assert_eq!(spilled.to_vec().unwrap(), in_memory.to_vec().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let reader = maxminddb::Reader::from_source(&spilled_db).unwrap();
+        let value: &str = reader.lookup([10, 0, 78, 31].into()).unwrap();
+        assert_eq!(value, "value 19999");
+    }
+
+    #[test]
+    fn test_borrowed_strings() {
+        let mut store = Datastore::default();
+        let borrowed = store.insert("US", Options::default()).unwrap();
+        let owned = store.insert("US".to_string(), Options::default()).unwrap();
+        let cow = store
+            .insert(std::borrow::Cow::Borrowed("US"), Options::default())
+            .unwrap();
+        assert_eq!(borrowed, owned);
+        assert_eq!(borrowed, cow);
+
+        let mut borrowed_db = Database::default();
+        let data = borrowed_db.insert_value("US").unwrap();
+        borrowed_db.insert_node([false].into_iter(), data);
+        let mut owned_db = Database::default();
+        let data = owned_db.insert_value("US".to_string()).unwrap();
+        owned_db.insert_node([false].into_iter(), data);
+        assert_eq!(borrowed_db.to_vec().unwrap(), owned_db.to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_get_ref() {
+        let mut db = Database::default();
+        let us = db.insert_value("US").unwrap();
+        let data_len = db.data.len();
+        assert_eq!(db.get_ref("US").unwrap(), Some(us));
+        assert_eq!(db.get_ref("US".to_string()).unwrap(), Some(us));
+        assert_eq!(db.get_ref("XX").unwrap(), None);
+        // looking up doesn't insert
+        assert_eq!(db.data.len(), data_len);
+        assert_eq!(db.get_ref("XX").unwrap(), None);
     }
 
-    pub fn serialized_data(&self) -> &[u8] {
-        &self.store
+    #[test]
+    fn test_insert_tracked() {
+        let mut db = Database::default();
+        let (us, new) = db.insert_value_tracked("US").unwrap();
+        assert!(new);
+        assert_eq!(db.insert_value_tracked("US").unwrap(), (us, false));
+        assert_eq!(
+            db.insert_value_tracked("US".to_string()).unwrap(),
+            (us, false)
+        );
+        let (pl, new) = db.insert_value_tracked("PL").unwrap();
+        assert!(new);
+        assert_ne!(pl, us);
+        assert_eq!(db.insert_value("PL").unwrap(), pl);
     }
 }
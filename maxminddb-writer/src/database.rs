@@ -0,0 +1,2716 @@
+use std::{io::Write as _, net::IpAddr, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    data::{self, DataRef, DATA_SECTION_SEPARATOR_LEN},
+    metadata, node,
+    paths::{IntoBitPath, IpAddrWithMask},
+    serializer::{self, Section},
+    value,
+};
+
+/// Number of zero bits in front of IPv4 networks in IPv6 databases.
+const IPV4_IN_IPV6_PREFIX_LEN: usize = 96;
+
+/// Special purpose IPv4 networks, see RFC 6890.
+const RESERVED_IPV4_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+];
+
+/// Special purpose IPv6 networks, see RFC 6890.
+const RESERVED_IPV6_NETWORKS: &[&str] = &[
+    "::1/128",
+    "100::/64",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// IPv6 networks which embed IPv4 addresses, pointed to the IPv4 subtree by
+/// `Database::add_ipv4_aliases`: IPv4-mapped, Teredo and 6to4 addresses.
+const IPV4_ALIAS_NETWORKS: &[&str] = &["::ffff:0:0/96", "2001::/32", "2002::/16"];
+
+/// Sizes of the parts of a database as it would be written right now, see `Database::stats`.
+#[derive(Clone, Copy, Debug)]
+pub struct DatabaseStats {
+    pub node_count: usize,
+    pub data_section_size: usize,
+    pub record_size: metadata::RecordSize,
+    /// Size in bytes of the whole database file, including the metadata.
+    pub estimated_size: usize,
+}
+
+/// A database split into its parts, see `Database::write_sections`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DatabaseSections {
+    pub nodes: Vec<u8>,
+    pub data_section: Vec<u8>,
+    /// Serialized metadata, without `metadata::METADATA_START_MARKER`.
+    pub metadata: Vec<u8>,
+}
+
+/// What `Database::merge` does when both databases have different data for the same
+/// network.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MergePolicy {
+    /// Keep the data of the database being merged into.
+    PreferSelf,
+    /// Use the data of the database being merged in.
+    PreferOther,
+    /// Fail with `Error::MergeConflict` without changing anything.
+    Error,
+}
+
+#[derive(Debug, Default)]
+pub struct Database {
+    nodes: node::NodeTree,
+    pub(crate) data: data::Datastore,
+    options: serializer::Options,
+    // record size set with `pin_record_size` instead of the smallest one that fits
+    pinned_record_size: Option<metadata::RecordSize>,
+    // IP versions of the inserted networks, checked against the metadata when writing
+    unmapped_ipv4_inserted: bool,
+    ipv6_inserted: bool,
+    // whether `metadata::CHECKSUM_KEY` is written
+    checksum: bool,
+    // whether empty `languages` and `description` are left out of the metadata
+    omit_empty_collections: bool,
+    // set with `align_data_section`, reached by padding the tree with unreachable nodes
+    data_alignment: Option<usize>,
+    pub metadata: metadata::Metadata,
+}
+
+impl Database {
+    /// Creates an IPv6 database which can hold both IPv4 and IPv6 networks.
+    ///
+    /// IPv4 networks passed to `insert_node` are mapped into the IPv4 subtree at `::/96`,
+    /// IPv6 networks are inserted as they are.
+    pub fn new_v6() -> Self {
+        let mut db = Self::default();
+        db.metadata.ip_version = metadata::IpVersion::V6;
+        db
+    }
+
+    /// Creates a database with room for `node_capacity` nodes and `data_capacity` bytes of
+    /// data section before reallocating, which speeds up building large databases.
+    ///
+    /// Each inserted network adds at most as many nodes as its prefix length. The capacities
+    /// are only hints, the database still grows past them.
+    pub fn with_capacity(node_capacity: usize, data_capacity: usize) -> Self {
+        Self {
+            nodes: node::NodeTree::with_capacity(node_capacity),
+            data: data::Datastore::with_capacity(data_capacity),
+            ..Default::default()
+        }
+    }
+
+    /// Node and data section capacities, see `with_capacity`. The data capacity is
+    /// `usize::MAX` for databases created with `with_spilling_datastore`.
+    pub fn capacity(&self) -> (usize, usize) {
+        (self.nodes.capacity(), self.data.capacity())
+    }
+
+    /// Removes all networks and values, keeping the allocated memory for building the next
+    /// database. `DataRef`s returned before can't be used anymore.
+    ///
+    /// Settings (like `set_deterministic` or `pin_record_size`) are kept, the metadata only
+    /// if `keep_metadata` is set (otherwise it's reset to the default).
+    pub fn clear(&mut self, keep_metadata: bool) {
+        self.nodes.clear();
+        self.data.clear();
+        self.unmapped_ipv4_inserted = false;
+        self.ipv6_inserted = false;
+        if !keep_metadata {
+            self.metadata = Default::default();
+        }
+        self.update_size();
+    }
+
+    /// Creates a database which keeps its data section in the file at `path` (created or
+    /// truncated) instead of in memory. Only the deduplication index stays in memory.
+    ///
+    /// The file is used as scratch space and can be removed once the database is written.
+    pub fn with_spilling_datastore(path: impl AsRef<Path>) -> Result<Self, serializer::Error> {
+        Ok(Self {
+            data: data::Datastore::spilling_to(path)?,
+            ..Default::default()
+        })
+    }
+
+    fn update_size(&mut self) {
+        (self.metadata.node_count, self.metadata.record_size) = self.sizes_for(self.nodes.len());
+    }
+
+    /// Metadata with the node count and record size matching the current contents, even if
+    /// `metadata` was replaced since the last insert.
+    fn metadata_to_write(&self) -> metadata::Metadata {
+        self.metadata_for(self.nodes.len())
+    }
+
+    /// Like `metadata_to_write`, but for a search tree of `tree_len` nodes.
+    fn metadata_for(&self, tree_len: usize) -> metadata::Metadata {
+        let (node_count, record_size) = self.sizes_for(tree_len);
+        let mut metadata = metadata::Metadata {
+            node_count,
+            record_size,
+            omit_empty_collections: self.omit_empty_collections,
+            ..self.metadata.clone()
+        };
+        if self.checksum {
+            // same length as the real one, which is only known while writing
+            metadata.checksum = Some(format!("{:08x}", 0));
+        }
+        metadata
+    }
+
+    /// Node count and record size written to the metadata for a search tree of `tree_len`
+    /// nodes, see `layout`.
+    fn sizes_for(&self, tree_len: usize) -> (u32, metadata::RecordSize) {
+        let (node_count, record_size) = self.layout(tree_len);
+        // too many nodes are reported when writing, see `check_size`
+        (node_count.try_into().unwrap_or(u32::MAX), record_size)
+    }
+
+    /// Node count (including the padding added for `align_data_section`) and record size the
+    /// database is written with, for a search tree of `tree_len` nodes.
+    fn layout(&self, tree_len: usize) -> (usize, metadata::RecordSize) {
+        let record_size_for = |node_count: usize| {
+            self.pinned_record_size.unwrap_or_else(|| {
+                metadata::RecordSize::choose(
+                    node_count + self.data.len() + DATA_SECTION_SEPARATOR_LEN,
+                )
+            })
+        };
+        let node_count = tree_len;
+        let mut record_size = record_size_for(node_count);
+        let Some(alignment) = self.data_alignment else {
+            return (node_count, record_size);
+        };
+        // padding may need larger records, which need different padding
+        loop {
+            let padded = node_count + alignment_padding(node_count, record_size, alignment);
+            let padded_record_size = record_size_for(padded);
+            if padded_record_size <= record_size {
+                return (padded, record_size);
+            }
+            record_size = padded_record_size;
+        }
+    }
+
+    fn check_size(&self) -> Result<(), serializer::Error> {
+        self.check_size_for(self.nodes.len())
+    }
+
+    /// Like `check_size`, but for a search tree of `tree_len` nodes.
+    fn check_size_for(&self, tree_len: usize) -> Result<(), serializer::Error> {
+        let (node_count, _) = self.layout(tree_len);
+        if u32::try_from(node_count).is_err() {
+            return Err(serializer::Error::TooManyNodes);
+        }
+        let max_ptr_value = (node_count + self.data.len() + DATA_SECTION_SEPARATOR_LEN) as u64;
+        check_max_ptr_value(max_ptr_value)?;
+        match self.pinned_record_size {
+            Some(record_size)
+                if record_size < metadata::RecordSize::choose(max_ptr_value as usize) =>
+            {
+                Err(serializer::Error::DatabaseTooLarge)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Always writes records of `record_size` instead of the smallest size the database fits
+    /// in, e.g. to keep the layout stable as the database grows. Writing fails with
+    /// `Error::DatabaseTooLarge` if the database outgrows it.
+    pub fn pin_record_size(&mut self, record_size: metadata::RecordSize) {
+        self.pinned_record_size = Some(record_size);
+        self.update_size();
+    }
+
+    /// Pads the search tree with unreachable empty nodes so the data section starts at a
+    /// multiple of `alignment` bytes into the database, e.g. for readers mapping it at page
+    /// boundaries. Pointers account for the padding, so lookups aren't affected.
+    ///
+    /// # Panics
+    ///
+    /// If `alignment` isn't a power of two.
+    pub fn align_data_section(&mut self, alignment: usize) {
+        assert!(
+            alignment.is_power_of_two(),
+            "alignment {} is not a power of two",
+            alignment
+        );
+        self.data_alignment = Some(alignment);
+        self.update_size();
+    }
+
+    /// Replaces the metadata, e.g. with one made by `metadata::MetadataBuilder`, keeping the
+    /// node count and record size up to date.
+    pub fn set_metadata(&mut self, metadata: metadata::Metadata) {
+        self.metadata = metadata;
+        self.update_size();
+    }
+
+    /// Makes the output reproducible by writing map entries sorted by their keys
+    /// instead of in the map's iteration order.
+    ///
+    /// Only affects values inserted after the call (and the metadata).
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.options.deterministic = deterministic;
+    }
+
+    /// Writes enum variants carrying data as single entry maps `{variant_name: value}`
+    /// (like serde's externally tagged representation) instead of dropping the variant name.
+    /// Unit variants are always written as their name.
+    ///
+    /// Only affects values inserted after the call.
+    pub fn set_tagged_enums(&mut self, tagged_enums: bool) {
+        self.options.tagged_enums = tagged_enums;
+    }
+
+    /// Makes inserting NaN or infinite floats fail with `Error::NonFiniteFloat`.
+    ///
+    /// By default floats are written as their raw IEEE 754 bits, including non-finite
+    /// values, which some readers reject.
+    pub fn set_strict_floats(&mut self, strict_floats: bool) {
+        self.options.strict_floats = strict_floats;
+    }
+
+    /// Makes inserting values nested in more than `max_depth` maps and arrays fail with
+    /// `Error::MaxDepthExceeded` instead of recursing until the stack overflows. `None`, the
+    /// default, doesn't limit the depth.
+    ///
+    /// Only affects values inserted after the call.
+    pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+        self.options.max_depth = max_depth;
+    }
+
+    /// Writes a checksum of the search tree and the data section to the metadata under
+    /// `metadata::CHECKSUM_KEY`, for verifying distributed copies of the database.
+    ///
+    /// Readers ignore metadata keys they don't know.
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+
+    /// Leaves `languages` and `description` out of the metadata when they are empty, for
+    /// readers which don't expect empty collections. They are written by default as other
+    /// readers (like the `maxminddb` crate) require them.
+    pub fn set_omit_empty_collections(&mut self, omit_empty_collections: bool) {
+        self.omit_empty_collections = omit_empty_collections;
+    }
+
+    /// Serializes `value` into the data section, reusing the existing copy if an identical
+    /// value was inserted before.
+    ///
+    /// Borrowed values (`&str`, `Cow<str>`, maps with `&str` keys, ...) are first-class: they
+    /// are written straight from the borrowed data and produce the same bytes as their
+    /// owned counterparts, so there's no need to allocate owned `String`s for inserting.
+    ///
+    /// # Panics
+    ///
+    /// If `value` contains a `Pointer` to data inserted into another database.
+    pub fn insert_value<T: serde::Serialize>(
+        &mut self,
+        value: T,
+    ) -> Result<DataRef, serializer::Error> {
+        let result = self.data.insert(value, self.options);
+        self.update_size();
+        result
+    }
+
+    /// Like `insert_value`, but also returns whether the value is new (`false` if an
+    /// identical value was inserted before and its `DataRef` was reused), e.g. for counting
+    /// unique values.
+    pub fn insert_value_tracked<T: serde::Serialize>(
+        &mut self,
+        value: T,
+    ) -> Result<(DataRef, bool), serializer::Error> {
+        let result = self.data.insert_tracked(value, self.options);
+        self.update_size();
+        result
+    }
+
+    /// Returns the serialized bytes of the value at `data`, e.g. for checking what a value
+    /// is encoded to.
+    ///
+    /// # Panics
+    ///
+    /// If `data` was inserted into another database.
+    pub fn bytes_for(&self, data: DataRef) -> Result<Vec<u8>, serializer::Error> {
+        Ok(self.data.bytes_for(data)?)
+    }
+
+    /// Returns the `DataRef` of `value` if an identical value was inserted before, without
+    /// inserting it.
+    pub fn get_ref<T: serde::Serialize>(
+        &self,
+        value: T,
+    ) -> Result<Option<DataRef>, serializer::Error> {
+        self.data.get_ref(value, self.options)
+    }
+
+    /// Inserts a value which is already encoded in MaxMind DB format (e.g. copied from
+    /// another database) without going through serde.
+    ///
+    /// The caller is responsible for `bytes` being exactly one valid MaxMind DB data
+    /// structure. Pointers inside `bytes` are not adjusted.
+    pub fn insert_raw_value(&mut self, bytes: &[u8]) -> Result<DataRef, serializer::Error> {
+        let result = self.data.insert_raw(bytes);
+        self.update_size();
+        result
+    }
+
+    /// Makes addresses in the network described by `path` resolve to `data`.
+    ///
+    /// IPv4 networks inserted into an IPv6 database (`metadata.ip_version`) are placed under
+    /// `::/96`, where readers look IPv4 addresses up.
+    ///
+    /// Lookups resolve to the most specific network containing the address, no matter in
+    /// which order overlapping networks were inserted. Inserting a network again with the
+    /// same prefix length replaces its data. An empty path (like `0.0.0.0/0`) sets the
+    /// default for all addresses.
+    ///
+    /// # Panics
+    ///
+    /// If `data` was inserted into another database or `path` is longer than 128 bits.
+    pub fn insert_node(&mut self, path: impl IntoBitPath, data: DataRef) {
+        self.insert_nodes([(path, data)]);
+    }
+
+    /// Makes the addresses in the inclusive range `start..=end` resolve to `value`, by
+    /// inserting it once (see `insert_value`) for the networks covering the range (see
+    /// `IpAddrWithMask::from_range`).
+    ///
+    /// Nothing is inserted if the range is invalid.
+    pub fn insert_range<T: serde::Serialize>(
+        &mut self,
+        start: IpAddr,
+        end: IpAddr,
+        value: T,
+    ) -> Result<DataRef, serializer::Error> {
+        let networks = IpAddrWithMask::from_range(start, end)?;
+        let data = self.insert_value(value)?;
+        self.insert_nodes(networks.into_iter().map(|network| (network, data)));
+        Ok(data)
+    }
+
+    /// Like `insert_node`, but returns the data the network was inserted with before, if it
+    /// was inserted with the same prefix length, so overwrites can be detected.
+    ///
+    /// Returns `None` if more specific networks were inserted within the network since, as
+    /// its data is split into several records then.
+    ///
+    /// # Panics
+    ///
+    /// If `data` was inserted into another database.
+    pub fn insert_node_checked(
+        &mut self,
+        path: impl IntoBitPath,
+        data: DataRef,
+    ) -> Option<DataRef> {
+        self.data.check_ref(data);
+        self.note_ip_version(&path);
+        let path = self.tree_path(path).collect::<Vec<_>>();
+        let previous = self.nodes.get(path.iter().copied());
+        self.nodes.insert(path.into_iter(), data);
+        self.update_size();
+        previous
+    }
+
+    /// Inserts many networks at once, see `insert_node`.
+    ///
+    /// Faster than calling `insert_node` in a loop as the sizes are only updated at the end.
+    pub fn insert_nodes<P: IntoBitPath>(
+        &mut self,
+        entries: impl IntoIterator<Item = (P, DataRef)>,
+    ) {
+        for (path, data) in entries {
+            self.data.check_ref(data);
+            self.note_ip_version(&path);
+            self.nodes.insert(self.tree_path(path), data);
+        }
+        self.update_size();
+    }
+
+    /// Makes addresses in the network described by `path` resolve to no data, even if it's
+    /// within a network inserted before. Readers stop the lookup at the reserved network.
+    pub fn reserve_network(&mut self, path: impl IntoBitPath) {
+        self.note_ip_version(&path);
+        self.nodes.reserve(self.tree_path(path));
+        self.update_size();
+    }
+
+    /// Makes addresses in the network described by `path` resolve to an empty map, so
+    /// clients can tell them apart from addresses not in the database (unlike with
+    /// `reserve_network`). All such networks share one copy of the empty map, whose
+    /// `DataRef` is returned.
+    pub fn insert_empty_record(
+        &mut self,
+        path: impl IntoBitPath,
+    ) -> Result<DataRef, serializer::Error> {
+        let empty = self.insert_value(value::DataValue::Map(Vec::new()))?;
+        self.insert_node(path, empty);
+        Ok(empty)
+    }
+
+    /// Reserves the private, loopback, link local, documentation and other special purpose
+    /// networks, like official databases do. IPv6 ones are only reserved in IPv6 databases.
+    pub fn reserve_standard_private_ranges(&mut self) {
+        let ipv6 = matches!(self.metadata.ip_version, metadata::IpVersion::V6);
+        for network in
+            RESERVED_IPV4_NETWORKS
+                .iter()
+                .chain(if ipv6 { RESERVED_IPV6_NETWORKS } else { &[] })
+        {
+            let network: IpAddrWithMask = network.parse().expect("valid reserved network");
+            self.note_ip_version(&network);
+            self.nodes.reserve(self.tree_path(network));
+        }
+        self.update_size();
+    }
+
+    /// Makes lookups of IPv6 addresses embedding IPv4 ones (`::ffff:0:0/96`, `2001::/32`
+    /// and `2002::/16`) resolve through the IPv4 networks, like official IPv6 databases do.
+    /// Fails with `Error::IpVersionMismatch` in IPv4 databases.
+    ///
+    /// The aliases point to the IPv4 subtree itself, so networks inserted within them
+    /// afterwards end up in the IPv4 subtree, while networks covering them don't replace
+    /// them. `networks` doesn't report the aliased networks again.
+    pub fn add_ipv4_aliases(&mut self) -> Result<(), serializer::Error> {
+        if self.metadata.ip_version == metadata::IpVersion::V4 {
+            return Err(serializer::Error::IpVersionMismatch);
+        }
+        for network in IPV4_ALIAS_NETWORKS {
+            let network: IpAddrWithMask = network.parse().expect("valid alias network");
+            self.nodes
+                .alias(network, std::iter::repeat_n(false, IPV4_IN_IPV6_PREFIX_LEN));
+        }
+        self.update_size();
+        Ok(())
+    }
+
+    /// Removes the data inserted exactly at `path`, see `insert_node`. Returns whether
+    /// anything was removed.
+    ///
+    /// Addresses within the removed network resolve to the network covering it afterwards,
+    /// if there's one, while more specific networks inserted within it are kept.
+    pub fn remove_node(&mut self, path: impl IntoBitPath) -> bool {
+        self.nodes.remove(self.tree_path(path))
+    }
+
+    /// Iterates over the networks in the tree and the data they resolve to.
+    ///
+    /// In IPv6 databases networks within the IPv4 subtree (`::/96`) are reported as IPv4
+    /// networks. A network which had more specific networks inserted within it is reported
+    /// as the pieces it was split into.
+    pub fn networks(&self) -> impl Iterator<Item = (IpAddrWithMask, DataRef)> + '_ {
+        self.nodes
+            .iter_prefixes()
+            .map(|(path, data)| (self.network_at(&path), data))
+    }
+
+    /// Like `networks`, but reports networks split by more specific ones inserted within
+    /// them with the prefix length they were inserted with instead of as their pieces.
+    fn inserted_networks(&self) -> impl Iterator<Item = (IpAddrWithMask, DataRef)> + '_ {
+        self.nodes
+            .iter_networks()
+            .map(|(path, data)| (self.network_at(&path), data))
+    }
+
+    /// The network of the tree path `path`, as an IPv4 network within the IPv4 subtree of
+    /// IPv6 databases.
+    fn network_at(&self, path: &[bool]) -> IpAddrWithMask {
+        let ipv6 = matches!(self.metadata.ip_version, metadata::IpVersion::V6);
+        match path.split_at_checked(IPV4_IN_IPV6_PREFIX_LEN) {
+            Some((prefix, ipv4)) if ipv6 && !prefix.contains(&true) => {
+                IpAddrWithMask::from_bit_path(ipv4, false)
+            }
+            _ => IpAddrWithMask::from_bit_path(path, ipv6),
+        }
+    }
+
+    /// Adds the networks of `other` to this database, e.g. to apply an overlay of corrections
+    /// to a base database. The values are copied over, sharing the data of identical ones,
+    /// along with the values their `Pointer`s point to.
+    ///
+    /// `policy` decides which data is kept for networks both databases inserted data for
+    /// with the same prefix length, also if more specific networks were inserted within them
+    /// since. Networks which only overlap keep longest prefix match semantics: the more
+    /// specific network wins, whichever database it comes from. Metadata isn't merged.
+    pub fn merge(
+        &mut self,
+        other: &Database,
+        policy: MergePolicy,
+    ) -> Result<(), serializer::Error> {
+        let mut other_section = Vec::new();
+        other.data.write_to(&mut other_section)?;
+
+        if policy == MergePolicy::Error {
+            // check everything first so nothing changes on conflict
+            let mut data_section = Vec::new();
+            self.data.write_to(&mut data_section)?;
+            for (network, other_data) in other.inserted_networks() {
+                if let Some(data) = self.nodes.get_network(self.tree_path(network)) {
+                    // compared decoded as pointers differ between the databases
+                    let (value, _) = value::DataValue::decode(&data_section, data.index as usize)?;
+                    let (other_value, _) =
+                        value::DataValue::decode(&other_section, other_data.index as usize)?;
+                    if value != other_value {
+                        return Err(serializer::Error::MergeConflict(network));
+                    }
+                }
+            }
+        }
+
+        let mut copied = std::collections::HashMap::new();
+        for (network, other_data) in other.inserted_networks() {
+            if policy == MergePolicy::PreferSelf
+                && self.nodes.get_network(self.tree_path(network)).is_some()
+            {
+                continue;
+            }
+            let data = self.copy_value(&other_section, other_data.index as usize, &mut copied)?;
+            self.note_ip_version(&network);
+            self.nodes.insert(self.tree_path(network), data);
+        }
+        self.update_size();
+        Ok(())
+    }
+
+    /// Inserts the value at `offset` of another database's data section, with the values its
+    /// pointers point to copied too and the pointers adjusted. `copied` maps offsets in
+    /// `other_section` to the copies.
+    fn copy_value(
+        &mut self,
+        other_section: &[u8],
+        offset: usize,
+        copied: &mut std::collections::HashMap<usize, DataRef>,
+    ) -> Result<DataRef, serializer::Error> {
+        if let Some(&data) = copied.get(&offset) {
+            return Ok(data);
+        }
+        let mut bytes = Vec::new();
+        value::copy_remapped(other_section, offset, &mut bytes, &mut |target| {
+            Ok(self.copy_value(other_section, target, copied)?.index as usize)
+        })?;
+        let data = self.data.insert_raw(&bytes)?;
+        copied.insert(offset, data);
+        Ok(data)
+    }
+
+    /// Rewrites every stored value with `f`, e.g. to edit the values of an imported
+    /// database, keeping the networks pointing at them. Values which become identical are
+    /// stored once.
+    ///
+    /// The values are decoded into memory, including ones kept in a file (see
+    /// `with_spilling_datastore`). Values written as a `Pointer` are passed to `f` as a copy
+    /// of the pointed to value. `DataRef`s returned before can't be used anymore. The
+    /// rewritten values are kept in memory, also if they were kept in a file before. Nothing
+    /// changes if a rewritten value can't be serialized or stored.
+    pub fn map_data_values<F>(&mut self, mut f: F) -> Result<(), serializer::Error>
+    where
+        F: FnMut(&mut value::DataValue),
+    {
+        let mut data_section = Vec::new();
+        self.data.write_to(&mut data_section)?;
+        let mut refs = self.data.lengths().into_keys().collect::<Vec<_>>();
+        refs.sort();
+
+        let mut rewritten = Vec::with_capacity(refs.len());
+        for &data in &refs {
+            let (mut value, _) = value::DataValue::decode(&data_section, data.index as usize)?;
+            f(&mut value);
+            let mut bytes = Vec::new();
+            value.serialize(&mut serializer::Serializer::with_options(
+                &mut bytes,
+                self.options,
+            ))?;
+            rewritten.push(bytes);
+        }
+
+        // filled separately so a failing insert leaves the current values in place
+        let mut new_data = data::Datastore::with_capacity(data_section.len());
+        let mut new_refs = std::collections::HashMap::with_capacity(refs.len());
+        for (data, bytes) in refs.into_iter().zip(rewritten) {
+            new_refs.insert(data, new_data.insert_raw(&bytes)?);
+        }
+        self.data = new_data;
+        self.nodes.map_data(|data| new_refs[&data]);
+        self.update_size();
+        Ok(())
+    }
+
+    /// Shrinks the search tree by merging prefixes that resolve to the same data. Every
+    /// address resolves to the same data as before and networks can still be inserted.
+    ///
+    /// Worth calling before writing a database built from many adjacent networks.
+    pub fn optimize(&mut self) {
+        self.nodes.optimize();
+        self.update_size();
+    }
+
+    /// Returns the data `addr` resolves to in the tree built so far (the longest matching
+    /// prefix), or `None` if no inserted network contains it.
+    pub fn lookup(&self, addr: IpAddr) -> Option<DataRef> {
+        self.nodes
+            .lookup(self.tree_path(IpAddrWithMask::from(addr)))
+    }
+
+    /// Like `lookup`, but also returns the network of the matching record, like readers'
+    /// `lookup_prefix`.
+    ///
+    /// The network is the piece of the inserted network `addr` is in if more specific
+    /// networks were inserted within it (see `networks`). IPv4 addresses get IPv4 networks,
+    /// also in IPv6 databases.
+    pub fn lookup_prefix(&self, addr: IpAddr) -> Option<(IpAddrWithMask, DataRef)> {
+        let path = self
+            .tree_path(IpAddrWithMask::from(addr))
+            .collect::<Vec<_>>();
+        let (data, depth) = self.nodes.lookup_with_depth(path.iter().copied())?;
+        // IPv4 addresses are looked up under `::/96` in IPv6 databases
+        let skipped = match (self.metadata.ip_version, addr) {
+            (metadata::IpVersion::V6, IpAddr::V4(_)) => IPV4_IN_IPV6_PREFIX_LEN.min(depth),
+            _ => 0,
+        };
+        let network = IpAddrWithMask::from_bit_path(&path[skipped..depth], addr.is_ipv6());
+        Some((network, data))
+    }
+
+    /// Walks the search tree for `addr` like `lookup`, for debugging where the longest prefix
+    /// match stops. Has an entry for every bit followed: the depth in the tree (IPv4
+    /// addresses in IPv6 databases start below `::/96`) and the data if a data record was
+    /// reached there. The last entry has no data if `addr` isn't in any inserted network.
+    pub fn trace(&self, addr: IpAddr) -> Vec<(u8, Option<DataRef>)> {
+        self.nodes
+            .trace(self.tree_path(IpAddrWithMask::from(addr)))
+            .into_iter()
+            .map(|(depth, data)| (depth as u8, data))
+            .collect()
+    }
+
+    /// Describes the search tree in Graphviz DOT for debugging, e.g. for rendering with
+    /// `dot -Tsvg`. Data is labeled with the index of its `DataRef`.
+    pub fn to_dot(&self) -> String {
+        self.nodes.to_dot()
+    }
+
+    /// Maps `path` to where it's placed in the tree, see `insert_node`.
+    fn tree_path(&self, path: impl IntoBitPath) -> impl Iterator<Item = bool> {
+        tree_path(self.metadata.ip_version, path)
+    }
+
+    fn note_ip_version(&mut self, path: &impl IntoBitPath) {
+        match (self.metadata.ip_version, path.ip_version()) {
+            (metadata::IpVersion::V4, Some(metadata::IpVersion::V4)) => {
+                self.unmapped_ipv4_inserted = true
+            }
+            (_, Some(metadata::IpVersion::V6)) => self.ipv6_inserted = true,
+            _ => {}
+        }
+    }
+
+    /// Checks that the networks were inserted with the IP version the metadata declares:
+    /// IPv6 networks need an IPv6 database and IPv4 networks inserted while the database was
+    /// IPv4 aren't mapped into the IPv4 subtree of an IPv6 one.
+    fn check_ip_version(&self) -> Result<(), serializer::Error> {
+        let mismatch = match self.metadata.ip_version {
+            metadata::IpVersion::V4 => self.ipv6_inserted,
+            metadata::IpVersion::V6 => self.unmapped_ipv4_inserted,
+        };
+        if mismatch {
+            Err(serializer::Error::IpVersionMismatch)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Record size the search tree would be written with now: the smallest one that can
+    /// address the current contents, or the one set with `pin_record_size`.
+    ///
+    /// Inserting more networks or values can make it grow.
+    pub fn record_size(&self) -> metadata::RecordSize {
+        self.sizes_for(self.nodes.len()).1
+    }
+
+    /// Number of nodes in the search tree, as written to the metadata.
+    pub fn node_count(&self) -> u32 {
+        self.sizes_for(self.nodes.len()).0
+    }
+
+    /// Reports how big the database would be if written now, without writing it.
+    pub fn stats(&self) -> Result<DatabaseStats, serializer::Error> {
+        let metadata = self.metadata_to_write();
+        let node_count = metadata.node_count as usize;
+        let record_size = metadata.record_size;
+        let data_section_size = self.data.len();
+
+        let mut serialized_metadata = Vec::new();
+        metadata.serialize(&mut serializer::Serializer::with_options(
+            &mut serialized_metadata,
+            self.options,
+        ))?;
+
+        Ok(DatabaseStats {
+            node_count,
+            data_section_size,
+            record_size,
+            estimated_size: node_count * record_size.node_size()
+                + DATA_SECTION_SEPARATOR_LEN
+                + data_section_size
+                + metadata::METADATA_START_MARKER.len()
+                + serialized_metadata.len(),
+        })
+    }
+
+    /// Writes the database to `writer`, returning it back.
+    ///
+    /// Failures of `writer` are reported as `Error::WhileWriting` with the section of the
+    /// database being written.
+    ///
+    /// The database is written in a single pass without buffering it in memory: the record
+    /// size and all pointers are known from the node count and data section size upfront, so
+    /// `writer` doesn't need to be seekable. See `write_to_seekable` for writing the data
+    /// section first.
+    pub fn write_to<W: std::io::Write>(&self, writer: W) -> Result<W, serializer::Error> {
+        self.write_tree_to(&self.nodes, writer)
+    }
+
+    /// Writes the database with `nodes` as its search tree, see `write_to`.
+    fn write_tree_to<W: std::io::Write>(
+        &self,
+        nodes: &node::NodeTree,
+        writer: W,
+    ) -> Result<W, serializer::Error> {
+        // make sure all pointers can be encoded
+        self.check_size_for(nodes.len())?;
+        self.check_ip_version()?;
+        let mut metadata = self.metadata_for(nodes.len());
+        let writer = Crc32Writer {
+            inner: writer,
+            crc: self.checksum.then_some(!0),
+        };
+        // write node tree
+        let mut writer = nodes
+            .write_to(writer, metadata.record_size, metadata.node_count as usize)
+            .map_err(|err| Section::Nodes.context(err))?;
+        // write data section separator
+        writer
+            .write_all(&[0u8; DATA_SECTION_SEPARATOR_LEN])
+            .map_err(|err| Section::DataSection.context(err))?;
+        // write data section
+        self.data
+            .write_to(&mut writer)
+            .map_err(|err| Section::DataSection.context(err))?;
+        if let Some(crc) = writer.crc {
+            metadata.checksum = Some(format!("{:08x}", !crc));
+        }
+        self.write_metadata_to(&metadata, writer.inner)
+    }
+
+    /// Writes the database to the seekable `writer` from its current position, returning it
+    /// back positioned after the database. The output is the same as with `write_to`.
+    ///
+    /// Room for the search tree is left first and the data section streamed right after it.
+    /// The search tree is written into that room once the data section is in place, with the
+    /// writer checked to hold as many data section bytes as the pointers in the tree expect.
+    /// With a checksum (see `set_checksum`) the data section is read from the datastore a
+    /// second time to compute it.
+    pub fn write_to_seekable<W: std::io::Write + std::io::Seek>(
+        &self,
+        mut writer: W,
+    ) -> Result<W, serializer::Error> {
+        use std::io::SeekFrom;
+
+        // make sure all pointers can be encoded
+        self.check_size()?;
+        self.check_ip_version()?;
+        let mut metadata = self.metadata_to_write();
+        let node_count = metadata.node_count as usize;
+        let nodes_len = (node_count * metadata.record_size.node_size()) as u64;
+        // leave room for the node tree
+        let start = writer
+            .stream_position()
+            .map_err(|err| Section::Nodes.context(err))?;
+        let data_start = start + nodes_len + DATA_SECTION_SEPARATOR_LEN as u64;
+        writer
+            .seek(SeekFrom::Start(start + nodes_len))
+            .map_err(|err| Section::DataSection.context(err))?;
+        // write data section separator and data section
+        writer
+            .write_all(&[0u8; DATA_SECTION_SEPARATOR_LEN])
+            .map_err(|err| Section::DataSection.context(err))?;
+        self.data
+            .write_to(&mut writer)
+            .map_err(|err| Section::DataSection.context(err))?;
+        let end = writer
+            .stream_position()
+            .map_err(|err| Section::DataSection.context(err))?;
+        if end - data_start != self.data.len() as u64 {
+            return Err(Section::DataSection.context(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                "data section not fully written",
+            )));
+        }
+        // backpatch node tree
+        writer
+            .seek(SeekFrom::Start(start))
+            .map_err(|err| Section::Nodes.context(err))?;
+        let mut crc_writer = self
+            .nodes
+            .write_to(
+                Crc32Writer {
+                    inner: &mut writer,
+                    crc: self.checksum.then_some(!0),
+                },
+                metadata.record_size,
+                node_count,
+            )
+            .map_err(|err| Section::Nodes.context(err))?;
+        if let Some(crc) = crc_writer.crc {
+            let mut crc_writer = Crc32Writer {
+                inner: std::io::sink(),
+                crc: Some(crc),
+            };
+            crc_writer.write_all(&[0u8; DATA_SECTION_SEPARATOR_LEN])?;
+            self.data
+                .write_to(&mut crc_writer)
+                .map_err(|err| Section::DataSection.context(err))?;
+            metadata.checksum = crc_writer.crc.map(|crc| format!("{:08x}", !crc));
+        }
+        crc_writer
+            .flush()
+            .map_err(|err| Section::Nodes.context(err))?;
+        writer
+            .seek(SeekFrom::Start(end))
+            .map_err(|err| Section::Metadata.context(err))?;
+        self.write_metadata_to(&metadata, writer)
+    }
+
+    /// Writes the metadata marker and `metadata` to `writer`, the end of a database.
+    fn write_metadata_to<W: std::io::Write>(
+        &self,
+        metadata: &metadata::Metadata,
+        mut writer: W,
+    ) -> Result<W, serializer::Error> {
+        // write metadata marker
+        writer
+            .write_all(metadata::METADATA_START_MARKER)
+            .map_err(|err| Section::Metadata.context(err))?;
+        // serialize metadata
+        let mut serializer = serializer::Serializer::with_options(writer, self.options);
+        metadata
+            .serialize(&mut serializer)
+            .map_err(|err| Section::Metadata.context(err))?;
+        // all done
+        Ok(serializer.into_inner())
+    }
+
+    /// Writes the search tree, the data section and the metadata separately, e.g. to
+    /// produce or store them apart. Joined with 16 zero bytes between the first two and
+    /// `metadata::METADATA_START_MARKER` before the metadata they are what `write_to` writes.
+    pub fn write_sections(&self) -> Result<DatabaseSections, serializer::Error> {
+        let mut nodes = self.to_bytes()?;
+        let (node_count, record_size) = self.layout(self.nodes.len());
+        // split the written database at the known section sizes
+        let mut data_section = nodes.split_off(node_count * record_size.node_size());
+        let metadata = data_section
+            .split_off(DATA_SECTION_SEPARATOR_LEN + self.data.len())
+            .split_off(metadata::METADATA_START_MARKER.len());
+        let data_section = data_section.split_off(DATA_SECTION_SEPARATOR_LEN);
+        Ok(DatabaseSections {
+            nodes,
+            data_section,
+            metadata,
+        })
+    }
+
+    /// Writes the smallest database resolving the same as this one to `writer`, see
+    /// `write_to`: the search tree is written optimized (see `optimize`) and with identical
+    /// subtrees shared. Values don't need a separate pass as identical ones are stored once
+    /// when inserted.
+    ///
+    /// The database itself isn't changed.
+    pub fn write_optimized_to<W: std::io::Write>(&self, writer: W) -> Result<W, serializer::Error> {
+        self.write_tree_to(&self.nodes.compacted(), writer)
+    }
+
+    /// Like `write_to`, but also returns the number of bytes written.
+    pub fn write_to_counted<W: std::io::Write>(
+        &self,
+        writer: W,
+    ) -> Result<(W, u64), serializer::Error> {
+        let counting = self.write_to(CountingWriter {
+            inner: writer,
+            count: 0,
+        })?;
+        Ok((counting.inner, counting.count))
+    }
+
+    /// Validates the metadata (see `Metadata::validate`) and writes the database to a file
+    /// at `path`, creating or truncating it.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<(), serializer::Error> {
+        self.metadata.validate()?;
+        let file = std::fs::File::create(path)?;
+        self.write_to(std::io::BufWriter::new(file))?
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        Ok(())
+    }
+
+    /// Writes the database to a buffer, e.g. for serving it without going through a file.
+    ///
+    /// The buffer is allocated upfront with the size from `stats`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serializer::Error> {
+        // don't allocate for a database that can't be written
+        self.check_size()?;
+        let buf = Vec::with_capacity(self.stats()?.estimated_size);
+        self.write_to(buf)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn to_vec(&self) -> Result<Vec<u8>, serializer::Error> {
+        self.to_bytes()
+    }
+}
+
+/// Maps `path` to where it's placed in the tree of a database with `ip_version`: IPv4
+/// networks in IPv6 databases go under `::/96`.
+pub(crate) fn tree_path(
+    ip_version: metadata::IpVersion,
+    path: impl IntoBitPath,
+) -> impl Iterator<Item = bool> {
+    let prefix_len = match (ip_version, path.ip_version()) {
+        (metadata::IpVersion::V6, Some(metadata::IpVersion::V4)) => IPV4_IN_IPV6_PREFIX_LEN,
+        _ => 0,
+    };
+    std::iter::repeat_n(false, prefix_len).chain(path.into_bit_path())
+}
+
+/// `metadata` with the node count and record size for a tree of `node_count` nodes and a data
+/// section of `data_len` bytes.
+pub(crate) fn sized_metadata(
+    metadata: &metadata::Metadata,
+    node_count: usize,
+    data_len: usize,
+) -> metadata::Metadata {
+    let max_ptr_value = node_count + data_len + DATA_SECTION_SEPARATOR_LEN;
+    metadata::Metadata {
+        // callers check that pointers (and so node indexes) fit in 32 bits before writing
+        node_count: node_count.try_into().unwrap_or(u32::MAX),
+        record_size: metadata::RecordSize::choose(max_ptr_value),
+        ..metadata.clone()
+    }
+}
+
+/// Number of empty nodes to add to a tree of `node_count` nodes so the data section (after
+/// the tree and the separator) starts at a multiple of `alignment`, a power of two.
+fn alignment_padding(
+    node_count: usize,
+    record_size: metadata::RecordSize,
+    alignment: usize,
+) -> usize {
+    let node_size = record_size.node_size() as u64;
+    // solve `(node_count + padding) * node_size + 16 = 0 (mod alignment)`: dividing by the
+    // common power of two (at most 8, which divides 16) leaves an odd, invertible node size
+    let common = 1 << node_size.trailing_zeros().min(alignment.trailing_zeros());
+    let modulus = alignment as u64 / common;
+    if modulus == 1 {
+        return 0;
+    }
+    let odd_node_size = node_size / common;
+    // inverse modulo 2^64 by Newton's iteration, each step doubles the correct low bits
+    let mut inverse = odd_node_size;
+    for _ in 0..5 {
+        inverse = inverse.wrapping_mul(2u64.wrapping_sub(odd_node_size.wrapping_mul(inverse)));
+    }
+    let aligned_node_count = 0u64
+        .wrapping_sub(DATA_SECTION_SEPARATOR_LEN as u64 / common)
+        .wrapping_mul(inverse);
+    (aligned_node_count.wrapping_sub(node_count as u64) & (modulus - 1)) as usize
+}
+
+/// A writer which only counts the bytes written to it, for finding out how big a database
+/// is without keeping it anywhere.
+///
+/// `write_to` only ever appends, so the count is exactly the size of the written database:
+///
+/// ```
+/// # use maxminddb_writer::{paths::IpAddrWithMask, Database, SizeWriter};
+/// let mut db = Database::default();
+/// let data = db.insert_value("US").unwrap();
+/// db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+/// let size = db.write_to(SizeWriter::default()).unwrap().size();
+/// # let mut bytes = Vec::new();
+/// # db.write_to(&mut bytes).unwrap();
+/// # assert_eq!(size, bytes.len() as u64);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeWriter {
+    size: u64,
+}
+
+impl SizeWriter {
+    /// Number of bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl std::io::Write for SizeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: std::io::Write> std::io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Lookup table for CRC-32 with the reversed polynomial `0xedb88320`.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// A writer which computes the CRC-32 of the bytes written to it, if `crc` is set (to the
+/// initial `!0`). The checksum is `!crc` in the end.
+struct Crc32Writer<W> {
+    inner: W,
+    crc: Option<u32>,
+}
+
+impl<W: std::io::Write> std::io::Write for Crc32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(crc) = &mut self.crc {
+            for &byte in &buf[..written] {
+                *crc = CRC32_TABLE[((*crc ^ byte as u32) & 0xff) as usize] ^ (*crc >> 8);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Checks that pointers up to (but excluding) `max_ptr_value` fit in the largest record size.
+pub(crate) fn check_max_ptr_value(max_ptr_value: u64) -> Result<(), serializer::Error> {
+    if max_ptr_value > 1 << 32 {
+        Err(serializer::Error::DatabaseTooLarge)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{paths, Pointer};
+
+    #[test]
+    fn test_simple() {
+        let mut db = Database::default();
+        let data_42 = db.insert_value(42u32).unwrap();
+        let data_foo = db.insert_value("foo".to_string()).unwrap();
+        db.insert_node("0.0.0.0/16".parse::<IpAddrWithMask>().unwrap(), data_42);
+        db.insert_node("1.0.0.0/16".parse::<IpAddrWithMask>().unwrap(), data_foo);
+        let raw_db = db.to_vec().unwrap();
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let expected_data_42: u32 = reader.lookup([0, 0, 0, 0].into()).unwrap();
+        let expected_data_foo: &str = reader.lookup([1, 0, 0, 0].into()).unwrap();
+
+        assert_eq!(expected_data_42, 42);
+        assert_eq!(expected_data_foo, "foo");
+    }
+
+    #[test]
+    fn test_raw_value() {
+        let mut db = Database::default();
+        // {"a": 42u16, "b": "foo"}
+        let raw = [
+            0xE2, 0x41, b'a', 0xA1, 42, 0x41, b'b', 0x43, b'f', b'o', b'o',
+        ];
+        let data = db.insert_raw_value(&raw).unwrap();
+        assert_eq!(db.insert_raw_value(&raw).unwrap(), data);
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+        let raw_db = db.to_vec().unwrap();
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        #[derive(serde::Deserialize)]
+        struct Raw<'a> {
+            a: u16,
+            b: &'a str,
+        }
+        let value: Raw = reader.lookup([1, 0, 0, 0].into()).unwrap();
+        assert_eq!(value.a, 42);
+        assert_eq!(value.b, "foo");
+    }
+
+    #[test]
+    fn test_database_too_large() {
+        assert!(check_max_ptr_value(1 << 32).is_ok());
+        assert!(matches!(
+            check_max_ptr_value((1 << 32) + 1),
+            Err(serializer::Error::DatabaseTooLarge)
+        ));
+
+        // a data section which pushes the last pointer past 2^32
+        let path = std::env::temp_dir().join(format!(
+            "maxminddb-writer-test-too-large-{}",
+            std::process::id()
+        ));
+        let mut db = Database::with_spilling_datastore(&path).unwrap();
+        let data = db.insert_value("US").unwrap();
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+        let node_count = db.nodes.len();
+        db.data
+            .append_zeros((1 << 32) - node_count - DATA_SECTION_SEPARATOR_LEN - db.data.len())
+            .unwrap();
+        // the last byte is at pointer 2^32 - 1, which still fits
+        assert!(db.check_size().is_ok());
+
+        db.data.append_zeros(1).unwrap();
+        let mut written = Vec::new();
+        assert!(matches!(
+            db.write_to(&mut written),
+            Err(serializer::Error::DatabaseTooLarge)
+        ));
+        // nothing is written instead of a corrupt file
+        assert!(written.is_empty());
+        assert!(matches!(
+            db.write_to_seekable(std::io::Cursor::new(&mut written)),
+            Err(serializer::Error::DatabaseTooLarge)
+        ));
+        assert!(written.is_empty());
+        assert!(matches!(
+            db.to_bytes(),
+            Err(serializer::Error::DatabaseTooLarge)
+        ));
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_medium_record_pointers_around_boundary() {
+        struct Blob(Vec<u8>);
+
+        impl Serialize for Blob {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(&self.0)
+            }
+        }
+
+        // 3 nodes: root and one for each /1
+        let node_count = 3;
+        let mut db = Database::default();
+        // blob with a 4 byte header filling the data section up to pointer 2^24 - 1
+        db.insert_value(Blob(vec![
+            0;
+            (1 << 24)
+                - 1
+                - node_count
+                - DATA_SECTION_SEPARATOR_LEN
+                - 4
+        ]))
+        .unwrap();
+        let below = db.insert_value(0u16).unwrap();
+        let at = db.insert_value(1u16).unwrap();
+        let above = db.insert_value("c").unwrap();
+        assert_eq!(below.data_section_offset(node_count), (1 << 24) - 1);
+        assert_eq!(at.data_section_offset(node_count), 1 << 24);
+
+        db.insert_node("0.0.0.0/2".parse::<IpAddrWithMask>().unwrap(), below);
+        db.insert_node("64.0.0.0/2".parse::<IpAddrWithMask>().unwrap(), at);
+        db.insert_node("128.0.0.0/2".parse::<IpAddrWithMask>().unwrap(), above);
+        // 192.0.0.0/2 is left empty and gets the "no data" sentinel
+        assert_eq!(db.nodes.len(), node_count);
+        let raw_db = db.to_vec().unwrap();
+        assert!(matches!(
+            db.metadata.record_size,
+            metadata::RecordSize::Medium
+        ));
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.record_size, 28);
+        assert_eq!(reader.lookup::<u16>([1, 0, 0, 0].into()).unwrap(), 0);
+        assert_eq!(reader.lookup::<u16>([65, 0, 0, 0].into()).unwrap(), 1);
+        assert_eq!(reader.lookup::<&str>([129, 0, 0, 0].into()).unwrap(), "c");
+        assert!(reader.lookup::<u16>([193, 0, 0, 0].into()).is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut db = Database::default();
+        let data_8 = db.insert_value("8").unwrap();
+        let data_24 = db.insert_value("24").unwrap();
+        let data_32 = db.insert_value("32").unwrap();
+        db.insert_node("10.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data_8);
+        db.insert_node("10.1.2.0/24".parse::<IpAddrWithMask>().unwrap(), data_24);
+        db.insert_node("10.1.3.4/32".parse::<IpAddrWithMask>().unwrap(), data_32);
+
+        // exact matches
+        assert_eq!(db.lookup([10, 0, 0, 0].into()), Some(data_8));
+        assert_eq!(db.lookup([10, 1, 2, 0].into()), Some(data_24));
+        assert_eq!(db.lookup([10, 1, 3, 4].into()), Some(data_32));
+        // longest prefix matches
+        assert_eq!(db.lookup([10, 200, 0, 1].into()), Some(data_8));
+        assert_eq!(db.lookup([10, 1, 2, 255].into()), Some(data_24));
+        assert_eq!(db.lookup([10, 1, 3, 5].into()), Some(data_8));
+        // no match
+        assert_eq!(db.lookup([11, 0, 0, 0].into()), None);
+        assert_eq!(db.lookup([9, 255, 255, 255].into()), None);
+
+        // agrees with the written database
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        for (addr, expected) in [([10, 1, 3, 5], "8"), ([10, 1, 2, 7], "24")] {
+            assert_eq!(reader.lookup::<&str>(addr.into()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_remove_node() {
+        let mut db = Database::default();
+        let data_8 = db.insert_value("8").unwrap();
+        let data_16 = db.insert_value("16").unwrap();
+        let net_8 = "10.0.0.0/8".parse::<IpAddrWithMask>().unwrap();
+        let net_16 = "10.1.0.0/16".parse::<IpAddrWithMask>().unwrap();
+        db.insert_node(net_8, data_8);
+        db.insert_node(net_16, data_16);
+
+        assert!(db.remove_node(net_16));
+        assert!(!db.remove_node(net_16));
+        assert_eq!(db.lookup([10, 1, 0, 0].into()), Some(data_8));
+        assert_eq!(db.lookup([10, 2, 0, 0].into()), Some(data_8));
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.lookup::<&str>([10, 1, 0, 0].into()).unwrap(), "8");
+        assert_eq!(reader.lookup::<&str>([10, 2, 0, 0].into()).unwrap(), "8");
+
+        assert!(db.remove_node(net_8));
+        assert_eq!(db.lookup([10, 1, 0, 0].into()), None);
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert!(reader.lookup::<&str>([10, 1, 0, 0].into()).is_err());
+    }
+
+    #[test]
+    fn test_optimize() {
+        let mut db = Database::default();
+        let data = db.insert_value("same").unwrap();
+        for third in 0..5 {
+            let addr = [1, 0, third, 0].into();
+            db.insert_node(IpAddrWithMask::new(addr, 24), data);
+        }
+        let node_count = db.nodes.len();
+        db.optimize();
+        assert!(db.nodes.len() < node_count);
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.node_count as usize, db.nodes.len());
+        for addr in [[1, 0, 0, 0], [1, 0, 2, 7], [1, 0, 4, 255]] {
+            assert_eq!(reader.lookup::<&str>(addr.into()).unwrap(), "same");
+        }
+        assert!(reader.lookup::<&str>([1, 0, 5, 0].into()).is_err());
+    }
+
+    #[test]
+    fn test_write_optimized_to() {
+        let networks = [
+            ("1.0.0.0/9", "one"),
+            ("1.128.0.0/9", "one"),
+            ("2.0.0.0/24", "two"),
+            ("2.0.1.0/24", "two"),
+            ("2.0.2.0/23", "two"),
+            ("2.0.4.0/24", "other"),
+            ("3.0.0.0/8", "one"),
+        ];
+        let mut db = database_with(&networks);
+        let plain = db.to_bytes().unwrap();
+        let optimized = db.write_optimized_to(Vec::new()).unwrap();
+        assert!(optimized.len() < plain.len());
+        // the database is left as it was
+        assert_eq!(db.to_bytes().unwrap(), plain);
+        let mut optimized_db = database_with(&networks);
+        optimized_db.optimize();
+        assert!(optimized.len() <= optimized_db.to_bytes().unwrap().len());
+
+        // networks inserted afterwards don't affect identically shaped ones
+        let other = db.insert_value("other").unwrap();
+        db.insert_node("2.0.0.0/24".parse::<IpAddrWithMask>().unwrap(), other);
+        let optimized_after = db.write_optimized_to(Vec::new()).unwrap();
+        assert_eq!(
+            lookup_str(&optimized_after, [2, 0, 0, 1]).as_deref(),
+            Some("other")
+        );
+        assert_eq!(
+            lookup_str(&optimized_after, [2, 0, 1, 1]).as_deref(),
+            Some("two")
+        );
+
+        for addr in [
+            [1, 0, 0, 0],
+            [1, 200, 3, 4],
+            [2, 0, 0, 1],
+            [2, 0, 3, 255],
+            [2, 0, 4, 1],
+            [2, 0, 5, 1],
+            [3, 3, 3, 3],
+            [4, 0, 0, 0],
+        ] {
+            assert_eq!(lookup_str(&optimized, addr), lookup_str(&plain, addr));
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut db = Database::default();
+        db.metadata.database_type = "test".to_string();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.node_count, 1);
+        assert_eq!(stats.data_section_size, 0);
+        assert_eq!(stats.estimated_size, db.to_vec().unwrap().len());
+
+        let data = db.insert_value("some data").unwrap();
+        db.insert_value(vec![1u32, 2, 3]).unwrap();
+        db.insert_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.node_count, 24);
+        assert!(matches!(stats.record_size, metadata::RecordSize::Small));
+        // 1 + 9 bytes for the string, 2 + 3 * 2 for the array
+        assert_eq!(stats.data_section_size, 10 + 8);
+        assert_eq!(stats.estimated_size, db.to_vec().unwrap().len());
+    }
+
+    #[test]
+    fn test_ipv4_in_ipv6_database() {
+        let mut db = Database::default();
+        db.metadata.ip_version = metadata::IpVersion::V6;
+        let data = db.insert_value("v4").unwrap();
+        db.insert_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        assert_eq!(db.nodes.len(), 96 + 24);
+        assert_eq!(db.lookup([1, 2, 3, 4].into()), Some(data));
+        assert_eq!(db.lookup("::1.2.3.4".parse().unwrap()), Some(data));
+        assert_eq!(db.lookup([1, 2, 4, 0].into()), None);
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.ip_version, 6);
+        assert_eq!(reader.lookup::<&str>([1, 2, 3, 4].into()).unwrap(), "v4");
+        assert_eq!(
+            reader
+                .lookup::<&str>("::1.2.3.255".parse().unwrap())
+                .unwrap(),
+            "v4"
+        );
+        assert!(reader.lookup::<&str>([1, 2, 4, 0].into()).is_err());
+
+        assert!(db.remove_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap()));
+        assert_eq!(db.lookup([1, 2, 3, 4].into()), None);
+    }
+
+    #[test]
+    fn test_mixed_ipv4_and_ipv6() {
+        let mut db = Database::new_v6();
+        let data_v4 = db.insert_value("v4").unwrap();
+        let data_v6 = db.insert_value("v6").unwrap();
+        db.insert_node("1.0.0.0/24".parse::<IpAddrWithMask>().unwrap(), data_v4);
+        db.insert_node("2001:db8::/32".parse::<IpAddrWithMask>().unwrap(), data_v6);
+        assert_eq!(db.lookup([1, 0, 0, 1].into()), Some(data_v4));
+        assert_eq!(db.lookup("2001:db8::1".parse().unwrap()), Some(data_v6));
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.ip_version, 6);
+        assert_eq!(reader.lookup::<&str>([1, 0, 0, 1].into()).unwrap(), "v4");
+        assert_eq!(
+            reader
+                .lookup::<&str>("2001:db8:ffff::1".parse().unwrap())
+                .unwrap(),
+            "v6"
+        );
+        assert!(reader.lookup::<&str>([1, 0, 1, 0].into()).is_err());
+        assert!(reader
+            .lookup::<&str>("2001:db9::".parse().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_networks() {
+        let networks = ["1.0.0.0/24", "10.0.0.0/8", "192.168.1.128/25"];
+        let mut db = Database::default();
+        for network in networks {
+            let data = db.insert_value(network).unwrap();
+            db.insert_node(network.parse::<IpAddrWithMask>().unwrap(), data);
+        }
+        let mut found: Vec<_> = db.networks().map(|(network, _)| network).collect();
+        found.sort_by_key(|network| network.addr);
+        assert_eq!(
+            found,
+            networks.map(|network| network.parse::<IpAddrWithMask>().unwrap())
+        );
+
+        let networks = ["1.0.0.0/24", "2001:db8::/32", "2002::/16"];
+        let mut db = Database::new_v6();
+        for network in networks {
+            let data = db.insert_value(network).unwrap();
+            db.insert_node(network.parse::<IpAddrWithMask>().unwrap(), data);
+        }
+        let mut found: Vec<_> = db.networks().collect();
+        found.sort_by_key(|(network, _)| (network.addr, network.mask));
+        let mut expected: Vec<_> = networks
+            .map(|network| {
+                let data = db.insert_value(network).unwrap();
+                (network.parse::<IpAddrWithMask>().unwrap(), data)
+            })
+            .into_iter()
+            .collect();
+        expected.sort_by_key(|(network, _)| (network.addr, network.mask));
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_insert_nodes() {
+        let networks = [
+            "1.0.0.0/24",
+            "10.0.0.0/8",
+            "10.1.0.0/16",
+            "192.168.1.128/25",
+        ];
+        let mut db = Database::default();
+        let mut bulk_db = Database::default();
+        let mut entries = Vec::new();
+        for network in networks {
+            let network = network.parse::<IpAddrWithMask>().unwrap();
+            let data = db.insert_value(network.to_string()).unwrap();
+            db.insert_node(network, data);
+            let bulk_data = bulk_db.insert_value(network.to_string()).unwrap();
+            entries.push((network, bulk_data));
+        }
+        bulk_db.insert_nodes(entries);
+
+        assert_eq!(bulk_db.metadata.node_count, db.metadata.node_count);
+        assert_eq!(bulk_db.to_vec().unwrap(), db.to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_reserve_network() {
+        let mut db = Database::default();
+        let data = db.insert_value("data").unwrap();
+        db.insert_node("10.0.0.0/7".parse::<IpAddrWithMask>().unwrap(), data);
+        db.reserve_network("10.0.0.0/8".parse::<IpAddrWithMask>().unwrap());
+        assert_eq!(db.lookup([10, 1, 2, 3].into()), None);
+        assert_eq!(db.lookup([11, 1, 2, 3].into()), Some(data));
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert!(reader.lookup::<&str>([10, 1, 2, 3].into()).is_err());
+        assert_eq!(reader.lookup::<&str>([11, 1, 2, 3].into()).unwrap(), "data");
+    }
+
+    #[test]
+    fn test_insert_empty_record() {
+        let network = |s: &str| s.parse::<IpAddrWithMask>().unwrap();
+        let mut db = Database::default();
+        let data = db.insert_value("data").unwrap();
+        db.insert_node(network("10.0.0.0/7"), data);
+        let empty = db.insert_empty_record(network("10.0.0.0/8")).unwrap();
+        assert_eq!(db.insert_empty_record(network("12.0.0.0/8")), Ok(empty));
+        assert_eq!(db.lookup([10, 1, 2, 3].into()), Some(empty));
+        assert_eq!(db.lookup([12, 1, 2, 3].into()), Some(empty));
+        // one byte for the empty map's control byte
+        assert_eq!(db.data.len(), "data".len() + 1 + 1);
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        type Map = std::collections::BTreeMap<String, String>;
+        for reserved in [[10, 1, 2, 3], [12, 1, 2, 3]] {
+            assert_eq!(reader.lookup::<Map>(reserved.into()).unwrap(), Map::new());
+        }
+        assert_eq!(reader.lookup::<&str>([11, 1, 2, 3].into()).unwrap(), "data");
+        assert!(reader.lookup::<Map>([13, 1, 2, 3].into()).is_err());
+    }
+
+    #[test]
+    fn test_reserve_standard_private_ranges() {
+        let mut db = Database::new_v6();
+        let data = db.insert_value("data").unwrap();
+        db.insert_node("192.168.0.0/15".parse::<IpAddrWithMask>().unwrap(), data);
+        db.insert_node("fc00::/6".parse::<IpAddrWithMask>().unwrap(), data);
+        db.reserve_standard_private_ranges();
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        for reserved in ["192.168.1.1", "fd00::1", "fe80::1"] {
+            assert!(reader.lookup::<&str>(reserved.parse().unwrap()).is_err());
+        }
+        for public in ["192.169.1.1", "fec0::1"] {
+            assert_eq!(
+                reader.lookup::<&str>(public.parse().unwrap()).unwrap(),
+                "data"
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_path() {
+        // an empty custom bit path is the default for everything, like `::/0`
+        for default in [None, Some("::/0")] {
+            let mut db = Database::new_v6();
+            let data = db.insert_value("default").unwrap();
+            match default {
+                None => db.insert_node(std::iter::empty(), data),
+                Some(network) => db.insert_node(network.parse::<IpAddrWithMask>().unwrap(), data),
+            }
+            assert_eq!(db.lookup("2001:db8::1".parse().unwrap()), Some(data));
+            assert_eq!(db.lookup([1, 2, 3, 4].into()), Some(data));
+            // the tree has no record above the root's two
+            assert_eq!(
+                db.networks().collect::<Vec<_>>(),
+                [
+                    ("::/1".parse().unwrap(), data),
+                    ("8000::/1".parse().unwrap(), data)
+                ]
+            );
+        }
+
+        // in an IPv6 database `0.0.0.0/0` only covers the IPv4 subtree
+        let mut db = Database::new_v6();
+        let data = db.insert_value("ipv4").unwrap();
+        db.insert_node("0.0.0.0/0".parse::<IpAddrWithMask>().unwrap(), data);
+        assert_eq!(db.lookup([1, 2, 3, 4].into()), Some(data));
+        assert_eq!(db.lookup("2001:db8::1".parse().unwrap()), None);
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.lookup::<&str>([1, 2, 3, 4].into()).unwrap(), "ipv4");
+    }
+
+    #[test]
+    fn test_default_route() {
+        let mut db = Database::default();
+        let data_x = db.insert_value("X").unwrap();
+        let data_y = db.insert_value("Y").unwrap();
+        db.insert_node("0.0.0.0/0".parse::<IpAddrWithMask>().unwrap(), data_x);
+        db.insert_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap(), data_y);
+        assert_eq!(db.lookup([1, 2, 3, 4].into()), Some(data_y));
+        assert_eq!(db.lookup([200, 0, 0, 1].into()), Some(data_x));
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.lookup::<&str>([1, 2, 3, 4].into()).unwrap(), "Y");
+        assert_eq!(reader.lookup::<&str>([1, 2, 4, 4].into()).unwrap(), "X");
+        assert_eq!(reader.lookup::<&str>([0, 0, 0, 0].into()).unwrap(), "X");
+        assert_eq!(
+            reader.lookup::<&str>([255, 255, 255, 255].into()).unwrap(),
+            "X"
+        );
+    }
+
+    #[test]
+    fn test_set_metadata() {
+        let mut db = Database::default();
+        let data = db.insert_value("data").unwrap();
+        db.insert_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        db.set_metadata(
+            metadata::MetadataBuilder::new()
+                .database_type("Test")
+                .build()
+                .unwrap(),
+        );
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.database_type, "Test");
+        assert_eq!(reader.metadata.node_count, 24);
+        assert_eq!(reader.lookup::<&str>([1, 2, 3, 4].into()).unwrap(), "data");
+    }
+
+    #[test]
+    fn test_ip_version_mismatch() {
+        let mut db = Database::default();
+        let data = db.insert_value("data").unwrap();
+        db.insert_node("2001:db8::/32".parse::<IpAddrWithMask>().unwrap(), data);
+        assert!(matches!(
+            db.to_vec(),
+            Err(serializer::Error::IpVersionMismatch)
+        ));
+
+        // IPv4 networks inserted before switching to IPv6 aren't in the IPv4 subtree
+        let mut db = Database::default();
+        let data = db.insert_value("data").unwrap();
+        db.insert_node("1.0.0.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        db.metadata.ip_version = metadata::IpVersion::V6;
+        assert!(matches!(
+            db.to_vec(),
+            Err(serializer::Error::IpVersionMismatch)
+        ));
+
+        let mut db = Database::new_v6();
+        let data = db.insert_value("data").unwrap();
+        db.insert_node("1.0.0.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        db.insert_node("2001:db8::/32".parse::<IpAddrWithMask>().unwrap(), data);
+        assert!(db.to_vec().is_ok());
+
+        // raw bit paths aren't checked
+        let mut db = Database::default();
+        let data = db.insert_value("data").unwrap();
+        db.insert_node([true; 64].into_iter(), data);
+        assert!(db.to_vec().is_ok());
+    }
+
+    #[test]
+    fn test_write_to_file() {
+        let path = std::env::temp_dir().join(format!(
+            "maxminddb-writer-test-write-{}.mmdb",
+            std::process::id()
+        ));
+        let mut db = Database::default();
+        let data = db.insert_value("data").unwrap();
+        db.insert_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+
+        assert!(matches!(
+            db.write_to_file(&path),
+            Err(serializer::Error::InvalidMetadata(
+                metadata::MetadataError::EmptyDatabaseType
+            ))
+        ));
+        assert!(!path.exists());
+
+        db.metadata.database_type = "Test".to_string();
+        db.write_to_file(&path).unwrap();
+        let reader = maxminddb::Reader::open_readfile(&path).unwrap();
+        assert_eq!(reader.metadata.database_type, "Test");
+        assert_eq!(reader.lookup::<&str>([1, 2, 3, 4].into()).unwrap(), "data");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_to_counted() {
+        let mut db = Database::default();
+        let (raw_db, count) = db.write_to_counted(Vec::new()).unwrap();
+        assert_eq!(count, raw_db.len() as u64);
+
+        db.metadata.add_description("en", "Test");
+        let data = db.insert_value(vec!["a"; 1000]).unwrap();
+        db.insert_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        let (raw_db, count) = db.write_to_counted(Vec::new()).unwrap();
+        assert_eq!(count, raw_db.len() as u64);
+        assert_eq!(raw_db, db.to_vec().unwrap());
+    }
+
+    fn database_with(networks: &[(&str, &str)]) -> Database {
+        let mut db = Database::default();
+        for (network, value) in networks {
+            let data = db.insert_value(value).unwrap();
+            db.insert_node(network.parse::<IpAddrWithMask>().unwrap(), data);
+        }
+        db
+    }
+
+    fn lookup_str(raw_db: &[u8], addr: [u8; 4]) -> Option<String> {
+        let reader = maxminddb::Reader::from_source(raw_db).unwrap();
+        reader.lookup::<String>(addr.into()).ok()
+    }
+
+    #[test]
+    fn test_merge() {
+        let base = [
+            ("1.0.0.0/8", "base"),
+            ("2.0.0.0/8", "same"),
+            ("3.0.0.0/8", "base"),
+        ];
+        let overlay = [
+            ("1.0.0.0/8", "overlay"),
+            ("2.0.0.0/8", "same"),
+            ("3.3.0.0/16", "overlay"),
+            ("4.0.0.0/8", "overlay"),
+        ];
+        for (policy, expected) in [
+            (MergePolicy::PreferSelf, "base"),
+            (MergePolicy::PreferOther, "overlay"),
+        ] {
+            let mut db = database_with(&base);
+            db.merge(&database_with(&overlay), policy).unwrap();
+            let raw_db = db.to_vec().unwrap();
+            assert_eq!(lookup_str(&raw_db, [1, 1, 1, 1]).unwrap(), expected);
+            assert_eq!(lookup_str(&raw_db, [2, 2, 2, 2]).unwrap(), "same");
+            assert_eq!(lookup_str(&raw_db, [3, 3, 3, 3]).unwrap(), "overlay");
+            assert_eq!(lookup_str(&raw_db, [3, 4, 3, 3]).unwrap(), "base");
+            assert_eq!(lookup_str(&raw_db, [4, 4, 4, 4]).unwrap(), "overlay");
+            assert_eq!(lookup_str(&raw_db, [5, 5, 5, 5]), None);
+            // values are shared between the databases
+            assert_eq!(raw_db.windows(5).filter(|w| w == b"\x44same").count(), 1);
+        }
+
+        // less specific networks don't replace more specific ones
+        let mut db = database_with(&[("3.3.0.0/16", "base")]);
+        db.merge(
+            &database_with(&[("3.0.0.0/8", "overlay")]),
+            MergePolicy::PreferOther,
+        )
+        .unwrap();
+        let raw_db = db.to_vec().unwrap();
+        assert_eq!(lookup_str(&raw_db, [3, 3, 3, 3]).unwrap(), "base");
+        assert_eq!(lookup_str(&raw_db, [3, 4, 3, 3]).unwrap(), "overlay");
+    }
+
+    #[test]
+    fn test_merge_error_policy() {
+        let mut db = database_with(&[("1.0.0.0/8", "base"), ("2.0.0.0/8", "same")]);
+        let expected = db.to_vec().unwrap();
+
+        let disjoint = database_with(&[("2.0.0.0/8", "same"), ("4.0.0.0/8", "overlay")]);
+        let conflicting = database_with(&[("4.0.0.0/8", "overlay"), ("1.0.0.0/8", "overlay")]);
+        assert!(matches!(
+            db.merge(&conflicting, MergePolicy::Error),
+            Err(serializer::Error::MergeConflict(network)) if network.to_string() == "1.0.0.0/8"
+        ));
+        assert_eq!(db.to_vec().unwrap(), expected);
+
+        db.merge(&disjoint, MergePolicy::Error).unwrap();
+        let raw_db = db.to_vec().unwrap();
+        assert_eq!(lookup_str(&raw_db, [1, 1, 1, 1]).unwrap(), "base");
+        assert_eq!(lookup_str(&raw_db, [4, 4, 4, 4]).unwrap(), "overlay");
+    }
+
+    #[test]
+    fn test_merge_split_networks() {
+        // the /8 of either side is split by a more specific network
+        let split = [("10.0.0.0/8", "split"), ("10.1.0.0/16", "specific")];
+        let whole = [("10.0.0.0/8", "whole")];
+        for (base, overlay) in [(&split[..], &whole[..]), (&whole[..], &split[..])] {
+            for (policy, expected) in [
+                (MergePolicy::PreferSelf, base[0].1),
+                (MergePolicy::PreferOther, overlay[0].1),
+            ] {
+                let mut db = database_with(base);
+                let data_len = db.data.len();
+                db.merge(&database_with(overlay), policy).unwrap();
+                let raw_db = db.to_vec().unwrap();
+                assert_eq!(lookup_str(&raw_db, [10, 2, 0, 1]).unwrap(), expected);
+                assert_eq!(lookup_str(&raw_db, [10, 1, 0, 1]).unwrap(), "specific");
+                if policy == MergePolicy::PreferSelf && base.len() == 2 {
+                    // nothing of the overlay is used, so nothing is copied
+                    assert_eq!(db.data.len(), data_len);
+                }
+            }
+
+            let mut db = database_with(base);
+            let expected = db.to_vec().unwrap();
+            assert!(matches!(
+                db.merge(&database_with(overlay), MergePolicy::Error),
+                Err(serializer::Error::MergeConflict(network)) if network.to_string() == "10.0.0.0/8"
+            ));
+            assert_eq!(db.to_vec().unwrap(), expected);
+        }
+
+        // same data for the split network isn't a conflict
+        let mut db = database_with(&split);
+        db.merge(
+            &database_with(&[("10.0.0.0/8", "split")]),
+            MergePolicy::Error,
+        )
+        .unwrap();
+        let raw_db = db.to_vec().unwrap();
+        assert_eq!(lookup_str(&raw_db, [10, 2, 0, 1]).unwrap(), "split");
+        assert_eq!(lookup_str(&raw_db, [10, 1, 0, 1]).unwrap(), "specific");
+    }
+
+    #[test]
+    fn test_size_writer() {
+        let spill_path = std::env::temp_dir().join(format!(
+            "maxminddb-writer-test-size-writer-{}",
+            std::process::id()
+        ));
+        let mut spilled = Database::with_spilling_datastore(&spill_path).unwrap();
+        for i in 0..10_000u32 {
+            let data = spilled.insert_value(format!("value {}", i)).unwrap();
+            spilled.insert_node(IpAddrWithMask::from(std::net::Ipv4Addr::from(i << 8)), data);
+        }
+        let mut v6 = Database::new_v6();
+        v6.metadata.add_description("en", "Test");
+        let data = v6.insert_value(vec!["a"; 1000]).unwrap();
+        v6.insert_node("2001:db8::/32".parse::<IpAddrWithMask>().unwrap(), data);
+
+        for db in [
+            Database::default(),
+            database_with(&[("1.0.0.0/8", "one"), ("2.0.0.0/8", "two")]),
+            spilled,
+            v6,
+        ] {
+            let size = db.write_to(SizeWriter::default()).unwrap().size();
+            assert_eq!(size, db.to_vec().unwrap().len() as u64);
+            assert_eq!(size, db.stats().unwrap().estimated_size as u64);
+        }
+        std::fs::remove_file(&spill_path).unwrap();
+    }
+
+    #[test]
+    fn test_overlapping_insert_order() {
+        let networks = [
+            ("1.0.0.0/8", "/8"),
+            ("1.2.0.0/16", "/16"),
+            ("1.2.3.0/24", "/24"),
+            ("1.2.3.128/25", "/25"),
+        ];
+        let expected = [
+            ([1, 1, 1, 1], "/8"),
+            ([1, 2, 1, 1], "/16"),
+            ([1, 2, 3, 1], "/24"),
+            ([1, 2, 3, 200], "/25"),
+        ];
+        let most_specific_first = database_with(&networks).to_vec().unwrap();
+        let mut reversed = networks;
+        reversed.reverse();
+        let least_specific_first = database_with(&reversed).to_vec().unwrap();
+        for raw_db in [most_specific_first, least_specific_first] {
+            for (addr, value) in expected {
+                assert_eq!(lookup_str(&raw_db, addr).unwrap(), value);
+            }
+            assert_eq!(lookup_str(&raw_db, [2, 0, 0, 0]), None);
+        }
+    }
+
+    /// Accepts `remaining` bytes, then fails.
+    #[derive(Debug)]
+    struct FailingWriter {
+        remaining: usize,
+    }
+
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::other("sink failed"));
+            }
+            let len = buf.len().min(self.remaining);
+            self.remaining -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_error_section() {
+        let db = database_with(&[("1.0.0.0/8", "one"), ("10.0.0.0/8", "ten")]);
+        let stats = db.stats().unwrap();
+        let nodes_size = stats.node_count * 6;
+        let data_end = nodes_size + DATA_SECTION_SEPARATOR_LEN + stats.data_section_size;
+        for (remaining, expected) in [
+            (0, Section::Nodes),
+            (nodes_size - 1, Section::Nodes),
+            (nodes_size, Section::DataSection),
+            (data_end - 1, Section::DataSection),
+            (data_end, Section::Metadata),
+            (stats.estimated_size - 1, Section::Metadata),
+        ] {
+            match db.write_to(FailingWriter { remaining }) {
+                Err(serializer::Error::WhileWriting { section, source }) => {
+                    assert_eq!(section, expected, "failing after {} bytes", remaining);
+                    assert_eq!(source.to_string(), "sink failed");
+                }
+                other => panic!("unexpected result after {} bytes: {:?}", remaining, other),
+            }
+        }
+        assert!(db
+            .write_to(FailingWriter {
+                remaining: stats.estimated_size
+            })
+            .is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "different database")]
+    fn test_data_ref_from_other_database() {
+        let mut db = Database::default();
+        let mut other = Database::default();
+        db.insert_value("db").unwrap();
+        let data = other.insert_value("other").unwrap();
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+    }
+
+    #[test]
+    fn test_pointer() {
+        #[derive(Serialize)]
+        struct Asn {
+            number: u32,
+            network: Pointer,
+        }
+
+        let mut db = Database::default();
+        let shared = std::collections::BTreeMap::from([("name", "shared network")]);
+        let network = db.insert_value(&shared).unwrap();
+        for (number, path) in [(1, false), (2, true)] {
+            let asn = db
+                .insert_value(Asn {
+                    number,
+                    network: Pointer(network),
+                })
+                .unwrap();
+            db.insert_node([path].into_iter(), asn);
+        }
+        let raw_db = db.to_vec().unwrap();
+
+        let reader = maxminddb::Reader::from_source(raw_db.as_slice()).unwrap();
+        for (addr, number) in [([1, 2, 3, 4], 1), ([200, 2, 3, 4], 2)] {
+            let value: std::collections::BTreeMap<String, value::DataValue> = reader
+                .lookup(std::net::Ipv4Addr::from(addr).into())
+                .unwrap();
+            assert_eq!(value["number"], value::DataValue::Uint32(number));
+            assert_eq!(
+                value["network"],
+                value::DataValue::Map(vec![(
+                    "name".into(),
+                    value::DataValue::String("shared network".into())
+                )])
+            );
+        }
+
+        let mut map_bytes = Vec::new();
+        shared
+            .serialize(&mut serializer::Serializer::new(&mut map_bytes))
+            .unwrap();
+        let occurrences = raw_db
+            .windows(map_bytes.len())
+            .filter(|window| *window == map_bytes)
+            .count();
+        assert_eq!(occurrences, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "DataRef used with a different database")]
+    fn test_pointer_to_other_database() {
+        let mut other = Database::default();
+        let data = other.insert_value("other").unwrap();
+        Database::default().insert_value(Pointer(data)).unwrap();
+    }
+
+    #[test]
+    fn test_merge_pointers() {
+        let mut db = Database::default();
+        let filler = db.insert_value("filler filler filler").unwrap();
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), filler);
+
+        let mut other = Database::default();
+        let shared = other.insert_value("pointed to").unwrap();
+        for (network, number) in [("2.0.0.0/8", 2u32), ("3.0.0.0/8", 3)] {
+            let record = other
+                .insert_value(std::collections::BTreeMap::from([
+                    ("number", value::DataValue::Uint32(number)),
+                    ("shared", value::DataValue::String("inline".into())),
+                ]))
+                .unwrap();
+            let pointing = other
+                .insert_value((number, Pointer(shared), Pointer(record)))
+                .unwrap();
+            other.insert_node(network.parse::<IpAddrWithMask>().unwrap(), pointing);
+        }
+        db.merge(&other, MergePolicy::Error).unwrap();
+        // merging the same values again conflicts with nothing
+        db.merge(&other, MergePolicy::Error).unwrap();
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(raw_db.as_slice()).unwrap();
+        for (addr, number) in [([2, 0, 0, 1], 2), ([3, 0, 0, 1], 3)] {
+            let (read_number, shared, record): (u32, String, value::DataValue) = reader
+                .lookup(std::net::Ipv4Addr::from(addr).into())
+                .unwrap();
+            assert_eq!(read_number, number);
+            assert_eq!(shared, "pointed to");
+            assert_eq!(
+                record,
+                value::DataValue::Map(vec![
+                    ("number".into(), value::DataValue::Uint32(number)),
+                    ("shared".into(), value::DataValue::String("inline".into())),
+                ])
+            );
+        }
+        // the pointed to value is copied once
+        assert_eq!(
+            raw_db
+                .windows(11)
+                .filter(|w| w == b"\x4apointed to")
+                .count(),
+            1
+        );
+
+        // values losing every conflict aren't copied, nor the values they point to
+        let mut db = database_with(&[("2.0.0.0/8", "base"), ("3.0.0.0/8", "base")]);
+        let data_len = db.data.len();
+        db.merge(&other, MergePolicy::PreferSelf).unwrap();
+        assert_eq!(db.data.len(), data_len);
+    }
+
+    #[test]
+    fn test_record_size_and_node_count() {
+        let mut db = Database::default();
+        let data = db.insert_value("small").unwrap();
+        db.insert_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        assert_eq!(db.node_count(), 24);
+        assert_eq!(db.record_size(), metadata::RecordSize::Small);
+
+        // push the data section past what 24 bit records can address
+        let data = db.insert_value("x".repeat(1 << 24)).unwrap();
+        db.insert_node("10.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+        assert_eq!(db.record_size(), metadata::RecordSize::Medium);
+        db.pin_record_size(metadata::RecordSize::Large);
+        assert_eq!(db.record_size(), metadata::RecordSize::Large);
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(raw_db).unwrap();
+        assert_eq!(reader.metadata.node_count, db.node_count());
+        assert_eq!(reader.metadata.record_size, 32);
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut writer = Crc32Writer {
+            inner: std::io::sink(),
+            crc: Some(!0),
+        };
+        std::io::Write::write_all(&mut writer, bytes).unwrap();
+        !writer.crc.unwrap()
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut db = database_with(&[("1.0.0.0/8", "one"), ("10.0.0.0/8", "ten")]);
+        let without = db.to_vec().unwrap();
+        assert!(!without
+            .windows(metadata::CHECKSUM_KEY.len())
+            .any(|window| window == metadata::CHECKSUM_KEY.as_bytes()));
+
+        db.set_checksum(true);
+        let raw_db = db.to_vec().unwrap();
+        assert_eq!(raw_db.len(), db.stats().unwrap().estimated_size);
+        let marker = raw_db
+            .windows(metadata::METADATA_START_MARKER.len())
+            .rposition(|window| window == metadata::METADATA_START_MARKER)
+            .unwrap();
+        let key = raw_db
+            .windows(metadata::CHECKSUM_KEY.len())
+            .position(|window| window == metadata::CHECKSUM_KEY.as_bytes())
+            .unwrap();
+        // the key is followed by a string control byte and the 8 digits
+        let value_start = key + metadata::CHECKSUM_KEY.len();
+        assert_eq!(raw_db[value_start], 0x48);
+        let written = std::str::from_utf8(&raw_db[value_start + 1..value_start + 9]).unwrap();
+        assert_eq!(written, format!("{:08x}", crc32(&raw_db[..marker])));
+        // the search tree and data are the same, only the metadata differs
+        assert_eq!(raw_db[..marker], without[..marker]);
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.lookup::<&str>([1, 2, 3, 4].into()).unwrap(), "one");
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut db = Database::with_capacity(1000, 1000);
+        db.metadata.database_type = "Test".to_string();
+        let old_data = db.insert_value("old").unwrap();
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), old_data);
+        let capacity = db.capacity();
+        assert!(capacity.0 >= 1000 && capacity.1 >= 1000);
+
+        db.clear(true);
+        assert_eq!(db.capacity(), capacity);
+        assert_eq!(db.node_count(), 1);
+        assert_eq!(db.metadata.database_type, "Test");
+        assert_eq!(db.lookup([1, 2, 3, 4].into()), None);
+
+        let data = db.insert_value("new").unwrap();
+        db.insert_node("2.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+        let mut expected = database_with(&[("2.0.0.0/8", "new")]);
+        expected.metadata.database_type = "Test".to_string();
+        assert_eq!(db.to_vec().unwrap(), expected.to_vec().unwrap());
+
+        db.clear(false);
+        assert_eq!(db.capacity(), capacity);
+        assert_eq!(db.metadata.database_type, "");
+        assert_eq!(db.to_vec().unwrap(), Database::default().to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_trace() {
+        let network = |s: &str| s.parse::<IpAddrWithMask>().unwrap();
+
+        let mut db = Database::default();
+        let wide = db.insert_value("wide").unwrap();
+        let narrow = db.insert_value("narrow").unwrap();
+        db.insert_node(network("1.0.0.0/8"), wide);
+        db.insert_node(network("1.2.3.0/24"), narrow);
+        let nodes_until = |depth: u8| (1..depth).map(|depth| (depth, None));
+
+        let trace = db.trace([1, 2, 3, 4].into());
+        assert!(trace
+            .iter()
+            .copied()
+            .eq(nodes_until(24).chain([(24, Some(narrow))])));
+        // the /8 is split where the /24 branches off
+        let trace = db.trace([1, 2, 4, 4].into());
+        assert!(trace
+            .iter()
+            .copied()
+            .eq(nodes_until(22).chain([(22, Some(wide))])));
+        let trace = db.trace([1, 128, 0, 1].into());
+        assert!(trace
+            .iter()
+            .copied()
+            .eq(nodes_until(9).chain([(9, Some(wide))])));
+        // 2.0.0.0 leaves the path to 1.0.0.0 on the 7th bit, at an empty record
+        let trace = db.trace([2, 0, 0, 1].into());
+        assert!(trace.iter().copied().eq(nodes_until(7).chain([(7, None)])));
+
+        let mut db = Database::new_v6();
+        let ipv4 = db.insert_value("ipv4").unwrap();
+        db.insert_node(network("1.2.0.0/16"), ipv4);
+        let trace = db.trace([1, 2, 3, 4].into());
+        assert_eq!(trace.last(), Some(&(96 + 16, Some(ipv4))));
+        assert_eq!(trace.len(), 96 + 16);
+    }
+
+    #[test]
+    fn test_lookup_prefix() {
+        let network = |s: &str| s.parse::<IpAddrWithMask>().unwrap();
+
+        let mut db = Database::default();
+        let wide = db.insert_value("wide").unwrap();
+        let narrow = db.insert_value("narrow").unwrap();
+        db.insert_node(network("1.0.0.0/8"), wide);
+        db.insert_node(network("1.2.3.0/24"), narrow);
+        assert_eq!(
+            db.lookup_prefix([1, 2, 3, 4].into()),
+            Some((network("1.2.3.0/24"), narrow))
+        );
+        // the piece of 1.0.0.0/8 the /24 split off
+        assert_eq!(
+            db.lookup_prefix([1, 2, 4, 4].into()),
+            Some((network("1.2.4.0/22"), wide))
+        );
+        assert_eq!(
+            db.lookup_prefix([1, 128, 0, 1].into()),
+            Some((network("1.128.0.0/9"), wide))
+        );
+        assert_eq!(db.lookup_prefix([2, 0, 0, 1].into()), None);
+
+        let mut db = Database::new_v6();
+        let ipv4 = db.insert_value("ipv4").unwrap();
+        let ipv6 = db.insert_value("ipv6").unwrap();
+        let default = db.insert_value("default").unwrap();
+        db.insert_node(network("1.2.0.0/16"), ipv4);
+        db.insert_node(network("2001:db8::/32"), ipv6);
+        db.insert_node(network("::/0"), default);
+        assert_eq!(
+            db.lookup_prefix([1, 2, 3, 4].into()),
+            Some((network("1.2.0.0/16"), ipv4))
+        );
+        assert_eq!(
+            db.lookup_prefix("2001:db8::1".parse().unwrap()),
+            Some((network("2001:db8::/32"), ipv6))
+        );
+        assert_eq!(
+            db.lookup_prefix([8, 8, 8, 8].into()),
+            Some((network("8.0.0.0/5"), default))
+        );
+        assert_eq!(
+            db.lookup_prefix("8000::1".parse().unwrap()),
+            Some((network("8000::/1"), default))
+        );
+    }
+
+    #[test]
+    fn test_alignment_padding() {
+        for record_size in [
+            metadata::RecordSize::Small,
+            metadata::RecordSize::Medium,
+            metadata::RecordSize::Large,
+        ] {
+            for alignment in [1, 2, 4, 8, 16, 64, 4096] {
+                for node_count in 1..100 {
+                    let padding = alignment_padding(node_count, record_size, alignment);
+                    let expected = (0..)
+                        .find(|padding| {
+                            ((node_count + padding) * record_size.node_size()
+                                + DATA_SECTION_SEPARATOR_LEN)
+                                .is_multiple_of(alignment)
+                        })
+                        .unwrap();
+                    assert_eq!(padding, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_align_data_section() {
+        let networks = [
+            ("1.0.0.0/8", "one"),
+            ("2.0.0.0/16", "two"),
+            ("3.0.0.0/24", "three"),
+        ];
+        for (alignment, record_size) in [
+            (64, None),
+            (4096, None),
+            (4096, Some(metadata::RecordSize::Medium)),
+            (4096, Some(metadata::RecordSize::Large)),
+        ] {
+            let mut db = database_with(&networks);
+            if let Some(record_size) = record_size {
+                db.pin_record_size(record_size);
+            }
+            db.align_data_section(alignment);
+            assert!(db.node_count() as usize > db.nodes.len());
+            let raw_db = db.to_vec().unwrap();
+            assert_eq!(db.stats().unwrap().estimated_size, raw_db.len());
+
+            let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+            let data_section_start =
+                reader.metadata.node_count as usize * reader.metadata.record_size as usize / 4
+                    + DATA_SECTION_SEPARATOR_LEN;
+            assert!(data_section_start.is_multiple_of(alignment));
+            assert_eq!(
+                &raw_db[data_section_start - 16..data_section_start],
+                [0; 16]
+            );
+            for (addr, expected) in [
+                ([1, 2, 3, 4], Some("one")),
+                ([2, 0, 3, 4], Some("two")),
+                ([3, 0, 0, 4], Some("three")),
+                ([4, 0, 0, 0], None),
+            ] {
+                assert_eq!(reader.lookup::<&str>(addr.into()).ok(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_too_many_nodes() {
+        // the padding for an absurd alignment doesn't fit in the node count
+        let mut db = Database::default();
+        db.align_data_section(1 << 40);
+        let data = db.insert_value("data").unwrap();
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+        assert!(db.layout(db.nodes.len()).0 > u32::MAX as usize);
+        assert_eq!(db.node_count(), u32::MAX);
+        assert_eq!(db.to_vec(), Err(serializer::Error::TooManyNodes));
+        assert_eq!(db.write_sections(), Err(serializer::Error::TooManyNodes));
+    }
+
+    #[test]
+    #[should_panic(expected = "not a power of two")]
+    fn test_align_data_section_power_of_two() {
+        Database::default().align_data_section(24);
+    }
+
+    #[test]
+    fn test_write_sections() {
+        let networks = [("1.0.0.0/8", "one"), ("2.0.0.0/16", "two")];
+        for checksum in [false, true] {
+            let mut db = database_with(&networks);
+            db.metadata.database_type = "Test".to_string();
+            db.set_checksum(checksum);
+            let sections = db.write_sections().unwrap();
+            assert_eq!(sections.nodes.len(), db.node_count() as usize * 6);
+
+            let mut assembled = sections.nodes;
+            assembled.extend([0; 16]);
+            assembled.extend(sections.data_section);
+            assembled.extend(metadata::METADATA_START_MARKER);
+            assembled.extend(sections.metadata);
+            assert_eq!(assembled, db.to_vec().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_write_to_cursor() {
+        let db = database_with(&[("1.0.0.0/8", "one"), ("2.0.0.0/16", "two")]);
+        let mut cursor = std::io::Cursor::new(b"header".to_vec());
+        cursor.set_position(6);
+
+        let cursor = db.write_to(cursor).unwrap();
+        let written = cursor.into_inner();
+        assert_eq!(&written[..6], b"header");
+        assert_eq!(&written[6..], db.to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let mut db = database_with(&[("1.0.0.0/8", "one"), ("2.0.0.0/16", "two")]);
+        db.metadata.database_type = "Test".to_string();
+        let bytes = db.to_bytes().unwrap();
+        assert_eq!(bytes, db.write_to(Vec::new()).unwrap());
+        // allocated once, with the right size
+        assert_eq!(bytes.capacity(), bytes.len());
+
+        let reader = maxminddb::Reader::from_source(&bytes).unwrap();
+        assert_eq!(reader.metadata.database_type, "Test");
+        assert_eq!(reader.lookup::<&str>([1, 2, 3, 4].into()).ok(), Some("one"));
+        assert_eq!(reader.lookup::<&str>([2, 0, 3, 4].into()).ok(), Some("two"));
+    }
+
+    #[test]
+    fn test_insert_range() {
+        let mut db = Database::default();
+        let data = db
+            .insert_range([1, 2, 3, 0].into(), [1, 2, 3, 255].into(), "range")
+            .unwrap();
+        let odd = db
+            .insert_range([1, 2, 4, 1].into(), [1, 2, 4, 6].into(), "range")
+            .unwrap();
+        assert_eq!(odd, data);
+        assert_eq!(
+            db.insert_range([1, 2, 3, 4].into(), [1, 2, 3, 3].into(), "invalid"),
+            Err(serializer::Error::InvalidRange(
+                paths::RangeError::EndBeforeStart
+            ))
+        );
+        assert_eq!(db.get_ref("invalid").unwrap(), None);
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        for last in 0..=255 {
+            assert_eq!(
+                reader.lookup::<&str>([1, 2, 3, last].into()).unwrap(),
+                "range"
+            );
+        }
+        assert!(reader.lookup::<&str>([1, 2, 2, 255].into()).is_err());
+        assert!(reader.lookup::<&str>([1, 2, 4, 0].into()).is_err());
+        assert_eq!(reader.lookup::<&str>([1, 2, 4, 6].into()).unwrap(), "range");
+        assert!(reader.lookup::<&str>([1, 2, 4, 7].into()).is_err());
+    }
+
+    #[test]
+    fn test_write_to_seekable() {
+        let mut db = database_with(&[("1.0.0.0/8", "one"), ("2.0.0.0/16", "two")]);
+        let raw_db = db.to_vec().unwrap();
+        let mut cursor = std::io::Cursor::new(b"header".to_vec());
+        cursor.set_position(6);
+
+        let cursor = db.write_to_seekable(cursor).unwrap();
+        assert_eq!(cursor.position(), 6 + raw_db.len() as u64);
+        let written = cursor.into_inner();
+        assert_eq!(&written[..6], b"header");
+        assert_eq!(&written[6..], raw_db);
+
+        db.set_checksum(true);
+        let cursor = db
+            .write_to_seekable(std::io::Cursor::new(Vec::new()))
+            .unwrap();
+        assert_eq!(cursor.into_inner(), db.to_bytes().unwrap());
+
+        let cursor = Database::default()
+            .write_to_seekable(std::io::Cursor::new(Vec::new()))
+            .unwrap();
+        assert_eq!(cursor.into_inner(), Database::default().to_bytes().unwrap());
+    }
+
+    #[test]
+    fn test_insert_node_checked() {
+        let mut db = Database::default();
+        let a = db.insert_value("A").unwrap();
+        let b = db.insert_value("B").unwrap();
+        let network = "1.2.3.0/24".parse::<IpAddrWithMask>().unwrap();
+
+        // a less specific network isn't overwritten
+        assert_eq!(
+            db.insert_node_checked("1.2.0.0/16".parse::<IpAddrWithMask>().unwrap(), a),
+            None
+        );
+        assert_eq!(db.insert_node_checked(network, a), None);
+        assert_eq!(db.insert_node_checked(network, b), Some(a));
+        assert_eq!(db.lookup([1, 2, 3, 200].into()), Some(b));
+        assert_eq!(
+            lookup_str(&db.to_vec().unwrap(), [1, 2, 3, 200]),
+            Some("B".to_string())
+        );
+    }
+
+    #[test]
+    fn test_map_data_values() {
+        use value::DataValue;
+
+        fn upper(value: &mut DataValue) {
+            match value {
+                DataValue::String(s) => *s = s.to_uppercase(),
+                DataValue::Map(entries) => entries.iter_mut().for_each(|(_, v)| upper(v)),
+                DataValue::Array(values) => values.iter_mut().for_each(upper),
+                _ => {}
+            }
+        }
+
+        let record = |name: &str| {
+            DataValue::Map(vec![
+                ("name".to_string(), DataValue::String(name.to_string())),
+                ("id".to_string(), DataValue::Uint32(70_000)),
+                ("score".to_string(), DataValue::Int32(-3)),
+                (
+                    "tags".to_string(),
+                    DataValue::Array(vec![
+                        DataValue::String("a".to_string()),
+                        DataValue::Boolean(true),
+                    ]),
+                ),
+                ("ratio".to_string(), DataValue::Double(0.5)),
+            ])
+        };
+        let networks = [
+            ("1.0.0.0/8", "one"),
+            ("2.0.0.0/8", "One"),
+            ("3.0.0.0/8", "two"),
+        ];
+
+        let mut db = Database::default();
+        for (network, name) in networks {
+            let data = db.insert_value(record(name)).unwrap();
+            db.insert_node(network.parse::<IpAddrWithMask>().unwrap(), data);
+        }
+        let shared = db.insert_value(record("shared")).unwrap();
+        let pointing = db.insert_value(Pointer(shared)).unwrap();
+        db.insert_node("4.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), pointing);
+
+        db.map_data_values(upper).unwrap();
+
+        let mut expected = Database::default();
+        for (network, name) in networks.into_iter().chain([("4.0.0.0/8", "shared")]) {
+            let mut value = record(name);
+            upper(&mut value);
+            let data = expected.insert_value(value).unwrap();
+            expected.insert_node(network.parse::<IpAddrWithMask>().unwrap(), data);
+        }
+        assert_eq!(db.to_vec().unwrap(), expected.to_vec().unwrap());
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let mut expected_value = record("one");
+        upper(&mut expected_value);
+        assert_eq!(
+            reader.lookup::<DataValue>([2, 0, 0, 1].into()).unwrap(),
+            expected_value
+        );
+    }
+
+    #[test]
+    fn test_map_data_values_failing() {
+        use value::DataValue;
+
+        let mut db = database_with(&[("1.0.0.0/8", "one"), ("2.0.0.0/8", "two")]);
+        db.set_strict_floats(true);
+        let before = db.to_bytes().unwrap();
+        let data = db.lookup("2.0.0.1".parse().unwrap()).unwrap();
+
+        let mut calls = 0;
+        let result = db.map_data_values(|value| {
+            calls += 1;
+            if calls == 2 {
+                *value = DataValue::Double(f64::NAN);
+            }
+        });
+        assert_eq!(result, Err(serializer::Error::NonFiniteFloat));
+        assert_eq!(db.to_bytes().unwrap(), before);
+        assert_eq!(db.lookup("2.0.0.1".parse().unwrap()), Some(data));
+        db.insert_node("3.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+    }
+
+    #[test]
+    #[should_panic(expected = "different database")]
+    fn test_clear_invalidates_data_refs() {
+        let mut db = Database::default();
+        let data = db.insert_value("old").unwrap();
+        db.clear(true);
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let networks = [
+            ("1.0.0.0/8", "one"),
+            ("1.2.3.0/24", "one two three"),
+            ("10.0.0.0/8", "ten"),
+        ];
+        let expected = database_with(&networks).to_vec().unwrap();
+        for (node_capacity, data_capacity) in [(0, 0), (1, 1), (1000, 1000)] {
+            let mut db = Database::with_capacity(node_capacity, data_capacity);
+            for (network, value) in networks {
+                let data = db.insert_value(value).unwrap();
+                db.insert_node(network.parse::<IpAddrWithMask>().unwrap(), data);
+            }
+            assert_eq!(db.to_vec().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_pin_record_size() {
+        let mut db = Database::default();
+        db.pin_record_size(metadata::RecordSize::Large);
+        let data = db.insert_value("US").unwrap();
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+        assert_eq!(db.metadata.record_size, metadata::RecordSize::Large);
+        assert_eq!(db.stats().unwrap().record_size, metadata::RecordSize::Large);
+        let raw_db = db.to_vec().unwrap();
+        // 32 bit records: 8 byte nodes
+        assert_eq!(
+            &raw_db[db.nodes.len() * 8..][..DATA_SECTION_SEPARATOR_LEN],
+            &[0; DATA_SECTION_SEPARATOR_LEN]
+        );
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.record_size, 32);
+        assert_eq!(reader.lookup::<&str>([1, 2, 3, 4].into()).unwrap(), "US");
+
+        // outgrowing the pinned size
+        let mut db = Database::default();
+        db.pin_record_size(metadata::RecordSize::Small);
+        for c in ['a', 'b', 'c'] {
+            let data = db.insert_value(c.to_string().repeat(6 << 20)).unwrap();
+            db.insert_node(
+                IpAddrWithMask::from(std::net::Ipv4Addr::from(c as u32)),
+                data,
+            );
+        }
+        assert_eq!(db.metadata.record_size, metadata::RecordSize::Small);
+        assert!(matches!(
+            db.to_vec(),
+            Err(serializer::Error::DatabaseTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_data_section_separator() {
+        let mut db = Database::default();
+        let data = db.insert_value("first").unwrap();
+        assert_eq!(data.index, 0);
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+        let node_count = db.nodes.len();
+        assert_eq!(
+            data.data_section_offset(node_count),
+            node_count + DATA_SECTION_SEPARATOR_LEN
+        );
+
+        let raw_db = db.to_vec().unwrap();
+        let tree_size = node_count * db.metadata.record_size.node_size();
+        let (separator, data_section) = raw_db[tree_size..].split_at(DATA_SECTION_SEPARATOR_LEN);
+        assert_eq!(separator, &[0; DATA_SECTION_SEPARATOR_LEN]);
+        assert!(data_section.starts_with(b"\x45first"));
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.lookup::<&str>([1, 2, 3, 4].into()).unwrap(), "first");
+    }
+
+    #[test]
+    fn test_ipv4_aliases() {
+        let mut db = Database::new_v6();
+        let data = db.insert_value("v4").unwrap();
+        db.insert_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        let data = db.insert_value("v6").unwrap();
+        db.insert_node("2001:db8::/32".parse::<IpAddrWithMask>().unwrap(), data);
+        db.add_ipv4_aliases().unwrap();
+        // covering an alias doesn't leak into the IPv4 networks
+        let data = db.insert_value("2000::/3").unwrap();
+        db.insert_node("2000::/3".parse::<IpAddrWithMask>().unwrap(), data);
+        let v4 = db.get_ref("v4").unwrap().unwrap();
+        let v4_networks = db
+            .networks()
+            .filter(|(_, data)| *data == v4)
+            .map(|(network, _)| network.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(v4_networks, ["1.2.3.0/24"]);
+
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        for addr in [
+            "1.2.3.4",
+            "::1.2.3.4",
+            "::ffff:1.2.3.4",
+            "2002:102:304::1",
+            "2001:0:102:304::",
+        ] {
+            assert_eq!(
+                reader.lookup::<&str>(addr.parse().unwrap()).unwrap(),
+                "v4",
+                "{}",
+                addr
+            );
+        }
+        for addr in ["1.2.4.4", "::ffff:1.2.4.4", "2002:102:404::1"] {
+            assert!(
+                reader.lookup::<&str>(addr.parse().unwrap()).is_err(),
+                "{}",
+                addr
+            );
+        }
+        assert_eq!(
+            reader
+                .lookup::<&str>("2001:db8::1".parse().unwrap())
+                .unwrap(),
+            "v6"
+        );
+        assert_eq!(
+            reader.lookup::<&str>("2003::1".parse().unwrap()).unwrap(),
+            "2000::/3"
+        );
+
+        assert!(matches!(
+            Database::default().add_ipv4_aliases(),
+            Err(serializer::Error::IpVersionMismatch)
+        ));
+    }
+}
@@ -0,0 +1,254 @@
+//! Records laid out like the ones in MaxMind's GeoIP2 databases, so that readers for those
+//! (like `maxminddb::geoip2`) can read databases built with them.
+//!
+//! Fields set to `None` are left out of the written records, like in the official databases.
+//!
+//! ```
+//! # use maxminddb_writer::{geoip2, paths::IpAddrWithMask, Database};
+//! let mut db = Database::new_v6();
+//! db.metadata.database_type = "GeoIP2-Country".to_string();
+//! let record = db
+//!     .insert_value(geoip2::Country {
+//!         country: Some(geoip2::country::Country {
+//!             iso_code: Some("PL".to_string()),
+//!             names: Some([("en".to_string(), "Poland".to_string())].into()),
+//!             ..Default::default()
+//!         }),
+//!         ..Default::default()
+//!     })
+//!     .unwrap();
+//! db.insert_node("5.172.160.0/19".parse::<IpAddrWithMask>().unwrap(), record);
+//! ```
+
+use serde::Serialize;
+
+/// Record of GeoIP2 Country databases.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Country {
+    pub continent: Option<country::Continent>,
+    pub country: Option<country::Country>,
+    pub registered_country: Option<country::Country>,
+    pub represented_country: Option<country::RepresentedCountry>,
+    pub traits: Option<country::Traits>,
+}
+
+/// Record of GeoIP2 City databases.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct City {
+    pub city: Option<city::City>,
+    pub continent: Option<country::Continent>,
+    pub country: Option<country::Country>,
+    pub location: Option<city::Location>,
+    pub postal: Option<city::Postal>,
+    pub registered_country: Option<country::Country>,
+    pub represented_country: Option<country::RepresentedCountry>,
+    /// From the largest to the smallest.
+    pub subdivisions: Option<Vec<city::Subdivision>>,
+    pub traits: Option<country::Traits>,
+}
+
+/// Record of GeoLite2 ASN databases.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Asn {
+    pub autonomous_system_number: Option<u32>,
+    pub autonomous_system_organization: Option<String>,
+}
+
+/// Parts of `Country` records (also used by `City` records).
+pub mod country {
+    use std::collections::BTreeMap;
+
+    use serde::Serialize;
+
+    #[derive(Clone, Debug, Default, PartialEq, Serialize)]
+    pub struct Continent {
+        /// Two letter code, e.g. `EU`.
+        pub code: Option<String>,
+        pub geoname_id: Option<u32>,
+        /// Names by language code.
+        pub names: Option<BTreeMap<String, String>>,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Serialize)]
+    pub struct Country {
+        pub geoname_id: Option<u32>,
+        pub is_in_european_union: Option<bool>,
+        /// ISO 3166-1 alpha-2 code, e.g. `PL`.
+        pub iso_code: Option<String>,
+        /// Names by language code.
+        pub names: Option<BTreeMap<String, String>>,
+    }
+
+    /// Country represented by users of the network, e.g. by military bases abroad.
+    #[derive(Clone, Debug, Default, PartialEq, Serialize)]
+    pub struct RepresentedCountry {
+        pub geoname_id: Option<u32>,
+        pub is_in_european_union: Option<bool>,
+        pub iso_code: Option<String>,
+        pub names: Option<BTreeMap<String, String>>,
+        /// E.g. `military`.
+        #[serde(rename = "type")]
+        pub representation_type: Option<String>,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Serialize)]
+    pub struct Traits {
+        pub is_anonymous_proxy: Option<bool>,
+        pub is_satellite_provider: Option<bool>,
+    }
+}
+
+/// Parts of `City` records.
+pub mod city {
+    use std::collections::BTreeMap;
+
+    use serde::Serialize;
+
+    #[derive(Clone, Debug, Default, PartialEq, Serialize)]
+    pub struct City {
+        pub geoname_id: Option<u32>,
+        /// Names by language code.
+        pub names: Option<BTreeMap<String, String>>,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Serialize)]
+    pub struct Location {
+        /// Radius in kilometers around the coordinates where the address is likely to be.
+        pub accuracy_radius: Option<u16>,
+        pub latitude: Option<f64>,
+        pub longitude: Option<f64>,
+        pub metro_code: Option<u16>,
+        /// IANA time zone, e.g. `Europe/Warsaw`.
+        pub time_zone: Option<String>,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Serialize)]
+    pub struct Postal {
+        pub code: Option<String>,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Serialize)]
+    pub struct Subdivision {
+        pub geoname_id: Option<u32>,
+        /// ISO 3166-2 code without the country part, e.g. `14` for `PL-14`.
+        pub iso_code: Option<String>,
+        /// Names by language code.
+        pub names: Option<BTreeMap<String, String>>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{paths::IpAddrWithMask, Database};
+
+    fn names(names: &[(&str, &str)]) -> Option<std::collections::BTreeMap<String, String>> {
+        Some(
+            names
+                .iter()
+                .map(|(language, name)| (language.to_string(), name.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_city() {
+        let poland = country::Country {
+            geoname_id: Some(798544),
+            is_in_european_union: Some(true),
+            iso_code: Some("PL".to_string()),
+            names: names(&[("en", "Poland"), ("pl", "Polska")]),
+        };
+        let record = City {
+            city: Some(city::City {
+                geoname_id: Some(756135),
+                names: names(&[("en", "Warsaw"), ("pl", "Warszawa")]),
+            }),
+            continent: Some(country::Continent {
+                code: Some("EU".to_string()),
+                geoname_id: Some(6255148),
+                names: names(&[("en", "Europe")]),
+            }),
+            country: Some(poland.clone()),
+            location: Some(city::Location {
+                accuracy_radius: Some(20),
+                latitude: Some(52.2298),
+                longitude: Some(21.0118),
+                time_zone: Some("Europe/Warsaw".to_string()),
+                ..Default::default()
+            }),
+            postal: Some(city::Postal {
+                code: Some("00-001".to_string()),
+            }),
+            registered_country: Some(poland),
+            represented_country: Some(country::RepresentedCountry {
+                iso_code: Some("US".to_string()),
+                representation_type: Some("military".to_string()),
+                ..Default::default()
+            }),
+            subdivisions: Some(vec![city::Subdivision {
+                geoname_id: Some(858787),
+                iso_code: Some("14".to_string()),
+                names: names(&[("en", "Mazovia")]),
+            }]),
+            traits: Some(country::Traits {
+                is_anonymous_proxy: Some(false),
+                ..Default::default()
+            }),
+        };
+
+        let mut db = Database::new_v6();
+        db.metadata.database_type = "GeoIP2-City".to_string();
+        let data = db.insert_value(&record).unwrap();
+        db.insert_node("5.172.160.0/19".parse::<IpAddrWithMask>().unwrap(), data);
+        let raw_db = db.to_vec().unwrap();
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let read: maxminddb::geoip2::City = reader.lookup([5, 172, 161, 1].into()).unwrap();
+        let city = read.city.unwrap();
+        assert_eq!(city.geoname_id, Some(756135));
+        assert_eq!(city.names.unwrap()["pl"], "Warszawa");
+        assert_eq!(read.continent.unwrap().code, Some("EU"));
+        let country = read.country.unwrap();
+        assert_eq!(country.iso_code, Some("PL"));
+        assert_eq!(country.is_in_european_union, Some(true));
+        assert_eq!(country.names.unwrap()["en"], "Poland");
+        let location = read.location.unwrap();
+        assert_eq!(location.accuracy_radius, Some(20));
+        assert_eq!(location.latitude, Some(52.2298));
+        assert_eq!(location.longitude, Some(21.0118));
+        assert_eq!(location.metro_code, None);
+        assert_eq!(location.time_zone, Some("Europe/Warsaw"));
+        assert_eq!(read.postal.unwrap().code, Some("00-001"));
+        assert_eq!(read.registered_country.unwrap().geoname_id, Some(798544));
+        let represented = read.represented_country.unwrap();
+        assert_eq!(represented.iso_code, Some("US"));
+        assert_eq!(represented.representation_type, Some("military"));
+        let subdivisions = read.subdivisions.unwrap();
+        assert_eq!(subdivisions.len(), 1);
+        assert_eq!(subdivisions[0].iso_code, Some("14"));
+        assert_eq!(read.traits.unwrap().is_anonymous_proxy, Some(false));
+
+        // the country database reader reads the same fields out of city records
+        let read: maxminddb::geoip2::Country = reader.lookup([5, 172, 161, 1].into()).unwrap();
+        assert_eq!(read.country.unwrap().iso_code, Some("PL"));
+    }
+
+    #[test]
+    fn test_asn() {
+        let mut db = Database::default();
+        let data = db
+            .insert_value(Asn {
+                autonomous_system_number: Some(13335),
+                autonomous_system_organization: Some("CLOUDFLARENET".to_string()),
+            })
+            .unwrap();
+        db.insert_node("1.1.1.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        let raw_db = db.to_vec().unwrap();
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let read: maxminddb::geoip2::Asn = reader.lookup([1, 1, 1, 1].into()).unwrap();
+        assert_eq!(read.autonomous_system_number, Some(13335));
+        assert_eq!(read.autonomous_system_organization, Some("CLOUDFLARENET"));
+    }
+}
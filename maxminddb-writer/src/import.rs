@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::{
+    metadata::{IpVersion, Metadata},
+    paths::IpAddrWithMask,
+    serializer,
+    value::DataValue,
+    Database,
+};
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("reader error: {0}")]
+    Reader(#[from] maxminddb::MaxMindDBError),
+    #[error("unsupported IP version {0}")]
+    UnsupportedIpVersion(u16),
+    #[error("failed to insert imported data: {0}")]
+    Insert(#[from] serializer::Error),
+}
+
+impl Database {
+    /// Loads everything `reader` contains, so an existing database can be edited and
+    /// written again. Identical records are stored once.
+    ///
+    /// Networks which resolve to no data aren't imported, so they keep resolving to nothing.
+    /// IPv4 aliases (see `add_ipv4_aliases`) are added again if `reader` has them.
+    pub fn from_reader<S: AsRef<[u8]>>(
+        reader: &maxminddb::Reader<S>,
+    ) -> Result<Database, ImportError> {
+        let source = &reader.metadata;
+        let ip_version = match source.ip_version {
+            4 => IpVersion::V4,
+            6 => IpVersion::V6,
+            other => return Err(ImportError::UnsupportedIpVersion(other)),
+        };
+        let mut db = Database::default();
+        db.set_metadata(Metadata {
+            ip_version,
+            database_type: source.database_type.clone(),
+            languages: source.languages.clone(),
+            binary_format_major_version: source.binary_format_major_version,
+            binary_format_minor_version: source.binary_format_minor_version,
+            build_epoch: source.build_epoch,
+            description: HashMap::from_iter(source.description.clone()),
+            ..Default::default()
+        });
+
+        let everything = match ip_version {
+            IpVersion::V4 => ipnetwork::Ipv4Network::new([0, 0, 0, 0].into(), 0)
+                .expect("valid network")
+                .into(),
+            IpVersion::V6 => ipnetwork::Ipv6Network::new([0u16; 8].into(), 0)
+                .expect("valid network")
+                .into(),
+        };
+        let mut entries = Vec::new();
+        // a network of the IPv4 subtree, to check whether it's aliased
+        let mut ipv4_network = None;
+        for item in reader.within::<DataValue>(everything)? {
+            let item = item?;
+            let data = db.insert_value(&item.info)?;
+            let network = IpAddrWithMask::new(item.ip_net.network(), item.ip_net.prefix());
+            if let (None, ipnetwork::IpNetwork::V4(ip_net)) = (&ipv4_network, item.ip_net) {
+                ipv4_network = Some((ip_net, item.info));
+            }
+            entries.push((network, data));
+        }
+        db.insert_nodes(entries);
+        if let (IpVersion::V6, Some((ip_net, info))) = (ip_version, ipv4_network) {
+            if has_ipv4_aliases(reader, ip_net, &info)? {
+                db.add_ipv4_aliases()?;
+            }
+        }
+        Ok(db)
+    }
+}
+
+/// Whether `::ffff:0:0/96` of `reader` is an alias of the IPv4 subtree, which contains
+/// `ipv4_network` resolving to `info`.
+///
+/// `within` skips aliased networks, so an alias shows up as nothing being found within
+/// `::ffff:0:0/96` while a lookup through it still ends at `ipv4_network`.
+fn has_ipv4_aliases<S: AsRef<[u8]>>(
+    reader: &maxminddb::Reader<S>,
+    ipv4_network: ipnetwork::Ipv4Network,
+    info: &DataValue,
+) -> Result<bool, ImportError> {
+    let mapped = ipnetwork::Ipv6Network::new([0, 0, 0, 0, 0, 0xffff, 0, 0].into(), 96)
+        .expect("valid network");
+    if reader.within::<DataValue>(mapped.into())?.next().is_some() {
+        return Ok(false);
+    }
+    match reader.lookup_prefix::<DataValue>(ipv4_network.ip().to_ipv6_mapped().into()) {
+        Ok((found, prefix_len)) => {
+            Ok(prefix_len == 96 + ipv4_network.prefix() as usize && found == *info)
+        }
+        Err(maxminddb::MaxMindDBError::AddressNotFoundError(_)) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_reader() {
+        for (mut db, aliased) in [
+            (Database::default(), false),
+            (Database::new_v6(), false),
+            (Database::new_v6(), true),
+        ] {
+            db.metadata.database_type = "Test".to_string();
+            db.metadata.add_description("en", "Imported");
+            db.metadata.build_epoch = 1_700_000_000;
+            let mut networks = vec![
+                ("1.0.0.0/24", "one"),
+                ("1.0.1.0/24", "one"),
+                ("10.0.0.0/8", "ten"),
+                ("192.168.0.0/30", "private"),
+            ];
+            if matches!(db.metadata.ip_version, IpVersion::V6) {
+                networks.push(("2001:db8::/32", "documentation"));
+            }
+            for (network, value) in &networks {
+                let data = db.insert_value(value).unwrap();
+                db.insert_node(network.parse::<IpAddrWithMask>().unwrap(), data);
+            }
+            if aliased {
+                db.add_ipv4_aliases().unwrap();
+            }
+            let raw_db = db.to_vec().unwrap();
+
+            let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+            let mut imported = Database::from_reader(&reader).unwrap();
+            // identical values are stored once
+            assert_eq!(imported.data.len(), db.data.len());
+            let extra = imported.insert_value("extra").unwrap();
+            imported.insert_node("172.16.0.0/12".parse::<IpAddrWithMask>().unwrap(), extra);
+            let raw_imported = imported.to_vec().unwrap();
+
+            let reader = maxminddb::Reader::from_source(&raw_imported).unwrap();
+            assert_eq!(reader.metadata.database_type, "Test");
+            assert_eq!(reader.metadata.description["en"], "Imported");
+            assert_eq!(reader.metadata.build_epoch, 1_700_000_000);
+            for (network, value) in &networks {
+                let network = network.parse::<IpAddrWithMask>().unwrap();
+                assert_eq!(reader.lookup::<&str>(network.addr).unwrap(), *value);
+            }
+            assert_eq!(
+                reader.lookup::<&str>([172, 16, 1, 1].into()).unwrap(),
+                "extra"
+            );
+            assert!(reader.lookup::<&str>([11, 0, 0, 0].into()).is_err());
+            if matches!(db.metadata.ip_version, IpVersion::V6) {
+                // resolved through the IPv4 subtree only if the source has aliases
+                for (addr, value) in [
+                    ("::ffff:1.0.0.1", "one"),
+                    ("2001:0:a00:1::", "ten"),
+                    ("2002:a00:1::", "ten"),
+                ] {
+                    let found = reader.lookup::<&str>(addr.parse().unwrap()).ok();
+                    assert_eq!(found, aliased.then_some(value));
+                }
+            }
+        }
+    }
+}
@@ -1,89 +1,39 @@
-use paths::IntoBitPath;
-use serde::Serialize;
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
+#[cfg(feature = "std")]
+mod builder;
+#[cfg(feature = "csv")]
+mod csv;
+#[cfg(feature = "std")]
 pub(crate) mod data;
+#[cfg(feature = "std")]
+mod database;
+#[cfg(feature = "geoip2")]
+pub mod geoip2;
+#[cfg(feature = "maxminddb")]
+mod import;
+#[cfg(feature = "std")]
 pub mod metadata;
+#[cfg(feature = "std")]
 pub(crate) mod node;
+#[cfg(feature = "std")]
 pub mod paths;
-pub(crate) mod serializer;
-
-#[derive(Debug, Default)]
-pub struct Database {
-    nodes: node::NodeTree,
-    data: data::Datastore,
-    pub metadata: metadata::Metadata,
-}
-
-impl Database {
-    fn update_size(&mut self) {
-        // make sure we have correct node count
-        let node_count = self.nodes.len();
-        self.metadata.node_count = node_count.try_into().unwrap();
-
-        // update record size if needed
-        let data_size = self.data.len();
-        let max_ptr_value = node_count + data_size + 16;
-        self.metadata.record_size = metadata::RecordSize::choose(max_ptr_value);
-    }
-
-    pub fn insert_value<T: serde::Serialize>(
-        &mut self,
-        value: T,
-    ) -> Result<data::DataRef, serializer::Error> {
-        let result = self.data.insert(value);
-        self.update_size();
-        result
-    }
-
-    pub fn insert_node(&mut self, path: impl IntoBitPath, data: data::DataRef) {
-        self.nodes.insert(path, data);
-        self.update_size();
-    }
-
-    pub fn write_to<W: std::io::Write>(&self, writer: W) -> Result<W, serializer::Error> {
-        // write node tree
-        let mut writer = self.nodes.write_to(writer, self.metadata.record_size)?;
-        // write data section separator
-        writer.write_all(&[0u8; 16])?;
-        // write data section
-        writer.write_all(self.data.serialized_data())?;
-        // write metadata marker
-        writer.write_all(metadata::METADATA_START_MARKER)?;
-        // serialize metadata
-        let mut serializer = serializer::Serializer::new(writer);
-        self.metadata.serialize(&mut serializer)?;
-        // all done
-        Ok(serializer.into_inner())
-    }
-
-    #[cfg(test)]
-    pub(crate) fn to_vec(&self) -> Result<Vec<u8>, serializer::Error> {
-        let mut result = Vec::new();
-        self.write_to(&mut result)?;
-        Ok(result)
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::paths::IpAddrWithMask;
-
-    use super::*;
-
-    #[test]
-    fn test_simple() {
-        let mut db = Database::default();
-        let data_42 = db.insert_value(42u32).unwrap();
-        let data_foo = db.insert_value("foo".to_string()).unwrap();
-        db.insert_node("0.0.0.0/16".parse::<IpAddrWithMask>().unwrap(), data_42);
-        db.insert_node("1.0.0.0/16".parse::<IpAddrWithMask>().unwrap(), data_foo);
-        let raw_db = db.to_vec().unwrap();
-
-        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
-        let expected_data_42: u32 = reader.lookup([0, 0, 0, 0].into()).unwrap();
-        let expected_data_foo: &str = reader.lookup([1, 0, 0, 0].into()).unwrap();
-
-        assert_eq!(expected_data_42, 42);
-        assert_eq!(expected_data_foo, "foo");
-    }
-}
+pub mod serializer;
+#[cfg(feature = "std")]
+mod streaming;
+pub mod value;
+
+#[cfg(feature = "csv")]
+pub use crate::csv::CsvImportError;
+#[cfg(feature = "std")]
+pub use builder::DatabaseBuilder;
+#[cfg(feature = "std")]
+pub use data::{DataRef, Pointer};
+#[cfg(feature = "std")]
+pub use database::{Database, DatabaseSections, DatabaseStats, MergePolicy, SizeWriter};
+#[cfg(feature = "maxminddb")]
+pub use import::ImportError;
+#[cfg(feature = "std")]
+pub use streaming::StreamingBuilder;
@@ -1,8 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-pub(crate) const METADATA_START_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+use thiserror::Error;
 
-#[derive(Clone, Copy, Debug)]
+/// Bytes preceding the metadata, which readers search for from the end of the database.
+pub const METADATA_START_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// Metadata key of the checksum written with `Database::set_checksum`: the CRC-32 (as used
+/// by zip and PNG) of everything before the metadata start marker, as 8 lowercase hex digits.
+pub const CHECKSUM_KEY: &str = "maxminddb_writer_crc32";
+
+/// Size of a record (a pointer) in the search tree, from the smallest to the largest.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum RecordSize {
     Small,
     Medium,
@@ -10,6 +18,12 @@ pub enum RecordSize {
 }
 
 impl RecordSize {
+    /// The smallest record size for a database whose pointers are all below
+    /// `max_ptr_value` (node count + 16 + data section size).
+    ///
+    /// `k` bit records hold pointers up to `2^k - 1`, so this keeps one value spare: the
+    /// largest pointer (the last data record) is at most `max_ptr_value - 1` and the empty
+    /// record (the node count) is smaller still.
     pub fn choose(max_ptr_value: usize) -> Self {
         if max_ptr_value < 1 << 24 {
             RecordSize::Small
@@ -21,6 +35,17 @@ impl RecordSize {
     }
 }
 
+impl RecordSize {
+    /// Size of a single node (two records) in bytes.
+    pub fn node_size(self) -> usize {
+        match self {
+            RecordSize::Small => 6,
+            RecordSize::Medium => 7,
+            RecordSize::Large => 8,
+        }
+    }
+}
+
 impl serde::Serialize for RecordSize {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         match self {
@@ -31,7 +56,7 @@ impl serde::Serialize for RecordSize {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum IpVersion {
     V4,
     V6,
@@ -46,17 +71,138 @@ impl serde::Serialize for IpVersion {
     }
 }
 
-#[derive(Clone, Debug, serde::Serialize)]
+#[derive(Clone, Debug)]
 pub struct Metadata {
     pub(crate) node_count: u32,
     pub(crate) record_size: RecordSize,
     pub ip_version: IpVersion,
     pub database_type: String,
     pub languages: Vec<String>,
+    /// Version of the MaxMind DB format the database is written in, 2.0 by default.
     pub binary_format_major_version: u16,
     pub binary_format_minor_version: u16,
     pub build_epoch: u64,
     pub description: HashMap<String, String>,
+    // set by `Database::set_omit_empty_collections` for writing
+    pub(crate) omit_empty_collections: bool,
+    pub(crate) checksum: Option<String>,
+}
+
+/// Writes the keys in alphabetical order, like official databases do, and the descriptions
+/// sorted by language so the output doesn't depend on the map's iteration order.
+impl serde::Serialize for Metadata {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let description: BTreeMap<_, _> = self.description.iter().collect();
+        let mut state = serializer.serialize_struct("Metadata", 10)?;
+        state.serialize_field(
+            "binary_format_major_version",
+            &self.binary_format_major_version,
+        )?;
+        state.serialize_field(
+            "binary_format_minor_version",
+            &self.binary_format_minor_version,
+        )?;
+        state.serialize_field("build_epoch", &self.build_epoch)?;
+        state.serialize_field("database_type", &self.database_type)?;
+        // `None` fields are left out
+        let omit_empty = |empty: bool| !(self.omit_empty_collections && empty);
+        state.serialize_field(
+            "description",
+            &omit_empty(description.is_empty()).then_some(&description),
+        )?;
+        state.serialize_field("ip_version", &self.ip_version)?;
+        state.serialize_field(
+            "languages",
+            &omit_empty(self.languages.is_empty()).then_some(&self.languages),
+        )?;
+        state.serialize_field(CHECKSUM_KEY, &self.checksum)?;
+        state.serialize_field("node_count", &self.node_count)?;
+        state.serialize_field("record_size", &self.record_size)?;
+        state.end()
+    }
+}
+
+impl Metadata {
+    /// Checks that `database_type` is set and that every description is in one of the
+    /// `languages`.
+    pub fn validate(&self) -> Result<(), MetadataError> {
+        if self.database_type.is_empty() {
+            return Err(MetadataError::EmptyDatabaseType);
+        }
+        if let Some(language) = self
+            .description
+            .keys()
+            .find(|&language| !self.languages.contains(language))
+        {
+            return Err(MetadataError::UnknownDescriptionLanguage(language.clone()));
+        }
+        Ok(())
+    }
+
+    /// Checks that `languages` are well-formed language tags (like `en` or `zh-CN`: a 2 or 3
+    /// letter language code, optionally followed by `-` separated alphanumeric subtags) and
+    /// that none is listed twice, ignoring case.
+    ///
+    /// Not part of `validate`, readers don't check the tags. See
+    /// `MetadataBuilder::strict_languages`.
+    pub fn check_languages(&self) -> Result<(), MetadataError> {
+        for (i, language) in self.languages.iter().enumerate() {
+            if !is_language_tag(language) {
+                return Err(MetadataError::InvalidLanguage(language.clone()));
+            }
+            if self.languages[..i]
+                .iter()
+                .any(|previous| previous.eq_ignore_ascii_case(language))
+            {
+                return Err(MetadataError::DuplicateLanguage(language.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the description in `language`, adding the language to `languages` if needed.
+    pub fn add_description(&mut self, language: &str, text: &str) {
+        self.description
+            .insert(language.to_string(), text.to_string());
+        if !self.languages.iter().any(|known| known == language) {
+            self.languages.push(language.to_string());
+        }
+    }
+
+    /// Replaces all descriptions, adding their languages to `languages` if needed.
+    pub fn set_descriptions(&mut self, descriptions: impl IntoIterator<Item = (String, String)>) {
+        self.description.clear();
+        for (language, text) in descriptions {
+            self.add_description(&language, &text);
+        }
+    }
+
+    /// Sets `build_epoch` to the current time. Leave it explicitly set instead for
+    /// reproducible builds.
+    pub fn with_current_build_epoch(mut self) -> Self {
+        self.build_epoch = current_epoch();
+        self
+    }
+}
+
+/// Whether `tag` has the shape of a BCP 47 language tag, without checking the registry.
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let language = subtags.next().unwrap_or_default();
+    (2..=3).contains(&language.len())
+        && language.bytes().all(|byte| byte.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len())
+                && subtag.bytes().all(|byte| byte.is_ascii_alphanumeric())
+        })
+}
+
+fn current_epoch() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
 }
 
 impl Default for Metadata {
@@ -67,10 +213,370 @@ impl Default for Metadata {
             ip_version: IpVersion::V4,
             database_type: String::new(),
             languages: Vec::new(),
-            binary_format_major_version: 0,
+            binary_format_major_version: 2,
             binary_format_minor_version: 0,
             build_epoch: 0,
             description: HashMap::new(),
+            omit_empty_collections: false,
+            checksum: None,
         }
     }
 }
+
+#[derive(Debug, Error, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum MetadataError {
+    #[error("database type must not be empty")]
+    EmptyDatabaseType,
+    #[error("description language {0:?} is not one of the languages")]
+    UnknownDescriptionLanguage(String),
+    #[error("language {0:?} is listed more than once")]
+    DuplicateLanguage(String),
+    #[error("language {0:?} is not a valid language tag")]
+    InvalidLanguage(String),
+}
+
+/// Builds validated `Metadata`. Node count and record size are filled in by `Database`.
+#[derive(Clone, Debug, Default)]
+pub struct MetadataBuilder {
+    metadata: Metadata,
+    strict_languages: bool,
+}
+
+impl MetadataBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn database_type(mut self, database_type: impl Into<String>) -> Self {
+        self.metadata.database_type = database_type.into();
+        self
+    }
+
+    pub fn ip_version(mut self, ip_version: IpVersion) -> Self {
+        self.metadata.ip_version = ip_version;
+        self
+    }
+
+    pub fn languages<L: Into<String>>(mut self, languages: impl IntoIterator<Item = L>) -> Self {
+        self.metadata.languages = languages.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Adds a description in `language`, which has to be one of the `languages`.
+    pub fn description(mut self, language: impl Into<String>, text: impl Into<String>) -> Self {
+        self.metadata
+            .description
+            .insert(language.into(), text.into());
+        self
+    }
+
+    /// Makes `build` also fail on malformed or duplicate languages, see
+    /// `Metadata::check_languages`.
+    pub fn strict_languages(mut self, strict_languages: bool) -> Self {
+        self.strict_languages = strict_languages;
+        self
+    }
+
+    /// Overrides the advertised format version, 2.0 by default. Readers may reject other
+    /// major versions.
+    pub fn binary_format_version(mut self, major: u16, minor: u16) -> Self {
+        self.metadata.binary_format_major_version = major;
+        self.metadata.binary_format_minor_version = minor;
+        self
+    }
+
+    pub fn build_epoch(mut self, build_epoch: u64) -> Self {
+        self.metadata.build_epoch = build_epoch;
+        self
+    }
+
+    /// Sets the build epoch to the current time.
+    pub fn current_build_epoch(self) -> Self {
+        self.build_epoch(current_epoch())
+    }
+
+    pub fn build(self) -> Result<Metadata, MetadataError> {
+        self.metadata.validate()?;
+        if self.strict_languages {
+            self.metadata.check_languages()?;
+        }
+        Ok(self.metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialized(value: &(impl serde::Serialize + ?Sized)) -> Vec<u8> {
+        let mut buf = Vec::new();
+        serde::Serialize::serialize(value, &mut crate::serializer::Serializer::new(&mut buf))
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_builder() {
+        let built = MetadataBuilder::new()
+            .database_type("GeoIP2-Country")
+            .ip_version(IpVersion::V6)
+            .languages(["en", "pl"])
+            .description("en", "Countries")
+            .build_epoch(1_700_000_000)
+            .build()
+            .unwrap();
+
+        let manual = Metadata {
+            database_type: "GeoIP2-Country".to_string(),
+            ip_version: IpVersion::V6,
+            languages: vec!["en".to_string(), "pl".to_string()],
+            description: HashMap::from([("en".to_string(), "Countries".to_string())]),
+            build_epoch: 1_700_000_000,
+            ..Default::default()
+        };
+
+        assert_eq!(serialized(&built), serialized(&manual));
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        assert_eq!(
+            MetadataBuilder::new().build().unwrap_err(),
+            MetadataError::EmptyDatabaseType
+        );
+        assert_eq!(
+            MetadataBuilder::new()
+                .database_type("Test")
+                .languages(["en"])
+                .description("de", "Test")
+                .build()
+                .unwrap_err(),
+            MetadataError::UnknownDescriptionLanguage("de".to_string())
+        );
+        assert!(MetadataBuilder::new().database_type("Test").build().is_ok());
+    }
+
+    #[test]
+    fn test_check_languages() {
+        let builder = |languages: &[&str]| {
+            MetadataBuilder::new()
+                .database_type("Test")
+                .languages(languages.iter().copied())
+        };
+
+        let valid = ["de", "en", "pt-BR", "zh-CN", "fil", "sr-Latn-RS"];
+        assert!(builder(&valid).strict_languages(true).build().is_ok());
+
+        // not checked by default
+        let duplicate = builder(&["en", "pl", "En"]).build().unwrap();
+        assert_eq!(
+            duplicate.check_languages(),
+            Err(MetadataError::DuplicateLanguage("En".to_string()))
+        );
+        assert_eq!(
+            builder(&["en", "en"])
+                .strict_languages(true)
+                .build()
+                .unwrap_err(),
+            MetadataError::DuplicateLanguage("en".to_string())
+        );
+        for invalid in ["english", "e", "", "en_US", "en-", "zh-CN-toolongtag", "1a"] {
+            assert_eq!(
+                builder(&["pl", invalid])
+                    .strict_languages(true)
+                    .build()
+                    .unwrap_err(),
+                MetadataError::InvalidLanguage(invalid.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_current_build_epoch() {
+        // 2020-01-01
+        let some_time_ago = 1_577_836_800;
+        assert!(Metadata::default().with_current_build_epoch().build_epoch > some_time_ago);
+        let metadata = MetadataBuilder::new()
+            .database_type("Test")
+            .current_build_epoch()
+            .build()
+            .unwrap();
+        assert!(metadata.build_epoch > some_time_ago);
+    }
+
+    #[test]
+    fn test_explicit_build_epoch() {
+        let mut db = crate::Database::default();
+        db.set_metadata(
+            MetadataBuilder::new()
+                .database_type("Test")
+                .build_epoch(1_234_567)
+                .build()
+                .unwrap(),
+        );
+        let mut raw_db = Vec::new();
+        db.write_to(&mut raw_db).unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.build_epoch, 1_234_567);
+    }
+
+    #[test]
+    fn test_descriptions() {
+        let mut metadata = Metadata {
+            languages: vec!["en".to_string()],
+            ..Default::default()
+        };
+        metadata.add_description("en", "Countries");
+        metadata.add_description("pl", "Kraje");
+        metadata.add_description("pl", "Państwa");
+        assert_eq!(metadata.languages, ["en", "pl"]);
+        assert_eq!(metadata.description["pl"], "Państwa");
+
+        metadata.set_descriptions([
+            ("de".to_string(), "Länder".to_string()),
+            ("en".to_string(), "Countries".to_string()),
+            ("de".to_string(), "Staaten".to_string()),
+        ]);
+        assert_eq!(metadata.languages, ["en", "pl", "de"]);
+        assert_eq!(
+            metadata.description,
+            HashMap::from([
+                ("de".to_string(), "Staaten".to_string()),
+                ("en".to_string(), "Countries".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_serialized_order() {
+        let mut metadata = MetadataBuilder::new()
+            .database_type("Test")
+            .build()
+            .unwrap();
+        for language in ["pl", "en", "de", "fr", "es", "ja", "ru", "zh-CN"] {
+            metadata.add_description(language, language);
+        }
+        let raw = serialized(&metadata);
+        assert_eq!(raw, serialized(&metadata.clone()));
+
+        // position of a string as it's encoded in the data section
+        let position = |key: &str| {
+            let encoded = serialized(key);
+            raw.windows(encoded.len())
+                .position(|window| window == encoded)
+                .unwrap()
+        };
+        let keys = [
+            "binary_format_major_version",
+            "binary_format_minor_version",
+            "build_epoch",
+            "database_type",
+            "description",
+            "ip_version",
+            "languages",
+            "node_count",
+            "record_size",
+        ];
+        assert!(keys
+            .windows(2)
+            .all(|pair| position(pair[0]) < position(pair[1])));
+        let mut languages = metadata.languages.clone();
+        languages.sort();
+        assert!(languages
+            .windows(2)
+            .all(|pair| position(&pair[0]) < position(&pair[1])));
+    }
+
+    #[test]
+    fn test_binary_format_version() {
+        let mut db = crate::Database::default();
+        db.metadata.database_type = "Test".to_string();
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.binary_format_major_version, 2);
+        assert_eq!(reader.metadata.binary_format_minor_version, 0);
+
+        let metadata = MetadataBuilder::new()
+            .database_type("Test")
+            .binary_format_version(2, 1)
+            .build()
+            .unwrap();
+        db.set_metadata(metadata);
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.binary_format_major_version, 2);
+        assert_eq!(reader.metadata.binary_format_minor_version, 1);
+    }
+
+    #[test]
+    fn test_empty_collections() {
+        let mut db = crate::Database::default();
+        db.metadata.database_type = "Test".to_string();
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert!(reader.metadata.languages.is_empty());
+        assert!(reader.metadata.description.is_empty());
+        let contains = |raw: &[u8], key: &str| {
+            let encoded = serialized(key);
+            raw.windows(encoded.len()).any(|window| window == encoded)
+        };
+        assert!(contains(&raw_db, "languages"));
+        assert!(contains(&raw_db, "description"));
+
+        db.set_omit_empty_collections(true);
+        let raw_db = db.to_vec().unwrap();
+        assert!(!contains(&raw_db, "languages"));
+        assert!(!contains(&raw_db, "description"));
+
+        // only empty ones are left out
+        db.metadata.add_description("en", "Test");
+        let raw_db = db.to_vec().unwrap();
+        assert!(contains(&raw_db, "languages"));
+        assert!(contains(&raw_db, "description"));
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        assert_eq!(reader.metadata.languages, ["en"]);
+    }
+
+    #[test]
+    fn test_metadata_read_back() {
+        let mut db = crate::Database::new_v6();
+        let mut metadata = MetadataBuilder::new()
+            .database_type("GeoIP2-Country")
+            .ip_version(IpVersion::V6)
+            .languages(["en", "pl"])
+            .build_epoch(1_700_000_000)
+            .build()
+            .unwrap();
+        metadata.add_description("en", "Countries");
+        metadata.add_description("pl", "Kraje");
+        db.set_metadata(metadata);
+        let data = db.insert_value("data").unwrap();
+        db.insert_node(
+            "1.2.3.0/24"
+                .parse::<crate::paths::IpAddrWithMask>()
+                .unwrap(),
+            data,
+        );
+
+        let mut raw_db = Vec::new();
+        db.write_to(&mut raw_db).unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let read = &reader.metadata;
+        assert_eq!(read.binary_format_major_version, 2);
+        assert_eq!(read.binary_format_minor_version, 0);
+        assert_eq!(read.build_epoch, 1_700_000_000);
+        assert_eq!(read.database_type, "GeoIP2-Country");
+        assert_eq!(
+            read.description,
+            std::collections::BTreeMap::from([
+                ("en".to_string(), "Countries".to_string()),
+                ("pl".to_string(), "Kraje".to_string()),
+            ])
+        );
+        assert_eq!(read.ip_version, 6);
+        assert_eq!(read.languages, ["en", "pl"]);
+        assert_eq!(read.node_count as usize, db.stats().unwrap().node_count);
+        assert_eq!(read.record_size, 24);
+    }
+}
@@ -1,9 +1,12 @@
-use std::ops::{Index, IndexMut};
+use std::{
+    collections::HashMap,
+    ops::{Index, IndexMut},
+};
 
 use crate::{data::DataRef, metadata::RecordSize, paths::IntoBitPath};
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum Target {
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum Target {
     Node(NodeRef),
     Data(DataRef),
 }
@@ -17,11 +20,71 @@ impl Target {
     }
 }
 
+/// Nodes already added to a compacted tree, by their records and depths.
+type NodeIndexes = HashMap<([Option<Target>; 2], [u8; 2]), usize>;
+
+/// Length of the longest path in the tree, that of IPv6 addresses. Prefix lengths stay well
+/// below `ALIAS_DEPTH`.
+pub(crate) const MAX_PATH_LEN: usize = 128;
+
+/// Collects `path`, panicking if it's longer than `MAX_PATH_LEN`, before the tree is changed.
+fn bounded_path(path: impl IntoBitPath) -> ([bool; MAX_PATH_LEN], usize) {
+    let mut bits = [false; MAX_PATH_LEN];
+    let mut len = 0;
+    for bit in path.into_bit_path() {
+        assert!(len < MAX_PATH_LEN, "path longer than {} bits", MAX_PATH_LEN);
+        bits[len] = bit;
+        len += 1;
+    }
+    (bits, len)
+}
+
+/// Prefix length marking records which are aliases, see `NodeTree::alias`.
+const ALIAS_DEPTH: u8 = u8::MAX;
+
+/// Size of a node encoded by `Node::to_spilled`.
+pub(crate) const SPILLED_NODE_SIZE: usize = 18;
+
 #[derive(Clone, Copy, Debug, Default)]
-struct Node([Option<Target>; 2]);
+pub(crate) struct Node(pub(crate) [Option<Target>; 2]);
 
 impl Node {
-    fn write_to(
+    /// Encodes the node independently of the final node count and record size, for storing
+    /// it until those are known.
+    pub(crate) fn to_spilled(self) -> [u8; SPILLED_NODE_SIZE] {
+        let mut bytes = [0; SPILLED_NODE_SIZE];
+        for (record, chunk) in self.0.iter().zip(bytes.chunks_exact_mut(9)) {
+            let (tag, value) = match record {
+                None => (0, 0),
+                Some(Target::Node(node)) => (1, node.index as u64),
+                Some(Target::Data(data)) => (2, (data.store as u64) << 32 | data.index as u64),
+            };
+            chunk[0] = tag;
+            chunk[1..].copy_from_slice(&value.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes a node encoded by `to_spilled`.
+    pub(crate) fn from_spilled(bytes: &[u8; SPILLED_NODE_SIZE]) -> Self {
+        let mut node = Node::default();
+        for (record, chunk) in node.0.iter_mut().zip(bytes.chunks_exact(9)) {
+            let value = u64::from_be_bytes(chunk[1..].try_into().unwrap());
+            *record = match chunk[0] {
+                0 => None,
+                1 => Some(Target::Node(NodeRef {
+                    index: value as usize,
+                })),
+                _ => Some(Target::Data(DataRef {
+                    index: value as u32,
+                    store: (value >> 32) as u32,
+                })),
+            };
+        }
+        node
+    }
+
+    pub(crate) fn write_to(
         &self,
         writer: &mut impl std::io::Write,
         record_size: RecordSize,
@@ -46,11 +109,12 @@ impl Node {
                 ptrs[1] as u8,
             ]),
             // 28 bits/ptr -> 7 bytes
+            // the middle byte holds the high nibbles of both pointers
             RecordSize::Medium => writer.write_all(&[
-                (ptrs[0] >> 20) as u8,
-                (ptrs[0] >> 12) as u8,
-                (ptrs[0] >> 4) as u8,
-                (ptrs[0] << 4) as u8 | (ptrs[1] >> 24) as u8,
+                (ptrs[0] >> 16) as u8,
+                (ptrs[0] >> 8) as u8,
+                ptrs[0] as u8,
+                ((ptrs[0] >> 20) as u8 & 0xF0) | ((ptrs[1] >> 24) as u8 & 0x0F),
                 (ptrs[1] >> 16) as u8,
                 (ptrs[1] >> 8) as u8,
                 ptrs[1] as u8,
@@ -84,32 +148,90 @@ impl IndexMut<bool> for Node {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-struct NodeRef {
-    index: usize,
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct NodeRef {
+    pub(crate) index: usize,
 }
 
 #[derive(Debug)]
 pub struct NodeTree {
     nodes: Vec<Node>,
+    // prefix length of the network each record's data (or lack of it) was inserted with, so
+    // inserting a less specific network doesn't replace more specific ones
+    depths: Vec<[u8; 2]>,
+    // data (or lack of it) and prefix length of the most specific network covering all of a
+    // node, by node index, so removing a more specific network can restore it; left out for
+    // nodes no network covers
+    enclosing: HashMap<usize, (Option<Target>, u8)>,
 }
 
 impl NodeTree {
+    /// Creates a tree with room for `capacity` nodes before reallocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut nodes = Vec::with_capacity(capacity.max(1));
+        nodes.push(Node::default());
+        let mut depths = Vec::with_capacity(capacity.max(1));
+        depths.push([0; 2]);
+        Self {
+            nodes,
+            depths,
+            enclosing: HashMap::new(),
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.nodes.len()
     }
 
+    pub fn capacity(&self) -> usize {
+        self.nodes.capacity()
+    }
+
+    /// Removes all nodes but an empty root, keeping the allocated memory.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.nodes.push(Node::default());
+        self.depths.clear();
+        self.depths.push([0; 2]);
+        self.enclosing.clear();
+    }
+
     pub fn insert(&mut self, path: impl IntoBitPath, data: DataRef) {
-        let mut path = path.into_bit_path();
+        self.insert_target(path, Some(Target::Data(data)));
+    }
+
+    /// Makes `path` resolve to no data, even if it's within a prefix which has data.
+    pub fn reserve(&mut self, path: impl IntoBitPath) {
+        self.insert_target(path, None);
+    }
+
+    fn insert_target(&mut self, path: impl IntoBitPath, target: Option<Target>) {
+        match self.split_to(path) {
+            Some((index, bit, depth)) => self.replace_less_specific(index, bit, target, depth),
+            // empty path (e.g. a /0 default route) covers everything
+            None => {
+                for bit in [false, true] {
+                    self.replace_less_specific(0, bit, target, 0);
+                }
+            }
+        }
+    }
+
+    /// Follows `path` from the root, splitting records on the way, and returns the node and
+    /// side of the record `path` ends at along with the length of `path` (`None` for an
+    /// empty path).
+    ///
+    /// Panics if `path` is longer than `MAX_PATH_LEN`.
+    fn split_to(&mut self, path: impl IntoBitPath) -> Option<(usize, bool, u8)> {
+        let (bits, len) = bounded_path(path);
+        let mut path = bits[..len].iter().copied();
         let mut index = 0;
-        let Some(mut last_bit) = path.next() else {
-            // empty path doesn't insert anything
-            return;
-        };
+        let mut last_bit = path.next()?;
+        let mut depth = 1;
 
         for bit in path {
-            let target = self.nodes[index][last_bit];
-            match target {
+            let existing = self.nodes[index][last_bit];
+            match existing {
                 // node points to another -> follow the path
                 Some(Target::Node(NodeRef { index: new_index })) => {
                     index = new_index;
@@ -117,62 +239,997 @@ impl NodeTree {
                 // node points to data (or is empty) -> split the node
                 Some(Target::Data(_)) | None => {
                     let old_index = index;
+                    let existing_depth = self.depths[old_index][last_bit as usize];
                     index = self.nodes.len();
-                    self.nodes.push(Node([target, target]));
+                    self.nodes.push(Node([existing, existing]));
+                    self.depths.push([existing_depth; 2]);
+                    self.set_enclosing(index, (existing, existing_depth));
                     self.nodes[old_index][last_bit] = Some(Target::Node(NodeRef { index }));
                 }
             }
+            depth += 1;
             last_bit = bit;
         }
 
-        self.nodes[index][last_bit] = Some(Target::Data(data));
+        Some((index, last_bit, depth))
+    }
+
+    /// Makes lookups within `path` continue in the subtree of `target`, like readers of IPv6
+    /// databases expect for networks embedding IPv4 addresses. Both paths must be non-empty
+    /// and `path` can't be within `target`.
+    ///
+    /// Networks inserted covering `path` don't replace the alias.
+    pub fn alias(&mut self, path: impl IntoBitPath, target: impl IntoBitPath) {
+        let (index, bit, _) = self.split_to(target).expect("non-empty alias target");
+        let target = match self.nodes[index][bit] {
+            Some(Target::Node(node)) => node,
+            // the target needs a node to point to
+            existing => {
+                let node = NodeRef {
+                    index: self.nodes.len(),
+                };
+                let depth = self.depths[index][bit as usize];
+                self.nodes.push(Node([existing, existing]));
+                self.depths.push([depth; 2]);
+                self.set_enclosing(node.index, (existing, depth));
+                self.nodes[index][bit] = Some(Target::Node(node));
+                node
+            }
+        };
+        let (index, bit, _) = self.split_to(path).expect("non-empty alias path");
+        self.nodes[index][bit] = Some(Target::Node(target));
+        self.depths[index][bit as usize] = ALIAS_DEPTH;
+    }
+
+    /// Returns the data and prefix length of the most specific network covering all of node
+    /// `index`, `(None, 0)` if there's none.
+    fn enclosing(&self, index: usize) -> (Option<Target>, u8) {
+        self.enclosing.get(&index).copied().unwrap_or((None, 0))
+    }
+
+    /// Returns the data and prefix length of the most specific network covering all of record
+    /// `bit` of node `index`, like `enclosing` does for nodes. Not meaningful for aliases.
+    fn covering(&self, index: usize, bit: bool) -> (Option<Target>, u8) {
+        match self.nodes[index][bit] {
+            Some(Target::Node(NodeRef { index: child })) => self.enclosing(child),
+            target => (target, self.depths[index][bit as usize]),
+        }
+    }
+
+    /// Returns the data of an empty path (e.g. a /0 default route), which has no record of
+    /// its own.
+    fn default_network(&self) -> Option<DataRef> {
+        [false, true]
+            .into_iter()
+            .find_map(|bit| match self.covering(0, bit) {
+                (Some(Target::Data(data)), 0) => Some(data),
+                _ => None,
+            })
+    }
+
+    fn set_enclosing(&mut self, index: usize, enclosing: (Option<Target>, u8)) {
+        if enclosing == (None, 0) {
+            self.enclosing.remove(&index);
+        } else {
+            self.enclosing.insert(index, enclosing);
+        }
+    }
+
+    fn is_alias(&self, index: usize, bit: bool) -> bool {
+        matches!(self.nodes[index][bit], Some(Target::Node(_)))
+            && self.depths[index][bit as usize] == ALIAS_DEPTH
+    }
+
+    /// Points the record `bit` of node `index` to `target`, as inserted with prefix length
+    /// `depth`. If the record was split, only the records under it which came from networks
+    /// that aren't more specific are replaced.
+    fn replace_less_specific(
+        &mut self,
+        index: usize,
+        bit: bool,
+        target: Option<Target>,
+        depth: u8,
+    ) {
+        let mut stack = vec![(index, bit)];
+        while let Some((index, bit)) = stack.pop() {
+            match self.nodes[index][bit] {
+                _ if self.is_alias(index, bit) => {}
+                Some(Target::Node(NodeRef { index: child })) => {
+                    if self.enclosing(child).1 <= depth {
+                        self.set_enclosing(child, (target, depth));
+                    }
+                    stack.extend([(child, false), (child, true)]);
+                }
+                _ if self.depths[index][bit as usize] <= depth => {
+                    self.nodes[index][bit] = target;
+                    self.depths[index][bit as usize] = depth;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the data inserted exactly at `path`, if `path` ends at a data record which
+    /// came from a network with that prefix length.
+    pub fn get(&self, path: impl IntoBitPath) -> Option<DataRef> {
+        let (bits, len) = bounded_path(path);
+        let mut path = bits[..len].iter().copied();
+        let mut index = 0;
+        let mut last_bit = path.next()?;
+        let mut depth = 1;
+
+        for bit in path {
+            match self.nodes[index][last_bit] {
+                Some(Target::Node(NodeRef { index: next })) => index = next,
+                Some(Target::Data(_)) | None => return None,
+            }
+            depth += 1;
+            last_bit = bit;
+        }
+
+        match self.nodes[index][last_bit] {
+            Some(Target::Data(data)) if self.depths[index][last_bit as usize] == depth => {
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the data of the network inserted exactly at `path`, like `get`, but also if
+    /// more specific networks were inserted within it since and split it into several records.
+    ///
+    /// Panics if `path` is longer than `MAX_PATH_LEN`.
+    pub fn get_network(&self, path: impl IntoBitPath) -> Option<DataRef> {
+        let (bits, len) = bounded_path(path);
+        let Some((&last_bit, path)) = bits[..len].split_last() else {
+            return self.default_network();
+        };
+        let mut index = 0;
+        for &bit in path {
+            match self.nodes[index][bit] {
+                Some(Target::Node(NodeRef { index: next })) => index = next,
+                // data of a less specific network, unless merged from ones with this length
+                Some(Target::Data(_)) | None => {
+                    return match self.covering(index, bit) {
+                        (Some(Target::Data(data)), depth) if depth as usize == len => Some(data),
+                        _ => None,
+                    };
+                }
+            }
+        }
+        match self.covering(index, last_bit) {
+            _ if self.is_alias(index, last_bit) => None,
+            (Some(Target::Data(data)), depth) if depth as usize == len => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Removes the data inserted exactly at `path`, returning whether there was any.
+    ///
+    /// Addresses within the removed prefix resolve to the data of the most specific prefix
+    /// covering it afterwards (or to nothing), as if it was never inserted. More specific
+    /// prefixes inserted within it are kept. Returns `false` without changing anything if
+    /// `path` ends inside a less specific prefix or no data was inserted at it.
+    ///
+    /// Panics if `path` is longer than `MAX_PATH_LEN`.
+    pub fn remove(&mut self, path: impl IntoBitPath) -> bool {
+        let (bits, len) = bounded_path(path);
+        let Some((&last_bit, path)) = bits[..len].split_last() else {
+            // empty path (e.g. a /0 default route) covers everything
+            let removed = self.restore(0, false, 0, (None, 0));
+            return self.restore(0, true, 0, (None, 0)) || removed;
+        };
+        let mut index = 0;
+        for &bit in path {
+            match self.nodes[index][bit] {
+                Some(Target::Node(NodeRef { index: next })) => index = next,
+                Some(Target::Data(_)) | None => return false,
+            }
+        }
+        self.restore(index, last_bit, len as u8, self.enclosing(index))
+    }
+
+    /// Points the records under record `bit` of node `index` which were inserted with prefix
+    /// length `depth` to `enclosing` instead, returning whether any of them had data.
+    fn restore(
+        &mut self,
+        index: usize,
+        bit: bool,
+        depth: u8,
+        enclosing: (Option<Target>, u8),
+    ) -> bool {
+        let mut removed = false;
+        let mut stack = vec![(index, bit)];
+        while let Some((index, bit)) = stack.pop() {
+            match self.nodes[index][bit] {
+                _ if self.is_alias(index, bit) => {}
+                Some(Target::Node(NodeRef { index: child })) => {
+                    let (target, child_depth) = self.enclosing(child);
+                    if child_depth == depth && matches!(target, Some(Target::Data(_))) {
+                        self.set_enclosing(child, enclosing);
+                        removed = true;
+                    }
+                    stack.extend([(child, false), (child, true)]);
+                }
+                Some(Target::Data(_)) if self.depths[index][bit as usize] == depth => {
+                    self.nodes[index][bit] = enclosing.0;
+                    self.depths[index][bit as usize] = enclosing.1;
+                    removed = true;
+                }
+                _ => {}
+            }
+        }
+        removed
+    }
+
+    /// Replaces the data every record points to with `f(data)`. `f` is called once per
+    /// record, so data shared by several records (or reached through aliases) is passed to it
+    /// more than once.
+    pub fn map_data(&mut self, mut f: impl FnMut(DataRef) -> DataRef) {
+        for node in &mut self.nodes {
+            for record in &mut node.0 {
+                if let Some(Target::Data(data)) = record {
+                    *data = f(*data);
+                }
+            }
+        }
+    }
+
+    /// Describes the tree in Graphviz DOT: nodes are labeled with their index, edges with the
+    /// bit they follow and data with its `DataRef` index. Aliases are dashed, empty records
+    /// left out.
+    pub fn to_dot(&self) -> String {
+        use std::fmt::Write as _;
+
+        let mut dot = String::from("digraph {\n");
+        let mut data_indexes = std::collections::BTreeSet::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            writeln!(dot, "    n{} [label=\"{}\"];", index, index).unwrap();
+            for bit in [false, true] {
+                let style = if self.is_alias(index, bit) {
+                    ", style=dashed"
+                } else {
+                    ""
+                };
+                let target = match node[bit] {
+                    Some(Target::Node(NodeRef { index })) => format!("n{}", index),
+                    Some(Target::Data(data)) => {
+                        data_indexes.insert(data.index);
+                        format!("d{}", data.index)
+                    }
+                    None => continue,
+                };
+                writeln!(
+                    dot,
+                    "    n{} -> {} [label=\"{}\"{}];",
+                    index, target, bit as u8, style
+                )
+                .unwrap();
+            }
+        }
+        for index in data_indexes {
+            writeln!(dot, "    d{} [label=\"data {}\", shape=box];", index, index).unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Walks the tree depth first, yielding the path to every data record. Aliases aren't
+    /// followed.
+    ///
+    /// For non-overlapping inserts this yields exactly the inserted paths. A prefix which
+    /// had more specific prefixes inserted within it shows up as the pieces it was split
+    /// into.
+    pub fn iter_prefixes(&self) -> Prefixes<'_> {
+        Prefixes {
+            tree: self,
+            stack: vec![(Target::Node(NodeRef { index: 0 }), Vec::new())],
+        }
+    }
+
+    /// Walks the tree depth first like `iter_prefixes`, but yields the path every network
+    /// with data was inserted at, once. A network split by more specific networks inserted
+    /// within it is yielded with its own prefix length instead of as its pieces.
+    ///
+    /// Records merged by `optimize` are yielded as they are.
+    pub fn iter_networks(&self) -> impl Iterator<Item = (Vec<bool>, DataRef)> + '_ {
+        let mut stack = vec![(0, true, vec![true]), (0, false, vec![false])];
+        let default = self.default_network().map(|data| (Vec::new(), data));
+        default.into_iter().chain(std::iter::from_fn(move || {
+            while let Some((index, bit, path)) = stack.pop() {
+                if self.is_alias(index, bit) {
+                    continue;
+                }
+                let record = self.nodes[index][bit];
+                if let Some(Target::Node(NodeRef { index: child })) = record {
+                    // push the right side first so the left one is visited first
+                    for child_bit in [true, false] {
+                        let mut child_path = path.clone();
+                        child_path.push(child_bit);
+                        stack.push((child, child_bit, child_path));
+                    }
+                }
+                match self.covering(index, bit) {
+                    (Some(Target::Data(data)), depth) if depth as usize == path.len() => {
+                        return Some((path, data));
+                    }
+                    // merged from more specific networks
+                    (Some(Target::Data(data)), depth)
+                        if depth as usize > path.len() && record == Some(Target::Data(data)) =>
+                    {
+                        return Some((path, data));
+                    }
+                    _ => {}
+                }
+            }
+            None
+        }))
+    }
+
+    /// Shrinks the tree without changing what any address resolves to: nodes whose both
+    /// records hold the same data (or are both empty) are replaced by that data.
+    ///
+    /// A record merged from more specific networks keeps the most specific prefix length,
+    /// so networks inserted afterwards never replace data they didn't replace before.
+    pub fn optimize(&mut self) {
+        *self = self.optimized(None);
+    }
+
+    /// Like `optimize`, but also shares identical subtrees, returning the result as a new
+    /// tree for writing. Inserting into it would change every network sharing a node.
+    pub fn compacted(&self) -> NodeTree {
+        self.optimized(Some(&mut HashMap::new()))
+    }
+
+    /// Rebuilds the tree with merged nodes, sharing identical subtrees through `known` if
+    /// it's set.
+    fn optimized(&self, mut known: Option<&mut NodeIndexes>) -> NodeTree {
+        let mut tree = NodeTree::default();
+        for bit in [false, true] {
+            let (target, depth) = self.optimize_target(0, bit, &mut tree, known.as_deref_mut());
+            tree.nodes[0][bit] = target;
+            tree.depths[0][bit as usize] = depth;
+        }
+        tree
+    }
+
+    /// Adds the optimized subtree of record `bit` of node `index` to `tree`, returning what
+    /// the record points to there and its prefix length.
+    fn optimize_target(
+        &self,
+        index: usize,
+        bit: bool,
+        tree: &mut NodeTree,
+        mut known: Option<&mut NodeIndexes>,
+    ) -> (Option<Target>, u8) {
+        let target = self.nodes[index][bit];
+        let depth = self.depths[index][bit as usize];
+        let Some(Target::Node(NodeRef { index })) = target else {
+            return (target, depth);
+        };
+        let (left, left_depth) = self.optimize_target(index, false, tree, known.as_deref_mut());
+        let (right, right_depth) = self.optimize_target(index, true, tree, known.as_deref_mut());
+        match [left, right] {
+            // both halves resolve the same way -> no need for a node
+            [left, right] if left == right && !matches!(left, Some(Target::Node(_))) => {
+                (left, left_depth.max(right_depth))
+            }
+            children => {
+                let mut push = || {
+                    tree.nodes.push(Node(children));
+                    tree.depths.push([left_depth, right_depth]);
+                    let new_index = tree.nodes.len() - 1;
+                    tree.set_enclosing(new_index, self.enclosing(index));
+                    new_index
+                };
+                let index = match known {
+                    Some(known) => *known
+                        .entry((children, [left_depth, right_depth]))
+                        .or_insert_with(push),
+                    None => push(),
+                };
+                (Some(Target::Node(NodeRef { index })), depth)
+            }
+        }
+    }
+
+    /// Follows `path` from the root and returns the data it ends up in, like a reader would.
+    pub fn lookup(&self, path: impl IntoBitPath) -> Option<DataRef> {
+        self.lookup_with_depth(path).map(|(data, _)| data)
+    }
+
+    /// Like `lookup`, but also returns how many bits of `path` were followed to reach the
+    /// data (the prefix length of the matching record).
+    pub fn lookup_with_depth(&self, path: impl IntoBitPath) -> Option<(DataRef, usize)> {
+        let mut index = 0;
+        for (depth, bit) in path.into_bit_path().enumerate() {
+            match self.nodes[index][bit] {
+                Some(Target::Node(NodeRef { index: next })) => index = next,
+                Some(Target::Data(data)) => return Some((data, depth + 1)),
+                None => return None,
+            }
+        }
+        None
+    }
+
+    /// Follows `path` like `lookup`, recording every record on the way: how many bits were
+    /// followed to reach it and the data if it's a data record. Stops after the first data or
+    /// empty record.
+    pub fn trace(&self, path: impl IntoBitPath) -> Vec<(usize, Option<DataRef>)> {
+        let mut trace = Vec::new();
+        let mut index = 0;
+        for (depth, bit) in path.into_bit_path().enumerate() {
+            match self.nodes[index][bit] {
+                Some(Target::Node(NodeRef { index: next })) => {
+                    trace.push((depth + 1, None));
+                    index = next;
+                }
+                Some(Target::Data(data)) => {
+                    trace.push((depth + 1, Some(data)));
+                    break;
+                }
+                None => {
+                    trace.push((depth + 1, None));
+                    break;
+                }
+            }
+        }
+        trace
     }
 
+    /// Writes the tree padded with empty nodes to `node_count` nodes. The padding is never
+    /// reached by lookups.
     pub fn write_to<W: std::io::Write>(
         &self,
         mut writer: W,
         record_size: RecordSize,
+        node_count: usize,
     ) -> Result<W, std::io::Error> {
-        for node in &self.nodes {
-            node.write_to(&mut writer, record_size, self.len())?;
+        let padding = Node::default();
+        let padding = std::iter::repeat_n(&padding, node_count - self.len());
+        for node in self.nodes.iter().chain(padding) {
+            node.write_to(&mut writer, record_size, node_count)?;
         }
         Ok(writer)
     }
 }
 
+pub struct Prefixes<'a> {
+    tree: &'a NodeTree,
+    stack: Vec<(Target, Vec<bool>)>,
+}
+
+impl Iterator for Prefixes<'_> {
+    type Item = (Vec<bool>, DataRef);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((target, path)) = self.stack.pop() {
+            match target {
+                Target::Data(data) => return Some((path, data)),
+                Target::Node(NodeRef { index }) => {
+                    // push the right side first so the left one is visited first
+                    for bit in [true, false] {
+                        if self.tree.is_alias(index, bit) {
+                            continue;
+                        }
+                        if let Some(child) = self.tree.nodes[index][bit] {
+                            let mut child_path = path.clone();
+                            child_path.push(bit);
+                            self.stack.push((child, child_path));
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
 impl Default for NodeTree {
     fn default() -> Self {
-        Self {
-            nodes: vec![Node::default()],
-        }
+        Self::with_capacity(1)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::data::DATA_SECTION_SEPARATOR_LEN;
+
+    fn data_ref(index: u32) -> DataRef {
+        DataRef { index, store: 0 }
+    }
 
     #[test]
     fn test_insert_to_empty() {
         let mut tree = NodeTree::default();
         assert_eq!(tree.nodes.len(), 1);
-        tree.insert([false].into_iter(), DataRef { index: 0 });
+        tree.insert([false].into_iter(), data_ref(0));
         assert_eq!(tree.nodes.len(), 1);
-        assert_eq!(
-            tree.nodes[0][false],
-            Some(Target::Data(DataRef { index: 0 }))
-        );
+        assert_eq!(tree.nodes[0][false], Some(Target::Data(data_ref(0))));
         assert_eq!(tree.nodes[0][true], None);
 
-        tree.insert([true].into_iter(), DataRef { index: 1 });
+        tree.insert([true].into_iter(), data_ref(1));
         assert_eq!(tree.nodes.len(), 1);
+        assert_eq!(tree.nodes[0][false], Some(Target::Data(data_ref(0))));
+        assert_eq!(tree.nodes[0][true], Some(Target::Data(data_ref(1))));
+    }
+
+    /// Decodes a medium (28 bit) record pair the same way readers do.
+    fn decode_medium(bytes: &[u8]) -> [usize; 2] {
+        let left = ((bytes[3] as usize & 0xF0) << 20)
+            | (bytes[0] as usize) << 16
+            | (bytes[1] as usize) << 8
+            | bytes[2] as usize;
+        let right = ((bytes[3] as usize & 0x0F) << 24)
+            | (bytes[4] as usize) << 16
+            | (bytes[5] as usize) << 8
+            | bytes[6] as usize;
+        [left, right]
+    }
+
+    #[test]
+    fn test_medium_record_boundaries() {
+        // node counts at the edges of the nibble shared by both records
+        for node_count in [1 << 24, (1 << 24) + 1, (1 << 28) - 17, (1 << 28) - 2] {
+            let last_node = node_count - 1;
+            let nodes = [
+                Node([None, None]),
+                Node([Some(Target::Node(NodeRef { index: last_node })), None]),
+                Node([None, Some(Target::Node(NodeRef { index: last_node }))]),
+                Node([
+                    Some(Target::Node(NodeRef {
+                        index: (1 << 24) - 1,
+                    })),
+                    Some(Target::Node(NodeRef { index: 1 << 24 })),
+                ]),
+            ];
+            for node in nodes {
+                let mut buf = Vec::new();
+                node.write_to(&mut buf, RecordSize::Medium, node_count)
+                    .unwrap();
+                assert_eq!(buf.len(), 7);
+                let expected = node
+                    .0
+                    .map(|t| t.map_or(node_count, |t| t.to_ptr(node_count)));
+                assert_eq!(decode_medium(&buf), expected);
+            }
+        }
+
+        // largest data pointer that `RecordSize::choose` still maps to medium records
+        let node_count = 1000;
+        let max_ptr_value = (1 << 28) - 1;
+        assert!(matches!(
+            RecordSize::choose(max_ptr_value),
+            RecordSize::Medium
+        ));
+        let data = data_ref((max_ptr_value - 1 - node_count - DATA_SECTION_SEPARATOR_LEN) as u32);
+        let node = Node([Some(Target::Data(data)), None]);
+        let mut buf = Vec::new();
+        node.write_to(&mut buf, RecordSize::Medium, node_count)
+            .unwrap();
+        assert_eq!(decode_medium(&buf), [max_ptr_value - 1, node_count]);
+    }
+
+    #[test]
+    fn test_record_size_boundaries() {
+        // the largest data pointer on each side of the boundaries `RecordSize::choose` picks
+        let node_count = 1000;
+        for (max_ptr_value, record_size) in [
+            ((1 << 24) - 1, RecordSize::Small),
+            (1 << 24, RecordSize::Medium),
+            ((1 << 28) - 1, RecordSize::Medium),
+            (1 << 28, RecordSize::Large),
+            (1 << 32, RecordSize::Large),
+        ] {
+            assert_eq!(RecordSize::choose(max_ptr_value), record_size);
+            let data =
+                data_ref((max_ptr_value - 1 - node_count - DATA_SECTION_SEPARATOR_LEN) as u32);
+            let node = Node([Some(Target::Data(data)), None]);
+            let mut buf = Vec::new();
+            node.write_to(&mut buf, record_size, node_count).unwrap();
+            assert_eq!(buf.len(), record_size.node_size());
+            let decoded = match record_size {
+                RecordSize::Small => [&buf[..3], &buf[3..]]
+                    .map(|record| record.iter().fold(0, |ptr, &b| ptr << 8 | b as usize)),
+                RecordSize::Medium => decode_medium(&buf),
+                RecordSize::Large => [&buf[..4], &buf[4..]]
+                    .map(|record| record.iter().fold(0, |ptr, &b| ptr << 8 | b as usize)),
+            };
+            assert_eq!(
+                decoded,
+                [max_ptr_value - 1, node_count],
+                "max pointer value {}",
+                max_ptr_value
+            );
+        }
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let mut tree = NodeTree::default();
+        tree.insert([false, true].into_iter(), data_ref(3));
+        tree.insert([true].into_iter(), data_ref(7));
+        assert_eq!(
+            tree.to_dot(),
+            "digraph {
+    n0 [label=\"0\"];
+    n0 -> n1 [label=\"0\"];
+    n0 -> d7 [label=\"1\"];
+    n1 [label=\"1\"];
+    n1 -> d3 [label=\"1\"];
+    d3 [label=\"data 3\", shape=box];
+    d7 [label=\"data 7\", shape=box];
+}
+"
+        );
+
+        tree.alias([false, false].into_iter(), [true].into_iter());
+        assert!(tree
+            .to_dot()
+            .contains("n1 -> n2 [label=\"0\", style=dashed];"));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut tree = NodeTree::default();
+        assert_eq!(tree.lookup([false, false].into_iter()), None);
+
+        tree.insert([false, true].into_iter(), data_ref(0));
+        tree.insert([true].into_iter(), data_ref(1));
+        assert_eq!(tree.lookup([false, true].into_iter()), Some(data_ref(0)));
+        assert_eq!(
+            tree.lookup([false, true, true, false].into_iter()),
+            Some(data_ref(0))
+        );
+        assert_eq!(tree.lookup([true, false].into_iter()), Some(data_ref(1)));
+        assert_eq!(tree.lookup([false, false, true].into_iter()), None);
+        // path ends before reaching any data
+        assert_eq!(tree.lookup([false].into_iter()), None);
         assert_eq!(
-            tree.nodes[0][false],
-            Some(Target::Data(DataRef { index: 0 }))
+            tree.lookup_with_depth([false, true, true, false].into_iter()),
+            Some((data_ref(0), 2))
         );
         assert_eq!(
-            tree.nodes[0][true],
-            Some(Target::Data(DataRef { index: 1 }))
+            tree.lookup_with_depth([true, false].into_iter()),
+            Some((data_ref(1), 1))
         );
     }
+
+    #[test]
+    fn test_remove() {
+        let mut tree = NodeTree::default();
+        let wide = [false, true];
+        let narrow = [false, true, true, false];
+        tree.insert(wide.into_iter(), data_ref(0));
+        tree.insert(narrow.into_iter(), data_ref(1));
+
+        assert!(tree.remove(narrow.into_iter()));
+        // back to the covering prefix
+        assert_eq!(tree.lookup(narrow.into_iter()), Some(data_ref(0)));
+        assert_eq!(tree.get(narrow.into_iter()), None);
+        assert_eq!(tree.get(wide.into_iter()), None);
+        assert_eq!(
+            tree.lookup([false, true, true, true].into_iter()),
+            Some(data_ref(0))
+        );
+        // already removed
+        assert!(!tree.remove(narrow.into_iter()));
+        // more specific than anything inserted
+        assert!(!tree.remove([false, true, false, false].into_iter()));
+        // not inserted at all
+        assert!(!tree.remove([true, true].into_iter()));
+        assert!(!tree.remove([].into_iter()));
+        // a less specific prefix inserted afterwards replaces the restored data
+        tree.insert([false].into_iter(), data_ref(2));
+        assert_eq!(tree.lookup(narrow.into_iter()), Some(data_ref(0)));
+
+        let mut tree = NodeTree::default();
+        tree.insert(wide.into_iter(), data_ref(0));
+        tree.insert([true].into_iter(), data_ref(1));
+        assert!(tree.remove(wide.into_iter()));
+        assert_eq!(tree.lookup(wide.into_iter()), None);
+        assert_eq!(tree.lookup([true].into_iter()), Some(data_ref(1)));
+    }
+
+    #[test]
+    fn test_remove_split() {
+        let mut tree = NodeTree::default();
+        let widest = [false];
+        let wide = [false, true];
+        let narrow = [false, true, true, false];
+        tree.insert(widest.into_iter(), data_ref(0));
+        tree.insert(narrow.into_iter(), data_ref(2));
+        tree.insert(wide.into_iter(), data_ref(1));
+
+        // the pieces of a split prefix go back to the covering one, more specific ones stay
+        assert!(tree.remove(wide.into_iter()));
+        assert_eq!(
+            tree.lookup([false, true, false].into_iter()),
+            Some(data_ref(0))
+        );
+        assert_eq!(tree.lookup(narrow.into_iter()), Some(data_ref(2)));
+        assert_eq!(tree.lookup([false, false].into_iter()), Some(data_ref(0)));
+
+        // removing the more specific one now restores the covering prefix there too
+        assert!(tree.remove(narrow.into_iter()));
+        assert_eq!(tree.lookup(narrow.into_iter()), Some(data_ref(0)));
+
+        // a prefix completely covered by more specific ones
+        let mut tree = NodeTree::default();
+        tree.insert(widest.into_iter(), data_ref(0));
+        tree.insert(wide.into_iter(), data_ref(1));
+        tree.insert([false, true, false].into_iter(), data_ref(2));
+        tree.insert([false, true, true].into_iter(), data_ref(3));
+        assert!(tree.remove([false, true, false].into_iter()));
+        assert_eq!(
+            tree.lookup([false, true, false].into_iter()),
+            Some(data_ref(1))
+        );
+        assert!(tree.remove(wide.into_iter()));
+        assert_eq!(
+            tree.lookup([false, true, false].into_iter()),
+            Some(data_ref(0))
+        );
+        assert_eq!(
+            tree.lookup([false, true, true].into_iter()),
+            Some(data_ref(3))
+        );
+
+        // the default route
+        let mut tree = NodeTree::default();
+        tree.insert([].into_iter(), data_ref(0));
+        tree.insert(wide.into_iter(), data_ref(1));
+        assert!(tree.remove([].into_iter()));
+        assert_eq!(tree.lookup([true].into_iter()), None);
+        assert_eq!(tree.lookup([false, false].into_iter()), None);
+        assert_eq!(tree.lookup(wide.into_iter()), Some(data_ref(1)));
+    }
+
+    #[test]
+    fn test_optimize() {
+        use crate::paths::IpAddrWithMask;
+        use std::net::{IpAddr, Ipv4Addr};
+
+        let mut tree = NodeTree::default();
+        // adjacent networks with the same data
+        for network in IpAddrWithMask::from_count(IpAddr::V4(Ipv4Addr::new(1, 0, 0, 0)), 1000) {
+            tree.insert(network, data_ref(0));
+        }
+        // identically shaped /24s
+        for third in 0..=255 {
+            for (fourth, index) in [(0, 1), (128, 2)] {
+                let addr = IpAddr::V4(Ipv4Addr::new(10, 0, third, fourth));
+                tree.insert(IpAddrWithMask::new(addr, 25), data_ref(index));
+            }
+        }
+        // a removed network leaves empty records behind
+        tree.insert(IpAddrWithMask::new([20, 0, 0, 0].into(), 30), data_ref(3));
+        tree.remove(IpAddrWithMask::new([20, 0, 0, 0].into(), 30));
+
+        let addrs: Vec<IpAddr> = (0..=255u8)
+            .flat_map(|x| {
+                [
+                    [1, 0, x / 64, x],
+                    [1, 0, 3, x],
+                    [1, 0, 4, x],
+                    [10, 0, x, x],
+                    [10, 1, x, 0],
+                    [20, 0, 0, x],
+                ]
+            })
+            .map(|octets| IpAddr::V4(Ipv4Addr::from(octets)))
+            .collect();
+        let before: Vec<_> = addrs
+            .iter()
+            .map(|&addr| tree.lookup(IpAddrWithMask::from(addr)))
+            .collect();
+        let node_count = tree.len();
+
+        let lookups = |tree: &NodeTree| -> Vec<_> {
+            addrs
+                .iter()
+                .map(|&addr| tree.lookup(IpAddrWithMask::from(addr)))
+                .collect()
+        };
+        let compacted = tree.compacted();
+        assert!(compacted.len() < node_count / 10);
+        assert_eq!(lookups(&compacted), before);
+
+        tree.optimize();
+        assert!(tree.len() < node_count);
+        assert!(tree.len() > compacted.len());
+        assert_eq!(lookups(&tree), before);
+
+        // optimizing again doesn't change anything
+        let node_count = tree.len();
+        tree.optimize();
+        assert_eq!(tree.len(), node_count);
+    }
+
+    #[test]
+    fn test_insert_after_optimize() {
+        use crate::paths::IpAddrWithMask;
+
+        let network = |s: &str| s.parse::<IpAddrWithMask>().unwrap();
+        let mut tree = NodeTree::default();
+        // identically shaped /24s
+        for third in 0..2 {
+            tree.insert(network(&format!("10.0.{}.0/25", third)), data_ref(0));
+            tree.insert(network(&format!("10.0.{}.128/25", third)), data_ref(1));
+        }
+        tree.optimize();
+        tree.insert(network("10.0.0.0/25"), data_ref(2));
+        assert_eq!(tree.lookup(network("10.0.0.1")), Some(data_ref(2)));
+        assert_eq!(tree.lookup(network("10.0.1.1")), Some(data_ref(0)));
+        assert_eq!(tree.lookup(network("10.0.1.129")), Some(data_ref(1)));
+    }
+
+    #[test]
+    fn test_iter_prefixes() {
+        let mut tree = NodeTree::default();
+        assert_eq!(tree.iter_prefixes().count(), 0);
+
+        let mut prefixes = vec![
+            (vec![false, true, true], data_ref(0)),
+            (vec![true], data_ref(1)),
+            (vec![false, false, false, true], data_ref(2)),
+            (vec![false, true, false, false, true], data_ref(0)),
+        ];
+        for (path, data) in &prefixes {
+            tree.insert(path.iter().copied(), *data);
+        }
+
+        let mut iterated: Vec<_> = tree.iter_prefixes().collect();
+        prefixes.sort();
+        iterated.sort();
+        assert_eq!(iterated, prefixes);
+    }
+
+    #[test]
+    fn test_iter_networks() {
+        let wide = vec![false];
+        let narrow = vec![false, true, true];
+        let other = vec![true, false];
+        // the wide network inserted before and after the one splitting it
+        for order in [[&wide, &narrow], [&narrow, &wide]] {
+            let mut tree = NodeTree::default();
+            for path in order {
+                tree.insert(path.iter().copied(), data_ref(path.len() as u32));
+            }
+            tree.insert(other.iter().copied(), data_ref(2));
+
+            assert_eq!(
+                tree.iter_networks().collect::<Vec<_>>(),
+                [
+                    (wide.clone(), data_ref(1)),
+                    (narrow.clone(), data_ref(3)),
+                    (other.clone(), data_ref(2)),
+                ]
+            );
+            assert_eq!(tree.get(wide.iter().copied()), None);
+            assert_eq!(tree.get_network(wide.iter().copied()), Some(data_ref(1)));
+            assert_eq!(tree.get_network(narrow.iter().copied()), Some(data_ref(3)));
+            // only pieces of the wide network
+            assert_eq!(tree.get_network([false, false].into_iter()), None);
+            assert_eq!(tree.get_network([false, true].into_iter()), None);
+            // within the other network
+            assert_eq!(tree.get_network([true, false, true].into_iter()), None);
+            assert_eq!(tree.get_network(std::iter::empty()), None);
+        }
+
+        // default route split by every other network
+        let mut tree = NodeTree::default();
+        tree.insert([true, true].into_iter(), data_ref(1));
+        tree.insert(std::iter::empty(), data_ref(0));
+        assert_eq!(
+            tree.iter_networks().collect::<Vec<_>>(),
+            [(vec![], data_ref(0)), (vec![true, true], data_ref(1))]
+        );
+        assert_eq!(tree.get_network(std::iter::empty()), Some(data_ref(0)));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut tree = NodeTree::default();
+        tree.insert([false].into_iter(), data_ref(0));
+        tree.reserve([false, true].into_iter());
+        tree.reserve([true, true].into_iter());
+        assert_eq!(tree.lookup([false, false].into_iter()), Some(data_ref(0)));
+        assert_eq!(tree.lookup([false, true].into_iter()), None);
+        assert_eq!(tree.lookup([true, true].into_iter()), None);
+        assert_eq!(tree.len(), 3);
+    }
+
+    #[test]
+    fn test_insert_empty_path() {
+        let mut tree = NodeTree::default();
+        tree.insert([].into_iter(), data_ref(0));
+        tree.insert([true, false].into_iter(), data_ref(1));
+        assert_eq!(tree.lookup([false].into_iter()), Some(data_ref(0)));
+        assert_eq!(tree.lookup([true, true].into_iter()), Some(data_ref(0)));
+        assert_eq!(tree.lookup([true, false].into_iter()), Some(data_ref(1)));
+    }
+
+    #[test]
+    fn test_insert_order() {
+        let networks = [
+            (vec![false], data_ref(0)),
+            (vec![false, true, true], data_ref(1)),
+            (vec![false, true], data_ref(2)),
+            (vec![], data_ref(3)),
+        ];
+        let expected = [
+            (vec![false, false], Some(data_ref(0))),
+            (vec![false, true, false], Some(data_ref(2))),
+            (vec![false, true, true], Some(data_ref(1))),
+            (vec![true], Some(data_ref(3))),
+        ];
+        // every order of inserting the networks
+        let orders = [
+            [0, 1, 2, 3],
+            [3, 2, 1, 0],
+            [1, 0, 3, 2],
+            [2, 3, 0, 1],
+            [1, 2, 0, 3],
+            [3, 1, 0, 2],
+        ];
+        for order in orders {
+            let mut tree = NodeTree::default();
+            for i in order {
+                let (path, data) = &networks[i];
+                tree.insert(path.iter().copied(), *data);
+            }
+            for (path, data) in &expected {
+                assert_eq!(
+                    tree.lookup(path.iter().copied()),
+                    *data,
+                    "order {:?}",
+                    order
+                );
+            }
+            assert_eq!(tree.get([false, true, true].into_iter()), Some(data_ref(1)));
+            // split by the more specific network
+            assert_eq!(tree.get([false, true].into_iter()), None);
+            // only a piece of the /1
+            assert_eq!(tree.get([false, false].into_iter()), None);
+
+            tree.optimize();
+            for (path, data) in &expected {
+                assert_eq!(
+                    tree.lookup(path.iter().copied()),
+                    *data,
+                    "order {:?}",
+                    order
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_path_len() {
+        let mut tree = NodeTree::default();
+        let longest = std::iter::repeat_n(true, MAX_PATH_LEN);
+        tree.insert(longest.clone(), data_ref(0));
+        tree.insert([true].into_iter(), data_ref(1));
+        // not mistaken for an alias
+        assert_eq!(tree.lookup(longest.clone()), Some(data_ref(0)));
+        assert_eq!(tree.get(longest), Some(data_ref(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "path longer than 128 bits")]
+    fn test_path_too_long() {
+        let mut tree = NodeTree::default();
+        tree.insert(std::iter::repeat_n(true, 320), data_ref(0));
+    }
+
+    #[test]
+    fn test_reserve_before_less_specific() {
+        let mut tree = NodeTree::default();
+        tree.reserve([false, true].into_iter());
+        tree.insert([false].into_iter(), data_ref(0));
+        assert_eq!(tree.lookup([false, false].into_iter()), Some(data_ref(0)));
+        assert_eq!(tree.lookup([false, true].into_iter()), None);
+    }
 }
@@ -5,6 +5,8 @@ use std::{
 
 use thiserror::Error;
 
+use crate::metadata::IpVersion;
+
 fn trailing_zeros(s: &[u8]) -> usize {
     let mut count = 0;
     for &b in s.iter().rev() {
@@ -18,20 +20,25 @@ fn trailing_zeros(s: &[u8]) -> usize {
     count as usize
 }
 
+fn octets_to_u128<const N: usize>(octets: [u8; N]) -> u128 {
+    octets
+        .iter()
+        .fold(0, |value, &byte| (value << 8) | byte as u128)
+}
+
 fn octets_with_mask_from_range<const N: usize>(
     start: [u8; N],
     stop: [u8; N],
 ) -> Vec<([u8; N], u8)> {
-    let mut count = 0usize;
-    for (from, to) in start.iter().zip(stop.iter()) {
-        count <<= 8;
-        count |= (to - from) as usize;
+    let diff = octets_to_u128(stop) - octets_to_u128(start);
+    if diff == u128::MAX {
+        // the whole IPv6 address space, its size doesn't fit in u128
+        return vec![(start, 0)];
     }
-    count += 1;
-    octets_with_mask(start, count)
+    octets_with_mask(start, diff + 1)
 }
 
-fn octets_with_mask<const N: usize>(mut start: [u8; N], mut count: usize) -> Vec<([u8; N], u8)> {
+fn octets_with_mask<const N: usize>(mut start: [u8; N], mut count: u128) -> Vec<([u8; N], u8)> {
     let mut result = Vec::new();
     while count > 0 {
         // calculate the biggest possible mask
@@ -39,6 +46,10 @@ fn octets_with_mask<const N: usize>(mut start: [u8; N], mut count: usize) -> Vec
         let suffix = zeros.min(count.ilog2() as usize);
         let mask = N * 8 - suffix;
         result.push((start, mask as u8));
+        if mask == 0 {
+            // the whole address space is covered
+            break;
+        }
 
         // increment start
         let mut byte_to_change = N - suffix / 8 - 1;
@@ -50,7 +61,8 @@ fn octets_with_mask<const N: usize>(mut start: [u8; N], mut count: usize) -> Vec
             } else {
                 start[byte_to_change] = 0;
                 if byte_to_change == 0 {
-                    break;
+                    // reached the end of the address space, don't wrap around
+                    return result;
                 }
                 byte_to_change -= 1;
                 bit_to_change = 0;
@@ -67,6 +79,13 @@ pub trait IntoBitPath {
     type Output: Iterator<Item = bool>;
 
     fn into_bit_path(self) -> Self::Output;
+
+    /// IP version of the network the path describes, if it describes one.
+    ///
+    /// IPv4 networks are placed under `::/96` when inserted into an IPv6 database.
+    fn ip_version(&self) -> Option<IpVersion> {
+        None
+    }
 }
 
 impl<T> IntoBitPath for T
@@ -91,16 +110,34 @@ impl IpAddrWithMask {
         Self { addr, mask }
     }
 
+    /// Inverse of `into_bit_path`. Paths longer than 32 bits (or all paths if `ipv6` is set)
+    /// produce IPv6 networks.
+    pub(crate) fn from_bit_path(path: &[bool], ipv6: bool) -> Self {
+        let mut octets = [0u8; 16];
+        for (i, &bit) in path.iter().enumerate() {
+            octets[i / 8] |= (bit as u8) << (7 - i % 8);
+        }
+        let mask = path.len() as u8;
+        if ipv6 || path.len() > 32 {
+            Self::new(IpAddr::V6(Ipv6Addr::from(octets)), mask)
+        } else {
+            let octets = [octets[0], octets[1], octets[2], octets[3]];
+            Self::new(IpAddr::V4(Ipv4Addr::from(octets)), mask)
+        }
+    }
+
+    /// Splits the `count` addresses starting at `addr` into the smallest set of networks
+    /// covering them. Stops at the end of the address space.
     pub fn from_count(addr: IpAddr, count: usize) -> Vec<Self> {
         match addr {
-            IpAddr::V4(addr) => octets_with_mask(addr.octets(), count)
+            IpAddr::V4(addr) => octets_with_mask(addr.octets(), count as u128)
                 .into_iter()
                 .map(|(octets, mask)| {
                     let addr = Ipv4Addr::from(octets);
                     Self::new(IpAddr::V4(addr), mask)
                 })
                 .collect(),
-            IpAddr::V6(addr) => octets_with_mask(addr.octets(), count)
+            IpAddr::V6(addr) => octets_with_mask(addr.octets(), count as u128)
                 .into_iter()
                 .map(|(octets, mask)| {
                     let addr = Ipv6Addr::from(octets);
@@ -110,29 +147,104 @@ impl IpAddrWithMask {
         }
     }
 
+    /// Number of addresses in the network. Saturates at `u128::MAX` for `::/0`, which has
+    /// one address more than that.
+    pub fn address_count(&self) -> u128 {
+        let (start, end) = self.bounds();
+        (end - start).saturating_add(1)
+    }
+
+    /// First and last address of the network as numbers.
+    fn bounds(&self) -> (u128, u128) {
+        let (addr, bits) = match self.addr {
+            IpAddr::V4(addr) => (u32::from(addr) as u128, 32u32),
+            IpAddr::V6(addr) => (u128::from(addr), 128),
+        };
+        let host_bits = bits - (self.mask as u32).min(bits);
+        let host_mask = u128::MAX.checked_shr(128 - host_bits).unwrap_or(0);
+        (addr & !host_mask, addr | host_mask)
+    }
+
+    /// Like `from_range`, but panics if the range is invalid.
     pub fn from_ip_range(first: IpAddr, last: IpAddr) -> Vec<Self> {
-        match (first, last) {
-            (IpAddr::V4(first), IpAddr::V4(last)) => {
-                octets_with_mask_from_range(first.octets(), last.octets())
+        Self::from_range(first, last).expect("invalid IP range")
+    }
+
+    /// Splits the inclusive range `start..=end` into the smallest set of networks covering it.
+    pub fn from_range(start: IpAddr, end: IpAddr) -> Result<Vec<Self>, RangeError> {
+        match (start, end) {
+            (IpAddr::V4(_), IpAddr::V6(_)) | (IpAddr::V6(_), IpAddr::V4(_)) => {
+                Err(RangeError::IpVersionMismatch)
+            }
+            _ if start > end => Err(RangeError::EndBeforeStart),
+            (IpAddr::V4(start), IpAddr::V4(end)) => {
+                Ok(octets_with_mask_from_range(start.octets(), end.octets())
                     .into_iter()
                     .map(|(octets, mask)| {
                         let addr = Ipv4Addr::from(octets);
                         Self::new(IpAddr::V4(addr), mask)
                     })
-                    .collect()
+                    .collect())
             }
-            (IpAddr::V6(first), IpAddr::V6(last)) => {
-                octets_with_mask_from_range(first.octets(), last.octets())
+            (IpAddr::V6(start), IpAddr::V6(end)) => {
+                Ok(octets_with_mask_from_range(start.octets(), end.octets())
                     .into_iter()
                     .map(|(octets, mask)| {
                         let addr = Ipv6Addr::from(octets);
                         Self::new(IpAddr::V6(addr), mask)
                     })
-                    .collect()
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Merges overlapping and adjacent networks into the smallest set of networks covering the
+/// same addresses, e.g. `196.11.106.0/24` and `196.11.107.0/24` into `196.11.106.0/23`.
+///
+/// The result is sorted, IPv4 networks first.
+pub fn merge_prefixes(prefixes: &[IpAddrWithMask]) -> Vec<IpAddrWithMask> {
+    let mut ranges: Vec<_> = prefixes
+        .iter()
+        .map(|prefix| (prefix.addr.is_ipv6(), prefix.bounds()))
+        .collect();
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(bool, (u128, u128))> = Vec::new();
+    for (ipv6, (start, end)) in ranges {
+        match merged.last_mut() {
+            Some((last_ipv6, (_, last_end)))
+                if *last_ipv6 == ipv6
+                    && last_end.checked_add(1).is_none_or(|next| next >= start) =>
+            {
+                *last_end = end.max(*last_end);
             }
-            _ => panic!("IP version mismatch"),
+            _ => merged.push((ipv6, (start, end))),
         }
     }
+
+    merged
+        .into_iter()
+        .flat_map(|(ipv6, (start, end))| {
+            let (start, end) = if ipv6 {
+                (IpAddr::V6(start.into()), IpAddr::V6(end.into()))
+            } else {
+                (
+                    IpAddr::V4((start as u32).into()),
+                    IpAddr::V4((end as u32).into()),
+                )
+            };
+            IpAddrWithMask::from_ip_range(start, end)
+        })
+        .collect()
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum RangeError {
+    #[error("start and end of the range are different IP versions")]
+    IpVersionMismatch,
+    #[error("end of the range is before its start")]
+    EndBeforeStart,
 }
 
 impl From<IpAddr> for IpAddrWithMask {
@@ -162,39 +274,140 @@ impl From<Ipv6Addr> for IpAddrWithMask {
     }
 }
 
-#[derive(Debug, Error)]
+/// Host bits set in the network's address are cleared, as they don't affect the network.
+#[cfg(feature = "ipnet")]
+impl From<ipnet::IpNet> for IpAddrWithMask {
+    fn from(net: ipnet::IpNet) -> Self {
+        match net {
+            ipnet::IpNet::V4(net) => Self::from(net),
+            ipnet::IpNet::V6(net) => Self::from(net),
+        }
+    }
+}
+
+#[cfg(feature = "ipnet")]
+impl From<ipnet::Ipv4Net> for IpAddrWithMask {
+    fn from(net: ipnet::Ipv4Net) -> Self {
+        Self::new(IpAddr::V4(net.network()), net.prefix_len())
+    }
+}
+
+#[cfg(feature = "ipnet")]
+impl From<ipnet::Ipv6Net> for IpAddrWithMask {
+    fn from(net: ipnet::Ipv6Net) -> Self {
+        Self::new(IpAddr::V6(net.network()), net.prefix_len())
+    }
+}
+
+impl std::fmt::Display for IpAddrWithMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.mask)
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
 pub enum IpAddrWithMaskParseError {
     #[error("address parse error")]
     AddrParseError(#[from] std::net::AddrParseError),
     #[error("mask parse error")]
     MaskParseError(#[from] std::num::ParseIntError),
+    #[error("mask {mask} is too large, at most {max} is allowed")]
+    MaskTooLarge { mask: u8, max: u8 },
+    #[error("IPv6 zone identifiers are not supported")]
+    ZoneIdNotSupported,
 }
 
 impl FromStr for IpAddrWithMask {
     type Err = IpAddrWithMaskParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split('/');
-        let addr = parts.next().unwrap_or(s);
-        let mask = parts.next();
+        let (addr, mask) = match s.split_once('/') {
+            Some((addr, mask)) => (addr, Some(mask)),
+            None => (s, None),
+        };
+        if addr.contains('%') {
+            return Err(IpAddrWithMaskParseError::ZoneIdNotSupported);
+        }
         let addr = IpAddr::from_str(addr)?;
         if let Some(mask) = mask {
-            Ok(Self {
-                addr,
-                mask: mask.parse()?,
-            })
+            let mask = mask.parse()?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            if mask > max {
+                return Err(IpAddrWithMaskParseError::MaskTooLarge { mask, max });
+            }
+            Ok(Self { addr, mask })
         } else {
             Ok(Self::from(addr))
         }
     }
 }
 
+/// Serializes the network as MaxMind DB `bytes`: the address octets followed by the mask,
+/// so 5 bytes for IPv4 and 17 bytes for IPv6.
+///
+/// Useful for embedding the matched network in the data record, e.g.:
+///
+/// ```
+/// # use maxminddb_writer::paths::{CidrBytes, IpAddrWithMask};
+/// #[derive(serde::Serialize)]
+/// struct Record {
+///     country: &'static str,
+///     network: CidrBytes,
+/// }
+///
+/// let record = Record {
+///     country: "PL",
+///     network: CidrBytes("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap()),
+/// };
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CidrBytes(pub IpAddrWithMask);
+
+impl CidrBytes {
+    /// Reconstructs the network from bytes written by the `Serialize` implementation.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let addr = match bytes.len() {
+            5 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[..4]).ok()?)),
+            17 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[..16]).ok()?)),
+            _ => return None,
+        };
+        let mask = bytes[bytes.len() - 1];
+        Some(Self(IpAddrWithMask::new(addr, mask)))
+    }
+}
+
+impl serde::Serialize for CidrBytes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.addr {
+            IpAddr::V4(addr) => {
+                let mut bytes = [0; 5];
+                bytes[..4].copy_from_slice(&addr.octets());
+                bytes[4] = self.0.mask;
+                serializer.serialize_bytes(&bytes)
+            }
+            IpAddr::V6(addr) => {
+                let mut bytes = [0; 17];
+                bytes[..16].copy_from_slice(&addr.octets());
+                bytes[16] = self.0.mask;
+                serializer.serialize_bytes(&bytes)
+            }
+        }
+    }
+}
+
 impl IntoBitPath for IpAddrWithMask {
     type Output = IpAddrWithMaskBitPath;
 
     fn into_bit_path(self) -> Self::Output {
         IpAddrWithMaskBitPath { addr: self, bit: 0 }
     }
+
+    fn ip_version(&self) -> Option<IpVersion> {
+        match self.addr {
+            IpAddr::V4(_) => Some(IpVersion::V4),
+            IpAddr::V6(_) => Some(IpVersion::V6),
+        }
+    }
 }
 
 pub struct IpAddrWithMaskBitPath {
@@ -222,12 +435,143 @@ impl Iterator for IpAddrWithMaskBitPath {
     }
 }
 
+/// The first `bit_len` bits of a byte string (most significant bit of each byte first), for
+/// building databases keyed on something else than IP addresses.
+///
+/// Readers only look up IP addresses, so a key is found by looking up the address with the
+/// same bits: an IPv6 database holds keys up to 16 bytes long.
+///
+/// ```
+/// # use maxminddb_writer::{metadata::IpVersion, paths::BytesPath, Database};
+/// let mut db = Database::default();
+/// db.metadata.ip_version = IpVersion::V6;
+/// let data = db.insert_value("starts with ab").unwrap();
+/// db.insert_node(BytesPath(b"ab".to_vec(), 16), data);
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BytesPath(pub Vec<u8>, pub usize);
+
+/// Panics if `bit_len` is longer than `bytes_len` bytes or than the longest path readers can
+/// look up (128 bits).
+fn check_bit_len(bytes_len: usize, bit_len: usize) {
+    assert!(
+        bit_len <= bytes_len * 8,
+        "bit length {} longer than {} bytes",
+        bit_len,
+        bytes_len
+    );
+    assert!(
+        bit_len <= crate::node::MAX_PATH_LEN,
+        "bit length {} longer than {} bits",
+        bit_len,
+        crate::node::MAX_PATH_LEN
+    );
+}
+
+impl IntoBitPath for BytesPath {
+    type Output = BytesBitPath;
+
+    /// Panics if the bit length is longer than the bytes or 128 bits.
+    fn into_bit_path(self) -> Self::Output {
+        let BytesPath(bytes, bit_len) = self;
+        check_bit_len(bytes.len(), bit_len);
+        BytesBitPath {
+            bytes,
+            bit_len,
+            bit: 0,
+        }
+    }
+}
+
+/// Like `BytesPath`, but borrowing the bytes.
+///
+/// ```
+/// # use maxminddb_writer::{metadata::IpVersion, paths::BytesSlicePath, Database};
+/// let mut db = Database::default();
+/// db.metadata.ip_version = IpVersion::V6;
+/// let data = db.insert_value("starts with a and the high half of b").unwrap();
+/// db.insert_node(BytesSlicePath(b"ab", 12), data);
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BytesSlicePath<'a>(pub &'a [u8], pub usize);
+
+impl<'a> From<(&'a [u8], usize)> for BytesSlicePath<'a> {
+    fn from((bytes, bit_len): (&'a [u8], usize)) -> Self {
+        Self(bytes, bit_len)
+    }
+}
+
+impl<'a> IntoBitPath for BytesSlicePath<'a> {
+    type Output = BytesBitPath<&'a [u8]>;
+
+    /// Panics if the bit length is longer than the bytes or 128 bits.
+    fn into_bit_path(self) -> Self::Output {
+        let BytesSlicePath(bytes, bit_len) = self;
+        check_bit_len(bytes.len(), bit_len);
+        BytesBitPath {
+            bytes,
+            bit_len,
+            bit: 0,
+        }
+    }
+}
+
+pub struct BytesBitPath<B = Vec<u8>> {
+    bytes: B,
+    bit_len: usize,
+    bit: usize,
+}
+
+impl<B: AsRef<[u8]>> Iterator for BytesBitPath<B> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bit >= self.bit_len {
+            return None;
+        }
+        let result = self.bytes.as_ref()[self.bit / 8] & (1 << (7 - self.bit % 8)) != 0;
+        self.bit += 1;
+        Some(result)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::vec;
 
     use super::*;
 
+    #[cfg(feature = "ipnet")]
+    #[test]
+    fn test_from_ipnet() {
+        let converted = |net: &str| IpAddrWithMask::from(net.parse::<ipnet::IpNet>().unwrap());
+        for net in [
+            "1.2.3.0/24",
+            "0.0.0.0/0",
+            "8.8.8.8/32",
+            "2001:db8::/32",
+            "::/0",
+        ] {
+            assert_eq!(converted(net), net.parse().unwrap());
+        }
+        // host bits are cleared
+        assert_eq!(converted("10.1.2.3/8"), "10.0.0.0/8".parse().unwrap());
+        assert_eq!(
+            converted("2001:db8:ffff::1/33"),
+            "2001:db8:8000::/33".parse().unwrap()
+        );
+
+        let ipv4 = IpAddrWithMask::from("1.2.3.0/24".parse::<ipnet::Ipv4Net>().unwrap());
+        assert!(ipv4
+            .into_bit_path()
+            .eq(BytesPath(vec![1, 2, 3], 24).into_bit_path()));
+        let ipv6 = IpAddrWithMask::from("2001:db8::/29".parse::<ipnet::Ipv6Net>().unwrap());
+        assert_eq!(ipv6.ip_version(), Some(IpVersion::V6));
+        assert!(ipv6
+            .into_bit_path()
+            .eq(BytesPath(vec![0x20, 0x01, 0x0d, 0xb8], 29).into_bit_path()));
+    }
+
     #[test]
     fn test_trailing_zeros() {
         assert_eq!(trailing_zeros(&[0, 0, 0, 0]), 32);
@@ -271,6 +615,84 @@ mod tests {
         );
     }
 
+    /// Checks that `prefixes` are aligned and cover exactly `first..=last` without gaps.
+    fn assert_covers(prefixes: &[IpAddrWithMask], first: u128, last: u128) {
+        let mut next = Some(first);
+        for prefix in prefixes {
+            let (start, end) = prefix.bounds();
+            assert_eq!(Some(start), next, "gap or overlap at {}", prefix);
+            let addr = match prefix.addr {
+                IpAddr::V4(addr) => u32::from(addr) as u128,
+                IpAddr::V6(addr) => u128::from(addr),
+            };
+            assert_eq!(addr, start, "{} has host bits set", prefix);
+            next = end.checked_add(1);
+        }
+        assert_eq!(next, last.checked_add(1));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_octets_with_mask_v4(start: u32, count in 1usize..(1 << 33)) {
+            let prefixes = IpAddrWithMask::from_count(IpAddr::V4(start.into()), count);
+            let last = (start as u128 + count as u128 - 1).min(u32::MAX as u128);
+            assert_covers(&prefixes, start as u128, last);
+            // no more prefixes than needed: at most two per bit
+            proptest::prop_assert!(prefixes.len() <= 2 * 32);
+        }
+
+        #[test]
+        fn prop_octets_with_mask_v6(start: u128, count in 1usize..) {
+            let prefixes = IpAddrWithMask::from_count(IpAddr::V6(start.into()), count);
+            let last = start.saturating_add(count as u128 - 1);
+            assert_covers(&prefixes, start, last);
+            proptest::prop_assert!(prefixes.len() <= 2 * 128);
+        }
+
+        #[test]
+        fn prop_from_range_v4(a: u32, b: u32) {
+            let (first, last) = (a.min(b), a.max(b));
+            let prefixes =
+                IpAddrWithMask::from_range(IpAddr::V4(first.into()), IpAddr::V4(last.into()))
+                    .unwrap();
+            assert_covers(&prefixes, first as u128, last as u128);
+        }
+
+        #[test]
+        fn prop_from_range_v6(a: u128, b: u128) {
+            let (first, last) = (a.min(b), a.max(b));
+            let prefixes =
+                IpAddrWithMask::from_range(IpAddr::V6(first.into()), IpAddr::V6(last.into()))
+                    .unwrap();
+            assert_covers(&prefixes, first, last);
+        }
+    }
+
+    #[test]
+    fn test_octets_with_mask_overflow() {
+        assert_eq!(
+            octets_with_mask([255, 255, 255, 0], 1024),
+            vec![([255, 255, 255, 0], 24)],
+        );
+        assert_eq!(
+            octets_with_mask([255, 255, 255, 250], 10),
+            vec![([255, 255, 255, 250], 31), ([255, 255, 255, 252], 30)],
+        );
+        assert_eq!(
+            IpAddrWithMask::from_count("255.255.255.0".parse().unwrap(), 1024),
+            vec!["255.255.255.0/24".parse().unwrap()],
+        );
+        assert_eq!(
+            IpAddrWithMask::from_count(
+                "ffff:ffff:ffff:ffff:ffff:ffff:ffff:fffe".parse().unwrap(),
+                3
+            ),
+            vec!["ffff:ffff:ffff:ffff:ffff:ffff:ffff:fffe/127"
+                .parse()
+                .unwrap()],
+        );
+    }
+
     #[test]
     fn test_octets_with_mask_from_range() {
         assert_eq!(
@@ -281,6 +703,93 @@ mod tests {
             octets_with_mask_from_range([0, 0, 0, 0], [1, 0, 0, 255]),
             vec![([0, 0, 0, 0], 8), ([1, 0, 0, 0], 24)],
         );
+        assert_eq!(
+            octets_with_mask_from_range([1, 0, 255, 0], [1, 1, 0, 255]),
+            vec![([1, 0, 255, 0], 24), ([1, 1, 0, 0], 24)],
+        );
+    }
+
+    #[test]
+    fn test_from_range() {
+        let v4 = |a, b, c, d| IpAddr::V4(Ipv4Addr::new(a, b, c, d));
+        let net = |s: &str| s.parse::<IpAddrWithMask>().unwrap();
+
+        assert_eq!(
+            IpAddrWithMask::from_range(v4(1, 0, 0, 0), v4(1, 0, 0, 254)),
+            Ok(vec![
+                net("1.0.0.0/25"),
+                net("1.0.0.128/26"),
+                net("1.0.0.192/27"),
+                net("1.0.0.224/28"),
+                net("1.0.0.240/29"),
+                net("1.0.0.248/30"),
+                net("1.0.0.252/31"),
+                net("1.0.0.254/32"),
+            ])
+        );
+        assert_eq!(
+            IpAddrWithMask::from_range(v4(1, 0, 0, 240), v4(1, 0, 1, 15)),
+            Ok(vec![net("1.0.0.240/28"), net("1.0.1.0/28")])
+        );
+        assert_eq!(
+            IpAddrWithMask::from_range(v4(196, 11, 105, 0), v4(196, 11, 108, 255)),
+            Ok(vec![
+                net("196.11.105.0/24"),
+                net("196.11.106.0/23"),
+                net("196.11.108.0/24"),
+            ])
+        );
+        // crossing byte boundaries in both directions
+        assert_eq!(
+            IpAddrWithMask::from_range(v4(192, 168, 1, 5), v4(192, 168, 3, 200)),
+            Ok(vec![
+                net("192.168.1.5/32"),
+                net("192.168.1.6/31"),
+                net("192.168.1.8/29"),
+                net("192.168.1.16/28"),
+                net("192.168.1.32/27"),
+                net("192.168.1.64/26"),
+                net("192.168.1.128/25"),
+                net("192.168.2.0/24"),
+                net("192.168.3.0/25"),
+                net("192.168.3.128/26"),
+                net("192.168.3.192/29"),
+                net("192.168.3.200/32"),
+            ])
+        );
+        assert_eq!(
+            IpAddrWithMask::from_range(v4(10, 0, 0, 1), v4(10, 0, 0, 1)),
+            Ok(vec![net("10.0.0.1/32")])
+        );
+        assert_eq!(
+            IpAddrWithMask::from_range(v4(0, 0, 0, 0), v4(255, 255, 255, 255)),
+            Ok(vec![net("0.0.0.0/0")])
+        );
+        assert_eq!(
+            IpAddrWithMask::from_range(
+                "2001:db8::".parse().unwrap(),
+                "2001:db8:0:1:ffff:ffff:ffff:ffff".parse().unwrap()
+            ),
+            Ok(vec![net("2001:db8::/63")])
+        );
+        assert_eq!(
+            IpAddrWithMask::from_range("::".parse().unwrap(), Ipv6Addr::from(u128::MAX).into()),
+            Ok(vec![net("::/0")])
+        );
+
+        assert_eq!(
+            IpAddrWithMask::from_range(v4(10, 0, 0, 2), v4(10, 0, 0, 1)),
+            Err(RangeError::EndBeforeStart)
+        );
+        assert_eq!(
+            IpAddrWithMask::from_range(v4(10, 0, 0, 1), "::1".parse().unwrap()),
+            Err(RangeError::IpVersionMismatch)
+        );
+        // IPv6 addresses order after IPv4 ones, the IP versions are checked first
+        assert_eq!(
+            IpAddrWithMask::from_range("::1".parse().unwrap(), v4(10, 0, 0, 1)),
+            Err(RangeError::IpVersionMismatch)
+        );
     }
 
     #[test]
@@ -306,4 +815,310 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_address_count() {
+        let count = |s: &str| s.parse::<IpAddrWithMask>().unwrap().address_count();
+        assert_eq!(count("196.11.105.0/24"), 256);
+        assert_eq!(count("1.2.3.4/32"), 1);
+        assert_eq!(count("1.2.3.4"), 1);
+        assert_eq!(count("0.0.0.0/0"), 1 << 32);
+        assert_eq!(count("2001:db8::/64"), 1 << 64);
+        assert_eq!(count("::1/128"), 1);
+        assert_eq!(count("8000::/1"), 1 << 127);
+        assert_eq!(count("::/0"), u128::MAX);
+
+        let start = "196.11.105.0".parse().unwrap();
+        let total: u128 = IpAddrWithMask::from_count(start, 1000)
+            .iter()
+            .map(IpAddrWithMask::address_count)
+            .sum();
+        assert_eq!(total, 1000);
+    }
+
+    #[test]
+    fn test_display() {
+        for (network, expected) in [
+            (
+                IpAddrWithMask::new([196, 11, 105, 0].into(), 24),
+                "196.11.105.0/24",
+            ),
+            (
+                IpAddrWithMask::from(Ipv4Addr::new(1, 2, 3, 4)),
+                "1.2.3.4/32",
+            ),
+            (IpAddrWithMask::new([0, 0, 0, 0].into(), 0), "0.0.0.0/0"),
+            (IpAddrWithMask::from(Ipv6Addr::LOCALHOST), "::1/128"),
+            (
+                IpAddrWithMask::new("2001:db8::".parse().unwrap(), 32),
+                "2001:db8::/32",
+            ),
+        ] {
+            assert_eq!(network.to_string(), expected);
+            assert_eq!(network.to_string().parse::<IpAddrWithMask>(), Ok(network));
+        }
+    }
+
+    #[test]
+    fn test_merge_prefixes() {
+        let nets = |nets: &[&str]| -> Vec<IpAddrWithMask> {
+            nets.iter().map(|net| net.parse().unwrap()).collect()
+        };
+
+        assert_eq!(
+            merge_prefixes(&nets(&["196.11.107.0/24", "196.11.106.0/24"])),
+            nets(&["196.11.106.0/23"])
+        );
+        // adjacent but not aligned
+        assert_eq!(
+            merge_prefixes(&nets(&["196.11.105.0/24", "196.11.106.0/24"])),
+            nets(&["196.11.105.0/24", "196.11.106.0/24"])
+        );
+        // not adjacent
+        assert_eq!(
+            merge_prefixes(&nets(&["10.0.0.0/24", "10.0.2.0/24"])),
+            nets(&["10.0.0.0/24", "10.0.2.0/24"])
+        );
+        // overlapping and duplicated
+        assert_eq!(
+            merge_prefixes(&nets(&[
+                "10.0.0.0/8",
+                "10.1.0.0/16",
+                "11.0.0.0/8",
+                "10.0.0.0/8"
+            ])),
+            nets(&["10.0.0.0/7"])
+        );
+        // chains of merges
+        assert_eq!(
+            merge_prefixes(&nets(&[
+                "1.0.0.0/26",
+                "1.0.0.64/26",
+                "1.0.0.128/25",
+                "1.0.1.0/24"
+            ])),
+            nets(&["1.0.0.0/23"])
+        );
+        // host bits set
+        assert_eq!(
+            merge_prefixes(&nets(&["1.0.0.7/24"])),
+            nets(&["1.0.0.0/24"])
+        );
+        // families are kept apart
+        assert_eq!(
+            merge_prefixes(&nets(&[
+                "2001:db8:1::/48",
+                "255.255.255.255/32",
+                "2001:db8::/48",
+                "::/128"
+            ])),
+            nets(&["255.255.255.255/32", "::/128", "2001:db8::/47"])
+        );
+        assert_eq!(
+            merge_prefixes(&nets(&["0.0.0.0/1", "128.0.0.0/1"])),
+            nets(&["0.0.0.0/0"])
+        );
+        assert_eq!(
+            merge_prefixes(&nets(&["::/1", "8000::/1"])),
+            nets(&["::/0"])
+        );
+        assert_eq!(merge_prefixes(&[]), vec![]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let parse = IpAddrWithMask::from_str;
+        assert_eq!(
+            parse("1.2.3.4"),
+            Ok(IpAddrWithMask::new([1, 2, 3, 4].into(), 32))
+        );
+        assert_eq!(
+            parse("1.2.3.0/24"),
+            Ok(IpAddrWithMask::new([1, 2, 3, 0].into(), 24))
+        );
+        assert_eq!(
+            parse("0.0.0.0/0"),
+            Ok(IpAddrWithMask::new([0, 0, 0, 0].into(), 0))
+        );
+        assert_eq!(
+            parse("1.2.3.4/32"),
+            Ok(IpAddrWithMask::new([1, 2, 3, 4].into(), 32))
+        );
+        assert_eq!(
+            parse("fe80::1/128"),
+            Ok(IpAddrWithMask::new("fe80::1".parse().unwrap(), 128))
+        );
+        assert_eq!(
+            parse("::/0"),
+            Ok(IpAddrWithMask::new("::".parse().unwrap(), 0))
+        );
+
+        assert_eq!(
+            parse("1.2.3.4/33"),
+            Err(IpAddrWithMaskParseError::MaskTooLarge { mask: 33, max: 32 })
+        );
+        assert_eq!(
+            parse("1.2.3.4/99"),
+            Err(IpAddrWithMaskParseError::MaskTooLarge { mask: 99, max: 32 })
+        );
+        assert_eq!(
+            parse("fe80::1/129"),
+            Err(IpAddrWithMaskParseError::MaskTooLarge {
+                mask: 129,
+                max: 128
+            })
+        );
+        assert!(matches!(
+            parse("1.2.3.4/256"),
+            Err(IpAddrWithMaskParseError::MaskParseError(_))
+        ));
+        assert!(matches!(
+            parse("1.2.3.4/8/9"),
+            Err(IpAddrWithMaskParseError::MaskParseError(_))
+        ));
+        assert!(matches!(
+            parse("1.2.3/8"),
+            Err(IpAddrWithMaskParseError::AddrParseError(_))
+        ));
+        assert_eq!(
+            parse("fe80::1%eth0/64"),
+            Err(IpAddrWithMaskParseError::ZoneIdNotSupported)
+        );
+        assert_eq!(
+            parse("fe80::1%eth0"),
+            Err(IpAddrWithMaskParseError::ZoneIdNotSupported)
+        );
+    }
+
+    #[test]
+    fn test_cidr_bytes() {
+        let v4: IpAddrWithMask = "196.11.105.0/24".parse().unwrap();
+        let v6: IpAddrWithMask = "2001:db8::/32".parse().unwrap();
+
+        #[derive(serde::Serialize)]
+        struct Record {
+            country: &'static str,
+            network: CidrBytes,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct RawRecord<'a> {
+            country: &'a str,
+            network: &'a [u8],
+        }
+
+        let mut db = crate::Database::default();
+        let v4_data = db
+            .insert_value(Record {
+                country: "ZA",
+                network: CidrBytes(v4),
+            })
+            .unwrap();
+        let v6_data = db
+            .insert_value(Record {
+                country: "XX",
+                network: CidrBytes(v6),
+            })
+            .unwrap();
+        db.insert_node([false].into_iter(), v4_data);
+        db.insert_node([true].into_iter(), v6_data);
+        let raw_db = db.to_vec().unwrap();
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let record: RawRecord = reader.lookup([1, 0, 0, 0].into()).unwrap();
+        assert_eq!(record.country, "ZA");
+        assert_eq!(record.network, [196, 11, 105, 0, 24]);
+        assert_eq!(CidrBytes::from_bytes(record.network), Some(CidrBytes(v4)));
+
+        let record: RawRecord = reader.lookup([128, 0, 0, 0].into()).unwrap();
+        assert_eq!(record.country, "XX");
+        assert_eq!(record.network.len(), 17);
+        assert_eq!(CidrBytes::from_bytes(record.network), Some(CidrBytes(v6)));
+
+        assert_eq!(CidrBytes::from_bytes(&[1, 2, 3, 4]), None);
+    }
+
+    #[test]
+    fn test_bytes_path() {
+        assert_eq!(
+            BytesPath(vec![0b1010_0000, 0xff], 4)
+                .into_bit_path()
+                .collect::<Vec<_>>(),
+            [true, false, true, false]
+        );
+        assert_eq!(BytesPath(vec![0xff], 8).into_bit_path().count(), 8);
+        assert_eq!(BytesPath(vec![], 0).into_bit_path().count(), 0);
+
+        let mut db = crate::Database::new_v6();
+        for (key, value) in [(&b"ab"[..], "ab"), (b"abc", "abc"), (b"b", "b")] {
+            let data = db.insert_value(value).unwrap();
+            db.insert_node(BytesPath(key.to_vec(), key.len() * 8), data);
+        }
+        let raw_db = db.to_vec().unwrap();
+
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let lookup = |key: &[u8]| {
+            let mut octets = [0; 16];
+            octets[..key.len()].copy_from_slice(key);
+            reader.lookup::<&str>(Ipv6Addr::from(octets).into()).ok()
+        };
+        assert_eq!(lookup(b"abc"), Some("abc"));
+        assert_eq!(lookup(b"abd"), Some("ab"));
+        assert_eq!(lookup(b"ab"), Some("ab"));
+        assert_eq!(lookup(b"bcd"), Some("b"));
+        assert_eq!(lookup(b"c"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "longer than 1 bytes")]
+    fn test_bytes_path_too_long() {
+        BytesPath(vec![0xff], 9).into_bit_path();
+    }
+
+    #[test]
+    #[should_panic(expected = "bit length 136 longer than 128 bits")]
+    fn test_bytes_path_longer_than_ipv6() {
+        BytesPath(vec![0xff; 17], 136).into_bit_path();
+    }
+
+    #[test]
+    fn test_bytes_slice_path() {
+        let bits = |bytes: &[u8], bit_len| {
+            BytesSlicePath(bytes, bit_len)
+                .into_bit_path()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bits(&[], 0), []);
+        assert_eq!(bits(&[0b1010_0000], 3), [true, false, true]);
+        assert_eq!(
+            bits(&[0x0f], 8),
+            [false, false, false, false, true, true, true, true]
+        );
+        assert_eq!(
+            bits(&[0xff, 0b0100_0000], 10),
+            [true, true, true, true, true, true, true, true, false, true]
+        );
+
+        let key = [0x12, 0x34, 0x56];
+        assert_eq!(
+            BytesSlicePath::from((&key[..], 20))
+                .into_bit_path()
+                .collect::<Vec<_>>(),
+            BytesPath(key.to_vec(), 20)
+                .into_bit_path()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "longer than 1 bytes")]
+    fn test_bytes_slice_path_too_long() {
+        BytesSlicePath(&[0xff], 9).into_bit_path();
+    }
+
+    #[test]
+    #[should_panic(expected = "bit length 136 longer than 128 bits")]
+    fn test_bytes_slice_path_longer_than_ipv6() {
+        BytesSlicePath(&[0xff; 17], 136).into_bit_path();
+    }
 }
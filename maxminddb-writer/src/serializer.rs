@@ -1,46 +1,206 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
 use serde::ser;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
+    #[cfg(feature = "std")]
     IO(std::io::Error),
     Custom(String),
+    #[deprecated(note = "maps and sequences of unknown length are buffered and counted")]
     UnknownLength,
-    LengthOutOfRange,
+    LengthOutOfRange {
+        data_type: &'static str,
+        len: usize,
+    },
     IntegerOutOfRange,
+    PointerOutOfRange,
+    NonStringMapKey,
+    NonFiniteFloat,
+    DatabaseTooLarge,
+    TooManyNodes,
+    MaxDepthExceeded,
+    IpVersionMismatch,
+    #[cfg(feature = "std")]
+    InvalidMetadata(crate::metadata::MetadataError),
+    #[cfg(feature = "std")]
+    MergeConflict(crate::paths::IpAddrWithMask),
+    #[cfg(feature = "std")]
+    InvalidRange(crate::paths::RangeError),
+    UnsortedNetworks,
+    #[cfg(feature = "std")]
+    WhileWriting {
+        section: Section,
+        source: std::io::Error,
+    },
+}
+
+/// Part of a database being written when an IO error happened, see `Error::WhileWriting`.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Section {
+    Nodes,
+    DataSection,
+    Metadata,
+}
+
+#[cfg(feature = "std")]
+impl Section {
+    /// Attributes an IO error to this section, other errors are returned as they are.
+    pub(crate) fn context(self, err: impl Into<Error>) -> Error {
+        match err.into() {
+            Error::IO(source) => Error::WhileWriting {
+                section: self,
+                source,
+            },
+            err => err,
+        }
+    }
 }
 
+#[cfg(feature = "std")]
+impl core::fmt::Display for Section {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Section::Nodes => write!(f, "search tree"),
+            Section::DataSection => write!(f, "data section"),
+            Section::Metadata => write!(f, "metadata"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error::IO(err)
     }
 }
 
-impl std::error::Error for Error {}
+#[cfg(feature = "std")]
+impl From<crate::metadata::MetadataError> for Error {
+    fn from(err: crate::metadata::MetadataError) -> Self {
+        Error::InvalidMetadata(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<crate::paths::RangeError> for Error {
+    fn from(err: crate::paths::RangeError) -> Self {
+        Error::InvalidRange(err)
+    }
+}
+
+/// IO errors are equal if they are of the same `ErrorKind`, so errors can be compared in
+/// tests.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            #[cfg(feature = "std")]
+            (Error::IO(a), Error::IO(b)) => a.kind() == b.kind(),
+            (Error::Custom(a), Error::Custom(b)) => a == b,
+            (
+                Error::LengthOutOfRange { data_type, len },
+                Error::LengthOutOfRange {
+                    data_type: other_data_type,
+                    len: other_len,
+                },
+            ) => data_type == other_data_type && len == other_len,
+            #[cfg(feature = "std")]
+            (Error::InvalidMetadata(a), Error::InvalidMetadata(b)) => a == b,
+            #[cfg(feature = "std")]
+            (Error::MergeConflict(a), Error::MergeConflict(b)) => a == b,
+            #[cfg(feature = "std")]
+            (Error::InvalidRange(a), Error::InvalidRange(b)) => a == b,
+            #[cfg(feature = "std")]
+            (
+                Error::WhileWriting { section, source },
+                Error::WhileWriting {
+                    section: other_section,
+                    source: other_source,
+                },
+            ) => section == other_section && source.kind() == other_source.kind(),
+            #[allow(deprecated)]
+            (Error::UnknownLength, Error::UnknownLength) => true,
+            (Error::IntegerOutOfRange, Error::IntegerOutOfRange)
+            | (Error::PointerOutOfRange, Error::PointerOutOfRange)
+            | (Error::NonStringMapKey, Error::NonStringMapKey)
+            | (Error::NonFiniteFloat, Error::NonFiniteFloat)
+            | (Error::DatabaseTooLarge, Error::DatabaseTooLarge)
+            | (Error::TooManyNodes, Error::TooManyNodes)
+            | (Error::MaxDepthExceeded, Error::MaxDepthExceeded)
+            | (Error::IpVersionMismatch, Error::IpVersionMismatch)
+            | (Error::UnsortedNetworks, Error::UnsortedNetworks) => true,
+            _ => false,
+        }
+    }
+}
+
+impl core::error::Error for Error {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            Error::IO(err) | Error::WhileWriting { source: err, .. } => Some(err),
+            #[cfg(feature = "std")]
+            Error::InvalidMetadata(err) => Some(err),
+            #[cfg(feature = "std")]
+            Error::InvalidRange(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl ser::Error for Error {
     fn custom<T>(msg: T) -> Self
     where
-        T: std::fmt::Display,
+        T: core::fmt::Display,
     {
         Error::Custom(msg.to_string())
     }
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match *self {
-            Error::IO(ref err) => write!(f, "IO error: {}", err),
+            #[cfg(feature = "std")]
+            Error::IO(_) => write!(f, "IO error"),
             Error::Custom(ref err) => write!(f, "Custom error: {}", err),
+            #[allow(deprecated)]
             Error::UnknownLength => write!(f, "Unknown length"),
-            Error::LengthOutOfRange => write!(f, "Length out of range"),
+            Error::LengthOutOfRange { data_type, len } => {
+                write!(f, "Length {} out of range for {}", len, data_type)
+            }
             Error::IntegerOutOfRange => write!(f, "Integer out of range"),
+            Error::PointerOutOfRange => write!(f, "Pointer out of range"),
+            Error::NonStringMapKey => write!(f, "Map key must be a string"),
+            Error::NonFiniteFloat => write!(f, "Float must be finite"),
+            Error::DatabaseTooLarge => write!(f, "Database too large to be addressed by pointers"),
+            Error::TooManyNodes => write!(f, "Too many nodes for the metadata node count"),
+            Error::MaxDepthExceeded => write!(f, "Value nested too deeply"),
+            Error::IpVersionMismatch => {
+                write!(f, "Inserted networks don't match the database IP version")
+            }
+            #[cfg(feature = "std")]
+            Error::InvalidMetadata(_) => write!(f, "Invalid metadata"),
+            #[cfg(feature = "std")]
+            Error::MergeConflict(network) => {
+                write!(f, "Both databases have different data for {}", network)
+            }
+            #[cfg(feature = "std")]
+            Error::InvalidRange(_) => write!(f, "Invalid IP range"),
+            Error::UnsortedNetworks => write!(f, "Networks must be inserted in sorted order"),
+            #[cfg(feature = "std")]
+            Error::WhileWriting { section, .. } => write!(f, "IO error while writing {}", section),
         }
     }
 }
 
 #[derive(Clone, Copy, Debug)]
 enum TypeId {
-    // Pointer = 1,
+    Pointer = 1,
     String = 2,
     Double = 3,
     Bytes = 4,
@@ -57,27 +217,162 @@ enum TypeId {
     Float = 15,
 }
 
+impl TypeId {
+    fn name(self) -> &'static str {
+        match self {
+            TypeId::Pointer => "pointer",
+            TypeId::String => "string",
+            TypeId::Double => "double",
+            TypeId::Bytes => "bytes",
+            TypeId::Uint16 => "uint16",
+            TypeId::Uint32 => "uint32",
+            TypeId::Map => "map",
+            TypeId::Int32 => "int32",
+            TypeId::Uint64 => "uint64",
+            TypeId::Uint128 => "uint128",
+            TypeId::Array => "array",
+            TypeId::Boolean => "boolean",
+            TypeId::Float => "float",
+        }
+    }
+
+    /// Checks if the size (payload length or element count) can be encoded in the control byte.
+    fn check_size(self, size: usize) -> Result<(), Error> {
+        if size > 16_843_036 {
+            Err(Error::LengthOutOfRange {
+                data_type: self.name(),
+                len: size,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+use sink::Write;
+
+// private so the trait can't be named (or implemented) outside of the crate
+mod sink {
+    #[cfg(not(feature = "std"))]
+    use alloc::vec::Vec;
+
+    use super::Error;
+
+    /// Where a `Serializer` writes to: any `std::io::Write` with the `std` feature, a
+    /// `Vec<u8>` without it.
+    pub trait Write {
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), Error>;
+    }
+
+    #[cfg(feature = "std")]
+    impl<W: std::io::Write + ?Sized> Write for W {
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+            Ok(std::io::Write::write_all(self, buf)?)
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    impl Write for Vec<u8> {
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+            self.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    impl<W: Write + ?Sized> Write for &mut W {
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+            (**self).write_all(buf)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Options {
+    /// Write map entries sorted by their serialized keys instead of in iteration order.
+    pub deterministic: bool,
+    /// Write enum variants carrying data as single entry maps `{variant_name: value}`
+    /// instead of just the value.
+    pub tagged_enums: bool,
+    /// Reject NaN and infinite floats instead of writing their IEEE 754 bits.
+    pub strict_floats: bool,
+    /// Fail with `Error::MaxDepthExceeded` for values nested deeper than this many maps and
+    /// arrays, instead of recursing without a limit.
+    pub max_depth: Option<usize>,
+    /// Id of the datastore `Pointer`s have to point into, like `Datastore::check_ref` checks
+    /// (any if unset).
+    pub store: Option<u32>,
+}
+
 pub struct Serializer<W> {
     writer: W,
+    options: Options,
+    // set while serializing a struct field value: a top level `None` is skipped instead of written
+    omit_none: bool,
+    omitted: bool,
+    // set while serializing the offset of a `Pointer`: the next `u64` is written as a pointer
+    pointer_next: bool,
+    // number of maps and arrays the value being serialized is in
+    depth: usize,
 }
 
+/// Name of the newtype struct `Pointer` serializes as, the offset it wraps is written as a
+/// pointer instead of an integer.
+pub(crate) const POINTER_STRUCT_NAME: &str = "$maxminddb_writer::Pointer";
+
 impl<W> Serializer<W> {
     pub fn new(writer: W) -> Self {
-        Serializer { writer }
+        Self::with_options(writer, Options::default())
+    }
+
+    pub(crate) fn with_options(writer: W, options: Options) -> Self {
+        Serializer {
+            writer,
+            options,
+            omit_none: false,
+            omitted: false,
+            pointer_next: false,
+            depth: 0,
+        }
+    }
+
+    /// Fails with `Error::MaxDepthExceeded` instead of serializing values nested in more than
+    /// `max_depth` maps and arrays, which could overflow the stack.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.options.max_depth = Some(max_depth);
+        self
     }
 
     pub fn into_inner(self) -> W {
         self.writer
     }
 
+    /// Serializer for buffering a part of the value being serialized, at the same depth.
+    fn nested<V>(&self, writer: V) -> Serializer<V> {
+        Serializer {
+            depth: self.depth,
+            ..Serializer::with_options(writer, self.options)
+        }
+    }
+
+    /// Goes into a map or an array, until its `end`.
+    fn enter(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        match self.options.max_depth {
+            Some(max_depth) if self.depth > max_depth => Err(Error::MaxDepthExceeded),
+            _ => Ok(()),
+        }
+    }
+
     fn write_control(&mut self, type_id: TypeId, size: usize) -> Result<(), Error>
     where
-        W: std::io::Write,
+        W: Write,
     {
+        // a value is being written so any `None` below it isn't a struct field
+        self.omit_none = false;
+
         // check if the size will fit
-        if size > 16_843_036 {
-            return Err(Error::LengthOutOfRange);
-        }
+        type_id.check_size(size)?;
 
         // split the type into possibly 2 bytes
         let (first, second) = if type_id as usize <= 7 {
@@ -114,24 +409,74 @@ impl<W> Serializer<W> {
         Ok(())
     }
 
+    /// Writes a pointer to the given offset in the data section using the smallest encoding that fits.
+    pub fn serialize_pointer(&mut self, offset: usize) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        let first = (TypeId::Pointer as u8) << 5;
+        if offset < 2048 {
+            // 11 bits
+            self.writer
+                .write_all(&[first | (offset >> 8) as u8, offset as u8])?;
+        } else if offset < 526_336 {
+            // 19 bits
+            let value = offset - 2048;
+            self.writer.write_all(&[
+                first | 0b01000 | (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            ])?;
+        } else if offset < 134_744_064 {
+            // 27 bits
+            let value = offset - 526_336;
+            self.writer.write_all(&[
+                first | 0b10000 | (value >> 24) as u8,
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            ])?;
+        } else {
+            // 32 bits
+            let value: u32 = offset.try_into().map_err(|_| Error::PointerOutOfRange)?;
+            self.writer.write_all(&[first | 0b11000])?;
+            self.writer.write_all(&value.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Starts a single entry map keyed by the variant name, the variant's value has to follow.
+    fn write_variant_tag(&mut self, variant: &str) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        self.write_control(TypeId::Map, 1)?;
+        self.serialize(variant)
+    }
+
     fn serialize<T>(&mut self, value: T) -> Result<(), Error>
     where
         T: ser::Serialize,
-        W: std::io::Write,
+        W: Write,
     {
         value.serialize(self)
     }
 }
 
+/// MaxMind DB only has a signed 32-bit integer type, so signed values are mapped as follows:
+/// - values fitting in `i32` are written as `int32`,
+/// - positive `i64` values above `i32::MAX` are written as `uint64`,
+/// - positive `i128` values above `i32::MAX` are written as `uint128`,
+/// - negative values below `i32::MIN` fail with [`Error::IntegerOutOfRange`].
 impl<'a, W> ser::Serializer for &'a mut Serializer<W>
 where
-    W: std::io::Write,
+    W: Write,
 {
     type Ok = ();
 
     type Error = Error;
 
-    type SerializeSeq = Self;
+    type SerializeSeq = SeqSerializer<'a, W>;
 
     type SerializeTuple = Self;
 
@@ -139,11 +484,11 @@ where
 
     type SerializeTupleVariant = Self;
 
-    type SerializeMap = Self;
+    type SerializeMap = MapSerializer<'a, W>;
 
-    type SerializeStruct = Self;
+    type SerializeStruct = StructSerializer<'a, W>;
 
-    type SerializeStructVariant = Self;
+    type SerializeStructVariant = StructSerializer<'a, W>;
 
     fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
         self.write_control(TypeId::Boolean, if v { 1 } else { 0 })?;
@@ -159,20 +504,38 @@ where
     }
 
     fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
-        // FIXME
-        self.write_control(TypeId::Int32, 4)?;
-        self.writer.write_all(&v.to_be_bytes())?;
-        Ok(())
+        if v < 0 {
+            // readers don't sign-extend shorter payloads so negative values need all 4 bytes
+            self.write_control(TypeId::Int32, 4)?;
+            self.writer.write_all(&v.to_be_bytes())?;
+            Ok(())
+        } else {
+            (v as u32).as_big_endian_slice(|buf| {
+                self.write_control(TypeId::Int32, buf.len())?;
+                self.writer.write_all(buf)?;
+                Ok(())
+            })
+        }
     }
 
     fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
-        let v: i32 = v.try_into().map_err(|_| Error::IntegerOutOfRange)?;
-        self.serialize_i32(v)
+        if let Ok(v) = i32::try_from(v) {
+            self.serialize_i32(v)
+        } else if v > 0 {
+            self.serialize_u64(v as u64)
+        } else {
+            Err(Error::IntegerOutOfRange)
+        }
     }
 
     fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
-        let v: i32 = v.try_into().map_err(|_| Error::IntegerOutOfRange)?;
-        self.serialize_i32(v)
+        if let Ok(v) = i32::try_from(v) {
+            self.serialize_i32(v)
+        } else if v > 0 {
+            self.serialize_u128(v as u128)
+        } else {
+            Err(Error::IntegerOutOfRange)
+        }
     }
 
     fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
@@ -196,6 +559,17 @@ where
     }
 
     fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        if core::mem::take(&mut self.pointer_next) {
+            // see `Pointer`'s `Serialize`
+            let (store, offset) = ((v >> 32) as u32, v as u32);
+            if let Some(expected) = self.options.store {
+                assert_eq!(
+                    store, expected,
+                    "DataRef used with a different database than the one it was inserted into"
+                );
+            }
+            return self.serialize_pointer(offset as usize);
+        }
         v.as_big_endian_slice(|buf| {
             self.write_control(TypeId::Uint64, buf.len())?;
             self.writer.write_all(buf)?;
@@ -212,12 +586,18 @@ where
     }
 
     fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
+        if self.options.strict_floats && !v.is_finite() {
+            return Err(Error::NonFiniteFloat);
+        }
         self.write_control(TypeId::Float, 4)?;
         self.writer.write_all(&v.to_be_bytes())?;
         Ok(())
     }
 
     fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
+        if self.options.strict_floats && !v.is_finite() {
+            return Err(Error::NonFiniteFloat);
+        }
         self.write_control(TypeId::Double, 8)?;
         self.writer.write_all(&v.to_be_bytes())?;
         Ok(())
@@ -244,12 +624,16 @@ where
     }
 
     fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        if self.omit_none {
+            self.omitted = true;
+            return Ok(());
+        }
         self.serialize_bool(false)
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         value.serialize(self)
     }
@@ -271,40 +655,59 @@ where
         self.serialize_str(variant)
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(
+    fn serialize_newtype_struct<T>(
         self,
-        _name: &'static str,
+        name: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
+        if name == POINTER_STRUCT_NAME {
+            self.pointer_next = true;
+            let result = value.serialize(&mut *self);
+            self.pointer_next = false;
+            return result;
+        }
         value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
         _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
+        variant: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
+        if self.options.tagged_enums {
+            self.write_variant_tag(variant)?;
+        }
         value.serialize(self)
     }
 
     fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        self.enter()?;
         let Some(len) = len else {
-            return Err(Error::UnknownLength);
+            // elements are counted and the control byte is written at the end
+            return Ok(SeqSerializer {
+                ser: self,
+                buffered: Some((Vec::new(), 0)),
+            });
         };
         self.write_control(TypeId::Array, len)?;
-        Ok(self)
+        Ok(SeqSerializer {
+            ser: self,
+            buffered: None,
+        })
     }
 
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
-        self.serialize_seq(Some(len))
+        self.enter()?;
+        self.write_control(TypeId::Array, len)?;
+        Ok(self)
     }
 
     fn serialize_tuple_struct(
@@ -312,25 +715,49 @@ where
         _name: &'static str,
         len: usize,
     ) -> Result<Self::SerializeTupleStruct, Self::Error> {
-        self.serialize_seq(Some(len))
+        self.enter()?;
+        self.write_control(TypeId::Array, len)?;
+        Ok(self)
     }
 
     fn serialize_tuple_variant(
         self,
         _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
+        variant: &'static str,
         len: usize,
     ) -> Result<Self::SerializeTupleVariant, Self::Error> {
-        self.serialize_seq(Some(len))
+        if self.options.tagged_enums {
+            self.write_variant_tag(variant)?;
+        }
+        self.enter()?;
+        self.write_control(TypeId::Array, len)?;
+        Ok(self)
     }
 
     fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        self.enter()?;
         let Some(len) = len else {
-            return Err(Error::UnknownLength);
+            // entries are counted and the control byte is written at the end
+            return Ok(MapSerializer {
+                ser: self,
+                entries: Some(Vec::new()),
+            });
         };
+        // check upfront as the control byte might be written only after all the entries
+        TypeId::Map.check_size(len)?;
+        if self.options.deterministic {
+            // entries are sorted and the control byte is written at the end
+            return Ok(MapSerializer {
+                ser: self,
+                entries: Some(Vec::with_capacity(len)),
+            });
+        }
         self.write_control(TypeId::Map, len)?;
-        Ok(self)
+        Ok(MapSerializer {
+            ser: self,
+            entries: None,
+        })
     }
 
     fn serialize_struct(
@@ -338,262 +765,610 @@ where
         _name: &'static str,
         len: usize,
     ) -> Result<Self::SerializeStruct, Self::Error> {
-        self.serialize_map(Some(len))
+        self.enter()?;
+        Ok(StructSerializer::new(self, len))
     }
 
     fn serialize_struct_variant(
         self,
         _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
+        variant: &'static str,
         len: usize,
     ) -> Result<Self::SerializeStructVariant, Self::Error> {
-        self.serialize_map(Some(len))
+        if self.options.tagged_enums {
+            self.write_variant_tag(variant)?;
+        }
+        self.enter()?;
+        Ok(StructSerializer::new(self, len))
     }
 }
 
-impl<'a, W> ser::SerializeSeq for &'a mut Serializer<W>
+pub struct SeqSerializer<'a, W> {
+    ser: &'a mut Serializer<W>,
+    // serialized elements and their count when the length wasn't known upfront
+    buffered: Option<(Vec<u8>, usize)>,
+}
+
+impl<W> ser::SerializeSeq for SeqSerializer<'_, W>
 where
-    W: std::io::Write,
+    W: Write,
 {
-    type Ok = <Self as ser::Serializer>::Ok;
+    type Ok = ();
 
-    type Error = <Self as ser::Serializer>::Error;
+    type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.serialize(value)
+        if let Some((buf, count)) = &mut self.buffered {
+            value.serialize(&mut self.ser.nested(buf))?;
+            *count += 1;
+            Ok(())
+        } else {
+            self.ser.serialize(value)
+        }
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
+        if let Some((buf, count)) = self.buffered {
+            self.ser.write_control(TypeId::Array, count)?;
+            self.ser.writer.write_all(&buf)?;
+        }
+        self.ser.depth -= 1;
         Ok(())
     }
 }
 
-impl<'a, W> ser::SerializeTuple for &'a mut Serializer<W>
+impl<W> ser::SerializeTuple for &mut Serializer<W>
 where
-    W: std::io::Write,
+    W: Write,
 {
     type Ok = <Self as ser::Serializer>::Ok;
 
     type Error = <Self as ser::Serializer>::Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         self.serialize(value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.depth -= 1;
         Ok(())
     }
 }
 
-impl<'a, W> ser::SerializeTupleStruct for &'a mut Serializer<W>
+impl<W> ser::SerializeTupleStruct for &mut Serializer<W>
 where
-    W: std::io::Write,
+    W: Write,
 {
     type Ok = <Self as ser::Serializer>::Ok;
 
     type Error = <Self as ser::Serializer>::Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         self.serialize(value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.depth -= 1;
         Ok(())
     }
 }
 
-impl<'a, W> ser::SerializeTupleVariant for &'a mut Serializer<W>
+impl<W> ser::SerializeTupleVariant for &mut Serializer<W>
 where
-    W: std::io::Write,
+    W: Write,
 {
     type Ok = <Self as ser::Serializer>::Ok;
 
     type Error = <Self as ser::Serializer>::Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         self.serialize(value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.depth -= 1;
         Ok(())
     }
 }
 
+pub struct MapSerializer<'a, W> {
+    ser: &'a mut Serializer<W>,
+    // serialized (key, value) pairs when entries have to be sorted before writing or the
+    // length wasn't known upfront
+    entries: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+}
+
 // TODO: do we have to care about the order of calls?
-impl<'a, W> ser::SerializeMap for &'a mut Serializer<W>
+impl<W> ser::SerializeMap for MapSerializer<'_, W>
 where
-    W: std::io::Write,
+    W: Write,
 {
-    type Ok = <Self as ser::Serializer>::Ok;
+    type Ok = ();
 
-    type Error = <Self as ser::Serializer>::Error;
+    type Error = Error;
 
-    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Self::Error>
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.serialize(key)
+        if let Some(entries) = &mut self.entries {
+            let mut buf = Vec::new();
+            let mut ser = self.ser.nested(&mut buf);
+            key.serialize(MapKeySerializer { ser: &mut ser })?;
+            entries.push((buf, Vec::new()));
+            Ok(())
+        } else {
+            key.serialize(MapKeySerializer { ser: self.ser })
+        }
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.serialize(value)
+        if let Some(entries) = &mut self.entries {
+            let Some((_, buf)) = entries.last_mut() else {
+                return Err(Error::Custom("map value without a key".to_string()));
+            };
+            value.serialize(&mut self.ser.nested(buf))
+        } else {
+            self.ser.serialize(value)
+        }
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
+        if let Some(mut entries) = self.entries {
+            if self.ser.options.deterministic {
+                entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+            }
+            self.ser.write_control(TypeId::Map, entries.len())?;
+            for (key, value) in entries {
+                self.ser.writer.write_all(&key)?;
+                self.ser.writer.write_all(&value)?;
+            }
+        }
+        self.ser.depth -= 1;
+        Ok(())
+    }
+}
+
+/// Buffers struct fields so that fields set to `None` can be left out of the map entirely.
+pub struct StructSerializer<'a, W> {
+    ser: &'a mut Serializer<W>,
+    fields: Vec<u8>,
+    count: usize,
+}
+
+impl<'a, W> StructSerializer<'a, W> {
+    fn new(ser: &'a mut Serializer<W>, len: usize) -> Self {
+        StructSerializer {
+            ser,
+            fields: Vec::with_capacity(len * 8),
+            count: 0,
+        }
+    }
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        let start = self.fields.len();
+        let mut ser = self.ser.nested(&mut self.fields);
+        ser.serialize(key)?;
+        ser.omit_none = true;
+        value.serialize(&mut ser)?;
+        if ser.omitted {
+            self.fields.truncate(start);
+        } else {
+            self.count += 1;
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        self.ser.write_control(TypeId::Map, self.count)?;
+        self.ser.writer.write_all(&self.fields)?;
+        self.ser.depth -= 1;
         Ok(())
     }
 }
 
-impl<'a, W> ser::SerializeStruct for &'a mut Serializer<W>
+impl<W> ser::SerializeStruct for StructSerializer<'_, W>
 where
-    W: std::io::Write,
+    W: Write,
 {
-    type Ok = <Self as ser::Serializer>::Ok;
+    type Ok = ();
 
-    type Error = <Self as ser::Serializer>::Error;
+    type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
-        &mut self,
-        key: &'static str,
-        value: &T,
-    ) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.serialize(key)?;
-        self.serialize(value)
+        StructSerializer::serialize_field(self, key, value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+        StructSerializer::end(self)
     }
 }
 
-impl<'a, W> ser::SerializeStructVariant for &'a mut Serializer<W>
+impl<W> ser::SerializeStructVariant for StructSerializer<'_, W>
 where
-    W: std::io::Write,
+    W: Write,
 {
-    type Ok = <Self as ser::Serializer>::Ok;
+    type Ok = ();
 
-    type Error = <Self as ser::Serializer>::Error;
+    type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
-        &mut self,
-        key: &'static str,
-        value: &T,
-    ) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.serialize(key)?;
-        self.serialize(value)
+        StructSerializer::serialize_field(self, key, value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+        StructSerializer::end(self)
     }
 }
 
-trait AsBigEndianSlice {
-    fn as_big_endian_slice<R, F: FnMut(&[u8]) -> R>(&self, f: F) -> R;
+/// Serializer for map keys which only lets strings through, as required by MaxMind DB.
+struct MapKeySerializer<'a, W> {
+    ser: &'a mut Serializer<W>,
 }
 
-macro_rules! impl_as_big_endian_slice_for {
-    ($uint:path) => {
-        impl AsBigEndianSlice for $uint {
-            fn as_big_endian_slice<R, F: FnMut(&[u8]) -> R>(&self, mut f: F) -> R {
-                let bytes = self.to_be_bytes();
-                let mut slice = bytes.as_ref();
-                while slice.strip_prefix(&[0]).is_some() {
-                    slice = &slice[1..];
-                }
-                f(slice)
-            }
-        }
-    };
-}
+impl<W> ser::Serializer for MapKeySerializer<'_, W>
+where
+    W: Write,
+{
+    type Ok = ();
 
-impl_as_big_endian_slice_for!(u16);
-impl_as_big_endian_slice_for!(u32);
-impl_as_big_endian_slice_for!(u64);
-impl_as_big_endian_slice_for!(u128);
+    type Error = Error;
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+    type SerializeSeq = ser::Impossible<(), Error>;
 
-    use crate::Database;
+    type SerializeTuple = ser::Impossible<(), Error>;
 
-    use super::*;
+    type SerializeTupleStruct = ser::Impossible<(), Error>;
 
-    fn control(type_id: TypeId, len: usize) -> Vec<u8> {
-        let mut buf = Vec::new();
-        let mut serializer = Serializer::new(&mut buf);
-        serializer.write_control(type_id, len).unwrap();
-        buf
+    type SerializeTupleVariant = ser::Impossible<(), Error>;
+
+    type SerializeMap = ser::Impossible<(), Error>;
+
+    type SerializeStruct = ser::Impossible<(), Error>;
+
+    type SerializeStructVariant = ser::Impossible<(), Error>;
+
+    fn serialize_bool(self, _v: bool) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
     }
 
-    #[test]
-    fn test_write_control() {
-        assert_eq!(control(TypeId::String, 2), vec![0b01000010]);
-        assert_eq!(control(TypeId::String, 28), vec![0b01011100]);
-        assert_eq!(control(TypeId::String, 80), vec![0b01011101, 0b00110011]);
-        assert_eq!(
-            control(TypeId::String, 13392),
-            vec![0b01011110, 0b00110011, 0b00110011]
-        );
-        assert_eq!(
-            control(TypeId::String, 3421264),
-            vec![0b01011111, 0b00110011, 0b00110011, 0b00110011]
-        );
-        assert_eq!(
-            control(TypeId::String, 16843036),
-            vec![0b01011111, 0xFF, 0xFF, 0xFF]
-        );
+    fn serialize_i8(self, _v: i8) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
 
-        assert_eq!(control(TypeId::Uint32, 1), vec![0b11000001]);
-        assert_eq!(control(TypeId::Uint128, 3), vec![0b00000011, 0b00000011]);
+    fn serialize_i16(self, _v: i16) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
     }
 
-    fn create_minimal_db<T>(value: &T) -> Vec<u8>
-    where
-        T: serde::Serialize,
-    {
-        let mut db = Database::default();
-        let data = db.insert_value(value).unwrap();
-        db.insert_node([false].into_iter(), data);
-        db.insert_node([true].into_iter(), data);
-        db.to_vec().unwrap()
+    fn serialize_i32(self, _v: i32) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
     }
 
-    fn test_pass_through_maxminddb<T>(value: T)
-    where
-        T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
-    {
-        let db = create_minimal_db(&value);
-        let reader = maxminddb::Reader::from_source(db.as_slice()).unwrap();
-        let deserialized_value: T = reader.lookup([0, 0, 0, 0].try_into().unwrap()).unwrap();
-        assert_eq!(value, deserialized_value);
+    fn serialize_i64(self, _v: i64) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
     }
 
-    #[test]
-    fn test() {
+    fn serialize_i128(self, _v: i128) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_u8(self, _v: u8) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_u128(self, _v: u128) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        ser::Serializer::serialize_char(self.ser, v)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        ser::Serializer::serialize_str(self.ser, v)
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(Error::NonStringMapKey)
+    }
+}
+
+trait AsBigEndianSlice {
+    fn as_big_endian_slice<R, F: FnMut(&[u8]) -> R>(&self, f: F) -> R;
+}
+
+macro_rules! impl_as_big_endian_slice_for {
+    ($uint:path) => {
+        impl AsBigEndianSlice for $uint {
+            fn as_big_endian_slice<R, F: FnMut(&[u8]) -> R>(&self, mut f: F) -> R {
+                let bytes = self.to_be_bytes();
+                let leading_zeros = bytes.iter().take_while(|&&b| b == 0).count();
+                f(&bytes[leading_zeros..])
+            }
+        }
+    };
+}
+
+impl_as_big_endian_slice_for!(u16);
+impl_as_big_endian_slice_for!(u32);
+impl_as_big_endian_slice_for!(u64);
+impl_as_big_endian_slice_for!(u128);
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use crate::{value::DataValue, Database};
+
+    use super::*;
+
+    fn control(type_id: TypeId, len: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut serializer = Serializer::new(&mut buf);
+        serializer.write_control(type_id, len).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_write_control() {
+        assert_eq!(control(TypeId::String, 2), vec![0b01000010]);
+        assert_eq!(control(TypeId::String, 28), vec![0b01011100]);
+        assert_eq!(control(TypeId::String, 80), vec![0b01011101, 0b00110011]);
+        assert_eq!(
+            control(TypeId::String, 13392),
+            vec![0b01011110, 0b00110011, 0b00110011]
+        );
+        assert_eq!(
+            control(TypeId::String, 3421264),
+            vec![0b01011111, 0b00110011, 0b00110011, 0b00110011]
+        );
+        assert_eq!(
+            control(TypeId::String, 16843036),
+            vec![0b01011111, 0xFF, 0xFF, 0xFF]
+        );
+
+        assert_eq!(control(TypeId::Uint32, 1), vec![0b11000001]);
+        assert_eq!(control(TypeId::Uint128, 3), vec![0b00000011, 0b00000011]);
+    }
+
+    fn pointer(offset: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut serializer = Serializer::new(&mut buf);
+        serializer.serialize_pointer(offset).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_serialize_pointer() {
+        assert_eq!(pointer(0), vec![0b00100000, 0]);
+        assert_eq!(pointer(2047), vec![0b00100111, 0xFF]);
+        assert_eq!(pointer(2048), vec![0b00101000, 0, 0]);
+        assert_eq!(pointer(526_335), vec![0b00101111, 0xFF, 0xFF]);
+        assert_eq!(pointer(526_336), vec![0b00110000, 0, 0, 0]);
+        assert_eq!(pointer(134_744_063), vec![0b00110111, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(
+            pointer(134_744_064),
+            vec![0b00111000, 0x08, 0x08, 0x08, 0x00]
+        );
+        assert_eq!(
+            pointer(u32::MAX as usize),
+            vec![0b00111000, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert!(matches!(
+            Serializer::new(Vec::new()).serialize_pointer(u32::MAX as usize + 1),
+            Err(Error::PointerOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn test_pointer_pass_through_maxminddb() {
+        for padding in [0, 3000, 600_000] {
+            let mut db = Database::default();
+            if padding > 0 {
+                db.insert_value("x".repeat(padding)).unwrap();
+            }
+            let target = db.insert_value("foo").unwrap();
+            let data = db
+                .data
+                .insert_with(|serializer| {
+                    serializer.write_control(TypeId::Map, 2)?;
+                    serializer.serialize("a")?;
+                    serializer.serialize_pointer(target.index as usize)?;
+                    serializer.serialize("b")?;
+                    serializer.serialize_pointer(target.index as usize)
+                })
+                .unwrap();
+            db.insert_node([false].into_iter(), data);
+            let raw_db = db.to_vec().unwrap();
+
+            let reader = maxminddb::Reader::from_source(raw_db.as_slice()).unwrap();
+            let value: HashMap<String, String> = reader.lookup([0, 0, 0, 0].into()).unwrap();
+            assert_eq!(value.len(), 2);
+            assert_eq!(value["a"], "foo");
+            assert_eq!(value["b"], "foo");
+        }
+    }
+
+    fn serialized<T>(value: T) -> Vec<u8>
+    where
+        T: serde::Serialize,
+    {
+        let mut buf = Vec::new();
+        value.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        buf
+    }
+
+    fn create_minimal_db<T>(value: &T) -> Vec<u8>
+    where
+        T: serde::Serialize,
+    {
+        let mut db = Database::default();
+        let data = db.insert_value(value).unwrap();
+        db.insert_node([false].into_iter(), data);
+        db.insert_node([true].into_iter(), data);
+        db.to_vec().unwrap()
+    }
+
+    fn test_pass_through_maxminddb<T>(value: T)
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        let db = create_minimal_db(&value);
+        let reader = maxminddb::Reader::from_source(db.as_slice()).unwrap();
+        let deserialized_value: T = reader.lookup([0, 0, 0, 0].into()).unwrap();
+        assert_eq!(value, deserialized_value);
+    }
+
+    #[test]
+    fn test() {
         test_pass_through_maxminddb(false);
         test_pass_through_maxminddb(true);
 
@@ -641,4 +1416,769 @@ mod tests {
             c: vec![1, 2, 3],
         });
     }
+
+    #[test]
+    fn test_empty_values() {
+        assert_eq!(serialized(""), control(TypeId::String, 0));
+        assert_eq!(serialized(Vec::<u32>::new()), control(TypeId::Array, 0));
+        assert_eq!(
+            serialized(HashMap::<String, u32>::new()),
+            control(TypeId::Map, 0)
+        );
+
+        test_pass_through_maxminddb(String::new());
+        test_pass_through_maxminddb(Vec::<u32>::new());
+        test_pass_through_maxminddb(HashMap::<String, u32>::new());
+
+        // readers see them as the empty values of their types, not as missing or false
+        for (value, expected) in [
+            (create_minimal_db(&""), DataValue::String(String::new())),
+            (
+                create_minimal_db(&Vec::<u32>::new()),
+                DataValue::Array(Vec::new()),
+            ),
+            (
+                create_minimal_db(&HashMap::<String, u32>::new()),
+                DataValue::Map(Vec::new()),
+            ),
+        ] {
+            let reader = maxminddb::Reader::from_source(value.as_slice()).unwrap();
+            let value: DataValue = reader.lookup([0, 0, 0, 0].into()).unwrap();
+            assert_eq!(value, expected);
+        }
+
+        #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+        struct Empty {
+            name: String,
+            tags: Vec<String>,
+            names: HashMap<String, String>,
+        }
+        test_pass_through_maxminddb(Empty {
+            name: String::new(),
+            tags: Vec::new(),
+            names: HashMap::new(),
+        });
+    }
+
+    #[test]
+    fn test_i32() {
+        assert_eq!(serialized(0i32), vec![0b00000000, 0b00000001]);
+        assert_eq!(serialized(1i32), vec![0b00000001, 0b00000001, 1]);
+        assert_eq!(serialized(255i32), vec![0b00000001, 0b00000001, 0xFF]);
+        assert_eq!(serialized(256i32), vec![0b00000010, 0b00000001, 1, 0]);
+        assert_eq!(
+            serialized(-1i32),
+            vec![0b00000100, 0b00000001, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+
+        test_pass_through_maxminddb(0i32);
+        test_pass_through_maxminddb(1i32);
+        test_pass_through_maxminddb(-1i32);
+        test_pass_through_maxminddb(255i32);
+        test_pass_through_maxminddb(-256i32);
+        test_pass_through_maxminddb(i32::MAX);
+        test_pass_through_maxminddb(i32::MIN);
+    }
+
+    #[test]
+    fn test_byte_integers() {
+        // uints are variable length, so bytes take no more space than in a dedicated type
+        assert_eq!(serialized(42u8), vec![0b10100001, 42]);
+        assert_eq!(serialized(0u8), vec![0b10100000]);
+        for value in 0..=u8::MAX {
+            assert_eq!(serialized(value), serialized(value as u16));
+        }
+        for value in i8::MIN..=i8::MAX {
+            assert_eq!(serialized(value), serialized(value as i32));
+        }
+        assert_eq!(serialized(42i8), vec![0b00000001, 0b00000001, 42]);
+
+        test_pass_through_maxminddb(u8::MAX);
+        test_pass_through_maxminddb(i8::MIN);
+        test_pass_through_maxminddb(i8::MAX);
+    }
+
+    #[test]
+    fn test_large_sizes() {
+        use crate::value::DataValue;
+
+        // bytes has a single byte type, followed by the size bytes
+        let bytes = DataValue::Bytes((0..70_000).map(|i| i as u8).collect());
+        let buf = serialized(&bytes);
+        let leftover = (70_000 - 65_821u32).to_be_bytes();
+        assert_eq!(
+            buf[..4],
+            [0b10011111, leftover[1], leftover[2], leftover[3]]
+        );
+        assert_eq!(buf.len(), 4 + 70_000);
+        test_pass_through_maxminddb(bytes);
+
+        // array is an extended type, its byte comes before the size bytes
+        for (len, header) in [
+            (65_820, vec![0b00011110, 4, 0xff, 0xff]),
+            (65_821, vec![0b00011111, 4, 0, 0, 0]),
+            (
+                70_000,
+                vec![0b00011111, 4, leftover[1], leftover[2], leftover[3]],
+            ),
+        ] {
+            let array = (0..len).map(|i| i as u16).collect::<Vec<_>>();
+            let buf = serialized(&array);
+            assert_eq!(buf[..header.len()], header);
+            let (decoded, end) = DataValue::decode(&buf, 0).unwrap();
+            assert_eq!(end, buf.len());
+            assert_eq!(
+                decoded,
+                DataValue::Array(array.iter().map(|&i| DataValue::Uint16(i)).collect())
+            );
+            test_pass_through_maxminddb(array);
+        }
+    }
+
+    #[test]
+    fn test_multibyte_strings() {
+        // the size is in bytes, not chars
+        for value in ["日本語", "zażółć", "😀"] {
+            let buf = serialized(value);
+            assert_eq!(buf[0], 0b01000000 | value.len() as u8);
+            assert_eq!(&buf[1..], value.as_bytes());
+            test_pass_through_maxminddb(value.to_string());
+        }
+        for value in ['ż', '日', '😀'] {
+            assert_eq!(serialized(value)[0], 0b01000000 | value.len_utf8() as u8);
+            test_pass_through_maxminddb(value.to_string());
+        }
+        test_pass_through_maxminddb(std::collections::BTreeMap::from([(
+            "język".to_string(),
+            "日本語".repeat(20),
+        )]));
+    }
+
+    #[test]
+    fn test_three_byte_uint32() {
+        // a geoname ID, like in GeoIP2 databases
+        assert_eq!(serialized(6_252_001u32), vec![0b11000011, 0x5f, 0x65, 0xe1]);
+        assert_eq!(
+            serialized(0x00ff_ffffu32),
+            vec![0b11000011, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(serialized(0x0100_0000u32), vec![0b11000100, 1, 0, 0, 0]);
+
+        for value in [6_252_001u32, 0x00ff_ffff, 0x0100_0000] {
+            test_pass_through_maxminddb(value);
+            test_pass_through_maxminddb(std::collections::BTreeMap::from([(
+                "geoname_id".to_string(),
+                value,
+            )]));
+        }
+    }
+
+    #[test]
+    fn test_wide_signed_integers() {
+        let db = create_minimal_db(&5_000_000_000i64);
+        let reader = maxminddb::Reader::from_source(db.as_slice()).unwrap();
+        let value: u64 = reader.lookup([0, 0, 0, 0].into()).unwrap();
+        assert_eq!(value, 5_000_000_000);
+
+        let db = create_minimal_db(&5_000_000_000i128);
+        let reader = maxminddb::Reader::from_source(db.as_slice()).unwrap();
+        let value: u128 = reader.lookup([0, 0, 0, 0].into()).unwrap();
+        assert_eq!(value, 5_000_000_000);
+
+        for value in [u64::MAX as i128 + 1, 1 << 100, i128::MAX] {
+            let db = create_minimal_db(&value);
+            let reader = maxminddb::Reader::from_source(db.as_slice()).unwrap();
+            let read: u128 = reader.lookup([0, 0, 0, 0].into()).unwrap();
+            assert_eq!(read, value as u128);
+            assert_eq!(serialized(value), serialized(value as u128));
+        }
+
+        assert_eq!(serialized(5_000_000_000i64), serialized(5_000_000_000u64));
+        assert_eq!(serialized(42i64), serialized(42i32));
+        assert!(matches!(
+            Database::default().insert_value(-5_000_000_000i64),
+            Err(Error::IntegerOutOfRange)
+        ));
+        assert!(matches!(
+            Database::default().insert_value(i128::MIN),
+            Err(Error::IntegerOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn test_non_string_map_keys() {
+        let mut map = HashMap::new();
+        map.insert(1u32, "one".to_string());
+        assert!(matches!(
+            Database::default().insert_value(&map),
+            Err(Error::NonStringMapKey)
+        ));
+
+        let mut map = HashMap::new();
+        map.insert((1, 2), 3);
+        assert!(matches!(
+            Database::default().insert_value(&map),
+            Err(Error::NonStringMapKey)
+        ));
+
+        let mut map = HashMap::new();
+        map.insert('a', 1);
+        assert!(Database::default().insert_value(&map).is_ok());
+    }
+
+    struct Entries(Vec<(&'static str, u32)>);
+
+    impl serde::Serialize for Entries {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().cloned())
+        }
+    }
+
+    #[test]
+    fn test_deterministic_maps() {
+        let serialized_with = |value: &Entries, deterministic: bool| {
+            let mut buf = Vec::new();
+            let mut serializer = Serializer::with_options(
+                &mut buf,
+                Options {
+                    deterministic,
+                    ..Default::default()
+                },
+            );
+            ser::Serialize::serialize(value, &mut serializer).unwrap();
+            buf
+        };
+        let sorted = Entries(vec![("a", 1), ("b", 2), ("c", 3)]);
+        let shuffled = Entries(vec![("c", 3), ("a", 1), ("b", 2)]);
+
+        assert_ne!(
+            serialized_with(&sorted, false),
+            serialized_with(&shuffled, false)
+        );
+        assert_eq!(
+            serialized_with(&sorted, true),
+            serialized_with(&shuffled, true)
+        );
+        assert_eq!(
+            serialized_with(&sorted, true),
+            serialized_with(&sorted, false)
+        );
+    }
+
+    #[test]
+    fn test_deterministic_database() {
+        let build = |keys: &mut dyn Iterator<Item = u32>| {
+            let mut map = HashMap::new();
+            for key in keys {
+                map.insert(format!("key{}", key), key);
+            }
+            let mut db = Database::default();
+            db.set_deterministic(true);
+            db.metadata
+                .description
+                .insert("en".to_string(), "test".to_string());
+            db.metadata
+                .description
+                .insert("pl".to_string(), "test".to_string());
+            let data = db.insert_value(&map).unwrap();
+            db.insert_node([false].into_iter(), data);
+            db.to_vec().unwrap()
+        };
+
+        let first = build(&mut (0..50));
+        let second = build(&mut (0..50).rev());
+        assert_eq!(first, second);
+
+        let reader = maxminddb::Reader::from_source(first.as_slice()).unwrap();
+        let map: HashMap<String, u32> = reader.lookup([0, 0, 0, 0].into()).unwrap();
+        assert_eq!(map.len(), 50);
+        assert_eq!(map["key42"], 42);
+    }
+
+    fn big_endian_slice<T: AsBigEndianSlice>(value: T) -> Vec<u8> {
+        value.as_big_endian_slice(|slice| slice.to_vec())
+    }
+
+    #[test]
+    fn test_as_big_endian_slice() {
+        assert_eq!(big_endian_slice(0u16), Vec::<u8>::new());
+        assert_eq!(big_endian_slice(0u32), Vec::<u8>::new());
+        assert_eq!(big_endian_slice(0u64), Vec::<u8>::new());
+        assert_eq!(big_endian_slice(0u128), Vec::<u8>::new());
+
+        assert_eq!(big_endian_slice(1u32), vec![0x01]);
+        assert_eq!(big_endian_slice(255u32), vec![0xFF]);
+        assert_eq!(big_endian_slice(256u32), vec![0x01, 0x00]);
+        assert_eq!(big_endian_slice(0x0000_0100u32), vec![0x01, 0x00]);
+        assert_eq!(big_endian_slice(0x0001_0000u32), vec![0x01, 0x00, 0x00]);
+        assert_eq!(
+            big_endian_slice(0x0100_0001u32),
+            vec![0x01, 0x00, 0x00, 0x01]
+        );
+        assert_eq!(big_endian_slice(0x0100u16), vec![0x01, 0x00]);
+        assert_eq!(
+            big_endian_slice(1u64 << 56),
+            vec![0x01, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(big_endian_slice(1u128 << 120).len(), 16);
+        assert_eq!(big_endian_slice(u128::MAX), vec![0xFF; 16]);
+    }
+
+    #[test]
+    fn test_unsigned_boundaries() {
+        for shift in 0..16 {
+            let value = 1u16 << shift;
+            test_pass_through_maxminddb(value);
+            test_pass_through_maxminddb(value - 1);
+        }
+        for shift in 0..32 {
+            let value = 1u32 << shift;
+            test_pass_through_maxminddb(value);
+            test_pass_through_maxminddb(value - 1);
+        }
+        for shift in 0..64 {
+            let value = 1u64 << shift;
+            test_pass_through_maxminddb(value);
+            test_pass_through_maxminddb(value - 1);
+        }
+        for shift in 0..128 {
+            let value = 1u128 << shift;
+            test_pass_through_maxminddb(value);
+            test_pass_through_maxminddb(value - 1);
+        }
+        test_pass_through_maxminddb(u16::MAX);
+        test_pass_through_maxminddb(u32::MAX);
+        test_pass_through_maxminddb(u64::MAX);
+        test_pass_through_maxminddb(u128::MAX);
+    }
+
+    #[test]
+    fn test_none_struct_fields() {
+        #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+        struct Inner {
+            c: Option<u32>,
+        }
+
+        #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+        struct Test {
+            a: Option<u32>,
+            b: Option<String>,
+            inner: Option<Inner>,
+            list: Vec<Option<bool>>,
+        }
+
+        let value = Test {
+            a: None,
+            b: Some("test".to_string()),
+            inner: Some(Inner { c: None }),
+            list: vec![None, Some(true)],
+        };
+        let db = create_minimal_db(&value);
+        let reader = maxminddb::Reader::from_source(db.as_slice()).unwrap();
+
+        #[derive(serde::Deserialize, PartialEq, Debug)]
+        struct Raw {
+            a: Option<u32>,
+            b: String,
+            inner: HashMap<String, u32>,
+            list: Vec<bool>,
+        }
+        let raw: Raw = reader.lookup([0, 0, 0, 0].into()).unwrap();
+        assert_eq!(
+            raw,
+            Raw {
+                a: None,
+                b: "test".to_string(),
+                inner: HashMap::new(),
+                list: vec![false, true],
+            }
+        );
+        let keys: HashMap<String, serde::de::IgnoredAny> =
+            reader.lookup([0, 0, 0, 0].into()).unwrap();
+        assert_eq!(keys.len(), 3);
+        assert!(!keys.contains_key("a"));
+
+        let all_none = Test {
+            a: None,
+            b: None,
+            inner: None,
+            list: vec![],
+        };
+        assert_eq!(serialized(&all_none)[..2], [0b11100001, 0b01000100]);
+        test_pass_through_maxminddb(Test {
+            a: Some(42),
+            b: None,
+            inner: Some(Inner { c: Some(7) }),
+            list: vec![],
+        });
+    }
+
+    struct ClaimedLength {
+        map: bool,
+        len: usize,
+    }
+
+    impl serde::Serialize for ClaimedLength {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::{SerializeMap, SerializeSeq};
+
+            if self.map {
+                serializer.serialize_map(Some(self.len))?.end()
+            } else {
+                serializer.serialize_seq(Some(self.len))?.end()
+            }
+        }
+    }
+
+    #[test]
+    fn test_container_length_out_of_range() {
+        for deterministic in [false, true] {
+            let mut db = Database::default();
+            db.set_deterministic(deterministic);
+            assert!(matches!(
+                db.insert_value(ClaimedLength {
+                    map: true,
+                    len: 20_000_000
+                }),
+                Err(Error::LengthOutOfRange {
+                    data_type: "map",
+                    len: 20_000_000
+                })
+            ));
+            assert!(matches!(
+                db.insert_value(ClaimedLength {
+                    map: false,
+                    len: 16_843_037
+                }),
+                Err(Error::LengthOutOfRange {
+                    data_type: "array",
+                    len: 16_843_037
+                })
+            ));
+        }
+        assert_eq!(
+            Error::LengthOutOfRange {
+                data_type: "map",
+                len: 20_000_000
+            }
+            .to_string(),
+            "Length 20000000 out of range for map"
+        );
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Empty,
+        Circle(u32),
+        Line(u32, u32),
+        Rect { width: u32, height: u32 },
+    }
+
+    /// Reads an enum written with tagged enums enabled, maxminddb hands maps to `deserialize_enum`.
+    #[derive(PartialEq, Debug)]
+    struct Tagged<T>(T);
+
+    impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Tagged<T> {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct Visitor<T>(std::marker::PhantomData<T>);
+
+            impl<'de, T: serde::Deserialize<'de>> serde::de::Visitor<'de> for Visitor<T> {
+                type Value = Tagged<T>;
+
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    write!(f, "an enum variant")
+                }
+
+                fn visit_borrowed_str<E: serde::de::Error>(
+                    self,
+                    v: &'de str,
+                ) -> Result<Self::Value, E> {
+                    T::deserialize(serde::de::value::BorrowedStrDeserializer::new(v)).map(Tagged)
+                }
+
+                fn visit_map<A: serde::de::MapAccess<'de>>(
+                    self,
+                    map: A,
+                ) -> Result<Self::Value, A::Error> {
+                    T::deserialize(serde::de::value::MapAccessDeserializer::new(map)).map(Tagged)
+                }
+            }
+
+            deserializer.deserialize_any(Visitor(std::marker::PhantomData))
+        }
+    }
+
+    #[test]
+    fn test_tagged_enums() {
+        let shapes = [
+            Shape::Empty,
+            Shape::Circle(3),
+            Shape::Line(1, 2),
+            Shape::Rect {
+                width: 4,
+                height: 5,
+            },
+        ];
+
+        for shape in shapes {
+            let mut db = Database::default();
+            db.set_tagged_enums(true);
+            let data = db.insert_value(&shape).unwrap();
+            db.insert_node([false].into_iter(), data);
+            let raw_db = db.to_vec().unwrap();
+
+            let reader = maxminddb::Reader::from_source(raw_db.as_slice()).unwrap();
+            let value: Tagged<Shape> = reader.lookup([0, 0, 0, 0].into()).unwrap();
+            assert_eq!(value.0, shape);
+        }
+
+        // by default the variant name is dropped
+        assert_eq!(serialized(Shape::Circle(3)), serialized(3u32));
+        let tagged = {
+            let mut buf = Vec::new();
+            let mut serializer = Serializer::with_options(
+                &mut buf,
+                Options {
+                    tagged_enums: true,
+                    ..Default::default()
+                },
+            );
+            ser::Serialize::serialize(&Shape::Circle(3), &mut serializer).unwrap();
+            buf
+        };
+        assert_eq!(
+            tagged,
+            [&[0b11100001][..], &serialized("Circle"), &serialized(3u32)].concat()
+        );
+    }
+
+    #[test]
+    fn test_floats() {
+        for value in [0.0f64, -0.0, 1.5, -42.25, f64::MAX, f64::MIN, f64::EPSILON] {
+            test_pass_through_maxminddb(value);
+        }
+        for value in [0.0f32, -0.0, 1.5, -42.25, f32::MAX, f32::MIN, f32::EPSILON] {
+            test_pass_through_maxminddb(value);
+        }
+        // -0.0 == 0.0 so check the sign survives too
+        let db = create_minimal_db(&-0.0f64);
+        let reader = maxminddb::Reader::from_source(db.as_slice()).unwrap();
+        let value: f64 = reader.lookup([0, 0, 0, 0].into()).unwrap();
+        assert!(value.is_sign_negative());
+    }
+
+    #[test]
+    fn test_non_finite_floats() {
+        // by default the IEEE 754 bits are written as they are
+        assert_eq!(
+            serialized(f64::INFINITY),
+            [&[0b01101000][..], &f64::INFINITY.to_be_bytes()].concat()
+        );
+        let db = create_minimal_db(&f64::NAN);
+        let reader = maxminddb::Reader::from_source(db.as_slice()).unwrap();
+        let value: f64 = reader.lookup([0, 0, 0, 0].into()).unwrap();
+        assert!(value.is_nan());
+
+        let strict = Options {
+            strict_floats: true,
+            ..Default::default()
+        };
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let mut serializer = Serializer::with_options(Vec::new(), strict);
+            assert!(matches!(
+                ser::Serialize::serialize(&value, &mut serializer),
+                Err(Error::NonFiniteFloat)
+            ));
+        }
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let mut serializer = Serializer::with_options(Vec::new(), strict);
+            assert!(matches!(
+                ser::Serialize::serialize(&value, &mut serializer),
+                Err(Error::NonFiniteFloat)
+            ));
+        }
+        let mut serializer = Serializer::with_options(Vec::new(), strict);
+        assert!(ser::Serialize::serialize(&f64::MAX, &mut serializer).is_ok());
+
+        let mut db = Database::default();
+        db.set_strict_floats(true);
+        assert!(matches!(
+            db.insert_value(vec![1.0, f64::NAN]),
+            Err(Error::NonFiniteFloat)
+        ));
+        assert!(db.insert_value(vec![1.0, 2.0]).is_ok());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        fn nested(depth: usize) -> DataValue {
+            (0..depth).fold(DataValue::Uint16(1), |value, _| {
+                DataValue::Array(vec![value])
+            })
+        }
+
+        let mut serializer = Serializer::new(Vec::new()).with_max_depth(3);
+        assert!(ser::Serialize::serialize(&nested(3), &mut serializer).is_ok());
+        assert_eq!(serializer.into_inner(), serialized(nested(3)));
+        let mut serializer = Serializer::new(Vec::new()).with_max_depth(3);
+        assert_eq!(
+            ser::Serialize::serialize(&nested(4), &mut serializer),
+            Err(Error::MaxDepthExceeded)
+        );
+
+        // siblings don't add up, only nesting does
+        let mut map = BTreeMap::new();
+        map.insert("a", vec![1u8]);
+        map.insert("b", vec![2u8]);
+        let mut serializer = Serializer::new(Vec::new()).with_max_depth(3);
+        assert!(ser::Serialize::serialize(&vec![map.clone()], &mut serializer).is_ok());
+        let mut serializer = Serializer::new(Vec::new()).with_max_depth(3);
+        assert_eq!(
+            ser::Serialize::serialize(&vec![vec![map]], &mut serializer),
+            Err(Error::MaxDepthExceeded)
+        );
+
+        let mut db = Database::default();
+        db.set_max_depth(Some(2));
+        assert_eq!(db.insert_value(nested(3)), Err(Error::MaxDepthExceeded));
+        assert!(db.insert_value(nested(2)).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_length() {
+        /// Even numbers below `limit`, as a sequence and as a map from their names, without
+        /// telling serde how many there are.
+        struct Evens {
+            limit: u32,
+        }
+
+        impl serde::Serialize for Evens {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let evens = || (0..self.limit).filter(|i| i % 2 == 0);
+                let mut map = serializer.serialize_map(None)?;
+                ser::SerializeMap::serialize_entry(
+                    &mut map,
+                    "list",
+                    &Collected(evens().map(|i| i as u16)),
+                )?;
+                for i in evens() {
+                    ser::SerializeMap::serialize_entry(&mut map, &i.to_string(), &i)?;
+                }
+                ser::SerializeMap::end(map)
+            }
+        }
+
+        struct Collected<I>(I);
+
+        impl<I: Iterator<Item = u16> + Clone> serde::Serialize for Collected<I> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_seq(self.0.clone())
+            }
+        }
+
+        for limit in [0, 5, 100] {
+            let evens = (0..limit).filter(|i| i % 2 == 0).collect::<Vec<u32>>();
+            let value = Evens { limit };
+            let mut expected = vec![("list".to_string(), DataValue::Array(vec![]))];
+            let mut list = Vec::new();
+            for &i in &evens {
+                list.push(DataValue::Uint16(i as u16));
+                expected.push((i.to_string(), DataValue::Uint32(i)));
+            }
+            expected[0].1 = DataValue::Array(list);
+            assert_eq!(
+                serialized(&value),
+                serialized(DataValue::Map(expected.clone()))
+            );
+
+            let raw_db = create_minimal_db(&value);
+            let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+            let read: DataValue = reader.lookup([1, 2, 3, 4].into()).unwrap();
+            assert_eq!(read, DataValue::Map(expected));
+        }
+    }
+
+    #[test]
+    fn test_error_source() {
+        use std::error::Error as _;
+
+        let err = Error::from(std::io::Error::other("disk full"));
+        assert_eq!(err.to_string(), "IO error");
+        assert_eq!(err.source().unwrap().to_string(), "disk full");
+        let err = Error::WhileWriting {
+            section: Section::Metadata,
+            source: std::io::Error::other("disk full"),
+        };
+        // the source is reported separately, not repeated in the message
+        assert_eq!(err.to_string(), "IO error while writing metadata");
+        assert_eq!(err.source().unwrap().to_string(), "disk full");
+        assert!(Error::IntegerOutOfRange.source().is_none());
+    }
+
+    #[test]
+    fn test_error_eq() {
+        let length_out_of_range = |len| Error::LengthOutOfRange {
+            data_type: "string",
+            len,
+        };
+        assert_eq!(length_out_of_range(1 << 25), length_out_of_range(1 << 25));
+        assert_ne!(length_out_of_range(1 << 25), length_out_of_range(1 << 26));
+        assert_eq!(Error::NonFiniteFloat, Error::NonFiniteFloat);
+        assert_ne!(Error::NonFiniteFloat, Error::NonStringMapKey);
+        assert_ne!(Error::NonFiniteFloat, Error::Custom("NaN".to_string()));
+
+        let io = |kind, msg| Error::from(std::io::Error::new(kind, msg));
+        assert_eq!(
+            io(std::io::ErrorKind::WriteZero, "a"),
+            io(std::io::ErrorKind::WriteZero, "b")
+        );
+        assert_ne!(
+            io(std::io::ErrorKind::WriteZero, "a"),
+            io(std::io::ErrorKind::Other, "a")
+        );
+        assert_eq!(
+            Section::Metadata.context(io(std::io::ErrorKind::WriteZero, "a")),
+            Section::Metadata.context(io(std::io::ErrorKind::WriteZero, "b"))
+        );
+        assert_ne!(
+            Section::Metadata.context(io(std::io::ErrorKind::WriteZero, "a")),
+            Section::Nodes.context(io(std::io::ErrorKind::WriteZero, "a"))
+        );
+    }
+}
+
+#[cfg(all(test, not(feature = "std")))]
+mod no_std_tests {
+    use alloc::{vec, vec::Vec};
+
+    use serde::Serialize;
+
+    use super::*;
+
+    #[test]
+    fn test_serialize_to_vec() {
+        #[derive(Serialize)]
+        struct Record {
+            code: &'static str,
+            values: [u16; 2],
+        }
+
+        let mut buf = Vec::new();
+        Record {
+            code: "PL",
+            values: [1, 300],
+        }
+        .serialize(&mut Serializer::new(&mut buf))
+        .unwrap();
+        let mut expected = vec![0xe2, 0x44];
+        expected.extend_from_slice(b"code");
+        expected.extend_from_slice(&[0x42, b'P', b'L', 0x46]);
+        expected.extend_from_slice(b"values");
+        expected.extend_from_slice(&[0x02, 0x04, 0xa1, 0x01, 0xa2, 0x01, 0x2c]);
+        assert_eq!(buf, expected);
+
+        let mut serializer = Serializer::new(Vec::new());
+        assert!(matches!(f64::NAN.serialize(&mut serializer), Ok(())));
+        assert_eq!(serializer.into_inner().len(), 9);
+    }
 }
@@ -0,0 +1,244 @@
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+
+use serde::Serialize;
+
+use crate::{
+    data::{DataRef, Datastore, DATA_SECTION_SEPARATOR_LEN},
+    metadata::{self, IpVersion},
+    node::{Node, NodeRef, Target, SPILLED_NODE_SIZE},
+    paths::IntoBitPath,
+    serializer::{self, Error, Section},
+};
+
+/// Builds a database from networks inserted in sorted order, keeping only the nodes on the
+/// path of the last inserted network in memory.
+///
+/// Nodes which can't change anymore are moved to `storage` (e.g. a scratch `File`), so
+/// databases with search trees larger than memory can be built. Networks have to be
+/// inserted in the order of their bit paths, less specific networks before the more specific
+/// ones within them (which is the order of `(address, prefix length)` pairs). The result is
+/// byte for byte what `Database` produces for the same inserts.
+///
+/// Values are still kept in memory, like in `Database::default`.
+#[derive(Debug)]
+pub struct StreamingBuilder<S> {
+    storage: S,
+    // nodes on the path of the last inserted network, starting at the root
+    spine: Vec<(usize, Node)>,
+    last_path: Vec<bool>,
+    node_count: usize,
+    data: Datastore,
+    pub metadata: metadata::Metadata,
+}
+
+impl<S: Read + Write + Seek> StreamingBuilder<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            spine: vec![(0, Node::default())],
+            last_path: Vec::new(),
+            node_count: 1,
+            data: Datastore::default(),
+            metadata: Default::default(),
+        }
+    }
+
+    /// Inserts a value to the data section, see `Database::insert_value`.
+    pub fn insert_value<T: Serialize>(&mut self, value: T) -> Result<DataRef, Error> {
+        self.data.insert(value, Default::default())
+    }
+
+    /// Makes addresses in the network described by `path` resolve to `data`, see
+    /// `Database::insert_node`.
+    ///
+    /// Fails with `Error::UnsortedNetworks` if `path` comes before the previously inserted
+    /// network and with `Error::IpVersionMismatch` for IPv6 networks in IPv4 databases.
+    /// Panics if `data` was inserted into another builder or database.
+    pub fn insert_node(&mut self, path: impl IntoBitPath, data: DataRef) -> Result<(), Error> {
+        self.data.check_ref(data);
+        if self.metadata.ip_version == IpVersion::V4 && path.ip_version() == Some(IpVersion::V6) {
+            return Err(Error::IpVersionMismatch);
+        }
+        let path = crate::database::tree_path(self.metadata.ip_version, path).collect::<Vec<_>>();
+        if path < self.last_path {
+            return Err(Error::UnsortedNetworks);
+        }
+
+        let Some((&last_bit, bits)) = path.split_last() else {
+            // the default for all addresses, nothing more specific can be inserted before it
+            self.spine[0].1 = Node([Some(Target::Data(data)); 2]);
+            self.last_path = path;
+            return Ok(());
+        };
+        for (depth, &bit) in bits.iter().enumerate() {
+            match self.spine[depth].1[bit] {
+                // networks are sorted, so only nodes on the spine can be followed
+                Some(Target::Node(NodeRef { index })) => {
+                    debug_assert_eq!(self.spine[depth + 1].0, index);
+                }
+                // node points to data (or is empty) -> split the node
+                existing => {
+                    self.store_nodes_below(depth + 1)?;
+                    let index = self.node_count;
+                    self.node_count += 1;
+                    self.spine[depth].1[bit] = Some(Target::Node(NodeRef { index }));
+                    self.spine.push((index, Node([existing, existing])));
+                }
+            }
+        }
+        self.store_nodes_below(bits.len() + 1)?;
+        self.spine[bits.len()].1[last_bit] = Some(Target::Data(data));
+        self.last_path = path;
+        Ok(())
+    }
+
+    /// Moves the spine nodes deeper than `depth` to the storage, they can't change anymore.
+    fn store_nodes_below(&mut self, depth: usize) -> Result<(), std::io::Error> {
+        while self.spine.len() > depth {
+            let (index, node) = self.spine.pop().expect("spine longer than depth");
+            self.storage
+                .seek(SeekFrom::Start((index * SPILLED_NODE_SIZE) as u64))?;
+            self.storage.write_all(&node.to_spilled())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the database to `writer`, see `Database::write_to`.
+    pub fn finish<W: Write>(mut self, mut writer: W) -> Result<W, Error> {
+        self.store_nodes_below(0)?;
+        let max_ptr_value = self.node_count + self.data.len() + DATA_SECTION_SEPARATOR_LEN;
+        crate::database::check_max_ptr_value(max_ptr_value as u64)?;
+        let metadata =
+            crate::database::sized_metadata(&self.metadata, self.node_count, self.data.len());
+
+        // write node tree
+        self.storage.seek(SeekFrom::Start(0))?;
+        let mut stored = BufReader::new(&mut self.storage);
+        let mut bytes = [0; SPILLED_NODE_SIZE];
+        for _ in 0..self.node_count {
+            stored.read_exact(&mut bytes)?;
+            Node::from_spilled(&bytes)
+                .write_to(&mut writer, metadata.record_size, self.node_count)
+                .map_err(|err| Section::Nodes.context(err))?;
+        }
+        // write data section separator
+        writer
+            .write_all(&[0u8; DATA_SECTION_SEPARATOR_LEN])
+            .map_err(|err| Section::DataSection.context(err))?;
+        // write data section
+        self.data
+            .write_to(&mut writer)
+            .map_err(|err| Section::DataSection.context(err))?;
+        // write metadata marker
+        writer
+            .write_all(metadata::METADATA_START_MARKER)
+            .map_err(|err| Section::Metadata.context(err))?;
+        // serialize metadata
+        let mut serializer = serializer::Serializer::new(writer);
+        metadata
+            .serialize(&mut serializer)
+            .map_err(|err| Section::Metadata.context(err))?;
+        Ok(serializer.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{paths::IpAddrWithMask, Database};
+
+    fn build_both(
+        ip_version: IpVersion,
+        networks: &[(&str, &str)],
+    ) -> (Result<Vec<u8>, Error>, Vec<u8>) {
+        let mut streaming = StreamingBuilder::new(Cursor::new(Vec::new()));
+        streaming.metadata.ip_version = ip_version;
+        let mut db = Database::default();
+        db.metadata.ip_version = ip_version;
+        let streamed = (|| {
+            for (network, value) in networks {
+                let network = network.parse::<IpAddrWithMask>().unwrap();
+                let data = streaming.insert_value(value)?;
+                streaming.insert_node(network, data)?;
+                let data = db.insert_value(value).unwrap();
+                db.insert_node(network, data);
+            }
+            streaming.finish(Vec::new())
+        })();
+        (streamed, db.to_vec().unwrap())
+    }
+
+    #[test]
+    fn test_same_as_database() {
+        let networks = [
+            ("1.0.0.0/8", "one"),
+            ("1.2.0.0/16", "one two"),
+            ("1.2.3.0/24", "one two three"),
+            ("1.2.3.0/24", "replaced"),
+            ("1.2.4.0/24", "one two four"),
+            ("1.128.0.0/9", "one"),
+            ("10.0.0.0/8", "ten"),
+            ("192.168.1.1/32", "host"),
+            ("192.168.1.2/31", "hosts"),
+        ];
+        let (streamed, expected) = build_both(IpVersion::V4, &networks);
+        let streamed = streamed.unwrap();
+        assert_eq!(streamed, expected);
+
+        let reader = maxminddb::Reader::from_source(&streamed).unwrap();
+        assert_eq!(
+            reader.lookup::<&str>([1, 2, 3, 4].into()).unwrap(),
+            "replaced"
+        );
+        assert_eq!(
+            reader.lookup::<&str>([1, 2, 5, 4].into()).unwrap(),
+            "one two"
+        );
+        assert_eq!(reader.lookup::<&str>([1, 1, 1, 1].into()).unwrap(), "one");
+
+        let mut networks = networks.to_vec();
+        networks.insert(0, ("0.0.0.0/0", "default"));
+        networks.extend([
+            ("2001:db8::/32", "documentation"),
+            ("fe80::/10", "link local"),
+        ]);
+        let (streamed, expected) = build_both(IpVersion::V6, &networks);
+        assert_eq!(streamed.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_many_networks() {
+        let mut streaming = StreamingBuilder::new(Cursor::new(Vec::new()));
+        let mut db = Database::default();
+        for i in 0..10_000u32 {
+            let network = IpAddrWithMask::new(std::net::Ipv4Addr::from(i * 7919).into(), 32);
+            let value = i % 100;
+            let data = streaming.insert_value(value).unwrap();
+            streaming.insert_node(network, data).unwrap();
+            let data = db.insert_value(value).unwrap();
+            db.insert_node(network, data);
+        }
+        assert!(streaming.spine.len() <= 33);
+        assert_eq!(streaming.finish(Vec::new()).unwrap(), db.to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_unsorted() {
+        for networks in [
+            [
+                ("1.2.0.0/16", "more specific"),
+                ("1.0.0.0/8", "less specific"),
+            ],
+            [("2.0.0.0/8", "two"), ("1.0.0.0/8", "one")],
+            [("1.0.0.0/8", "one"), ("0.0.0.0/0", "default")],
+        ] {
+            let (streamed, _) = build_both(IpVersion::V4, &networks);
+            assert!(matches!(streamed, Err(Error::UnsortedNetworks)));
+        }
+
+        let (streamed, _) = build_both(IpVersion::V4, &[("2001:db8::/32", "documentation")]);
+        assert!(matches!(streamed, Err(Error::IpVersionMismatch)));
+    }
+}
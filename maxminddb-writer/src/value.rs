@@ -0,0 +1,505 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+#[cfg(feature = "std")]
+use std::time::SystemTime;
+
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::{SerializeMap, SerializeSeq},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// Any value which can be stored in the data section, keeping its exact MaxMind DB type so
+/// that it's written back the way it was read.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataValue {
+    String(String),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Uint16(u16),
+    Uint32(u32),
+    /// Entries in the order they were read.
+    Map(Vec<(String, DataValue)>),
+    Int32(i32),
+    Uint64(u64),
+    Uint128(u128),
+    Array(Vec<DataValue>),
+    Boolean(bool),
+    Float(f32),
+}
+
+impl Serialize for DataValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            DataValue::String(value) => serializer.serialize_str(value),
+            DataValue::Double(value) => serializer.serialize_f64(*value),
+            DataValue::Bytes(value) => serializer.serialize_bytes(value),
+            DataValue::Uint16(value) => serializer.serialize_u16(*value),
+            DataValue::Uint32(value) => serializer.serialize_u32(*value),
+            DataValue::Map(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+            DataValue::Int32(value) => serializer.serialize_i32(*value),
+            DataValue::Uint64(value) => serializer.serialize_u64(*value),
+            DataValue::Uint128(value) => serializer.serialize_u128(*value),
+            DataValue::Array(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            DataValue::Boolean(value) => serializer.serialize_bool(*value),
+            DataValue::Float(value) => serializer.serialize_f32(*value),
+        }
+    }
+}
+
+struct DataValueVisitor;
+
+impl<'de> Visitor<'de> for DataValueVisitor {
+    type Value = DataValue;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        formatter.write_str("a MaxMind DB value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(DataValue::Boolean(v))
+    }
+
+    fn visit_i32<E: de::Error>(self, v: i32) -> Result<Self::Value, E> {
+        Ok(DataValue::Int32(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        v.try_into()
+            .map(DataValue::Int32)
+            .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &"a 32 bit integer"))
+    }
+
+    fn visit_u16<E: de::Error>(self, v: u16) -> Result<Self::Value, E> {
+        Ok(DataValue::Uint16(v))
+    }
+
+    fn visit_u32<E: de::Error>(self, v: u32) -> Result<Self::Value, E> {
+        Ok(DataValue::Uint32(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(DataValue::Uint64(v))
+    }
+
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<Self::Value, E> {
+        Ok(DataValue::Uint128(v))
+    }
+
+    fn visit_f32<E: de::Error>(self, v: f32) -> Result<Self::Value, E> {
+        Ok(DataValue::Float(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(DataValue::Double(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(DataValue::String(v.to_string()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(DataValue::Bytes(v.to_vec()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(DataValue::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(DataValue::Map(entries))
+    }
+}
+
+impl<'de> Deserialize<'de> for DataValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DataValueVisitor)
+    }
+}
+
+#[cfg(feature = "std")]
+impl DataValue {
+    /// Decodes the value at `offset` in a data section written by this crate, following
+    /// pointers. Returns the value and the offset right after it.
+    pub(crate) fn decode(
+        data_section: &[u8],
+        offset: usize,
+    ) -> Result<(DataValue, usize), crate::serializer::Error> {
+        Decoder { data_section }.decode(offset, true)
+    }
+}
+
+/// Copies the value at `offset` in a data section written by this crate to `out` as it is,
+/// except for pointers, which are replaced by pointers to `remap(target offset)`, e.g. for
+/// moving the value to another data section. Returns the offset right after the value.
+#[cfg(feature = "std")]
+pub(crate) fn copy_remapped(
+    data_section: &[u8],
+    offset: usize,
+    out: &mut Vec<u8>,
+    remap: &mut dyn FnMut(usize) -> Result<usize, crate::serializer::Error>,
+) -> Result<usize, crate::serializer::Error> {
+    Decoder { data_section }.copy_remapped(offset, out, remap)
+}
+
+#[cfg(feature = "std")]
+struct Decoder<'a> {
+    data_section: &'a [u8],
+}
+
+#[cfg(feature = "std")]
+impl Decoder<'_> {
+    fn invalid(offset: usize) -> crate::serializer::Error {
+        crate::serializer::Error::Custom(alloc::format!("invalid data at offset {}", offset))
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], crate::serializer::Error> {
+        self.data_section
+            .get(offset..offset + len)
+            .ok_or_else(|| Self::invalid(offset))
+    }
+
+    /// Big endian unsigned integer of `len` (at most 16) bytes.
+    fn uint(&self, offset: usize, len: usize) -> Result<u128, crate::serializer::Error> {
+        if len > 16 {
+            return Err(Self::invalid(offset));
+        }
+        Ok(self
+            .bytes(offset, len)?
+            .iter()
+            .fold(0, |value, &byte| (value << 8) | byte as u128))
+    }
+
+    /// Target of the pointer at `offset` and the offset right after the pointer.
+    fn pointer(&self, offset: usize) -> Result<(usize, usize), crate::serializer::Error> {
+        // the size bits are a part of the offset
+        let control = self.bytes(offset, 1)?[0];
+        let pointer_size = ((control >> 3) & 0b11) as usize + 1;
+        let high_bits = (control & 0b111) as usize;
+        let low_bits = self.uint(offset + 1, pointer_size)? as usize;
+        let target = match pointer_size {
+            1 => high_bits << 8 | low_bits,
+            2 => (high_bits << 16 | low_bits) + 2048,
+            3 => (high_bits << 24 | low_bits) + 526_336,
+            _ => low_bits,
+        };
+        Ok((target, offset + 1 + pointer_size))
+    }
+
+    /// Type, size and payload offset of the (non-pointer) value at `offset`.
+    fn header(&self, offset: usize) -> Result<(u8, usize, usize), crate::serializer::Error> {
+        let control = self.bytes(offset, 1)?[0];
+        let mut next = offset + 1;
+        let mut type_id = control >> 5;
+        if type_id == 0 {
+            // extended type
+            type_id = 7 + self.bytes(next, 1)?[0];
+            next += 1;
+        }
+        let size = match control & 0x1f {
+            29 => {
+                next += 1;
+                29 + self.uint(next - 1, 1)? as usize
+            }
+            30 => {
+                next += 2;
+                285 + self.uint(next - 2, 2)? as usize
+            }
+            31 => {
+                next += 3;
+                65_821 + self.uint(next - 3, 3)? as usize
+            }
+            size => size as usize,
+        };
+        Ok((type_id, size, next))
+    }
+
+    fn decode(
+        &self,
+        offset: usize,
+        follow_pointers: bool,
+    ) -> Result<(DataValue, usize), crate::serializer::Error> {
+        if self.bytes(offset, 1)?[0] >> 5 == 1 {
+            if !follow_pointers {
+                return Err(Self::invalid(offset));
+            }
+            let (target, next) = self.pointer(offset)?;
+            let (value, _) = self.decode(target, false)?;
+            return Ok((value, next));
+        }
+        let (type_id, size, mut next) = self.header(offset)?;
+
+        let value = match type_id {
+            2 => {
+                let bytes = self.bytes(next, size)?;
+                let string = core::str::from_utf8(bytes).map_err(|_| Self::invalid(offset))?;
+                DataValue::String(string.to_string())
+            }
+            3 if size == 8 => DataValue::Double(f64::from_bits(self.uint(next, size)? as u64)),
+            4 => DataValue::Bytes(self.bytes(next, size)?.to_vec()),
+            5 if size <= 2 => DataValue::Uint16(self.uint(next, size)? as u16),
+            6 if size <= 4 => DataValue::Uint32(self.uint(next, size)? as u32),
+            7 => {
+                let mut entries = Vec::with_capacity(size);
+                for _ in 0..size {
+                    let (key, after_key) = self.decode(next, true)?;
+                    let DataValue::String(key) = key else {
+                        return Err(Self::invalid(next));
+                    };
+                    let (value, after_value) = self.decode(after_key, true)?;
+                    entries.push((key, value));
+                    next = after_value;
+                }
+                return Ok((DataValue::Map(entries), next));
+            }
+            8 if size <= 4 => {
+                // shorter payloads aren't sign-extended
+                DataValue::Int32(self.uint(next, size)? as u32 as i32)
+            }
+            9 if size <= 8 => DataValue::Uint64(self.uint(next, size)? as u64),
+            10 => DataValue::Uint128(self.uint(next, size)?),
+            11 => {
+                let mut values = Vec::with_capacity(size);
+                for _ in 0..size {
+                    let (value, after) = self.decode(next, true)?;
+                    values.push(value);
+                    next = after;
+                }
+                return Ok((DataValue::Array(values), next));
+            }
+            // the value is the size, there's no payload
+            14 if size <= 1 => return Ok((DataValue::Boolean(size == 1), next)),
+            15 if size == 4 => DataValue::Float(f32::from_bits(self.uint(next, size)? as u32)),
+            _ => return Err(Self::invalid(offset)),
+        };
+        Ok((value, next + size))
+    }
+
+    fn copy_remapped(
+        &self,
+        offset: usize,
+        out: &mut Vec<u8>,
+        remap: &mut dyn FnMut(usize) -> Result<usize, crate::serializer::Error>,
+    ) -> Result<usize, crate::serializer::Error> {
+        if self.bytes(offset, 1)?[0] >> 5 == 1 {
+            let (target, next) = self.pointer(offset)?;
+            crate::serializer::Serializer::new(&mut *out).serialize_pointer(remap(target)?)?;
+            return Ok(next);
+        }
+        let (type_id, size, mut next) = self.header(offset)?;
+        let (payload_len, children) = match type_id {
+            7 => (0, 2 * size),
+            11 => (0, size),
+            // the value is the size, there's no payload
+            14 => (0, 0),
+            _ => (size, 0),
+        };
+        out.extend_from_slice(self.bytes(offset, next - offset + payload_len)?);
+        next += payload_len;
+        for _ in 0..children {
+            next = self.copy_remapped(next, out, remap)?;
+        }
+        Ok(next)
+    }
+}
+
+/// A point in time written as seconds since the Unix epoch (`uint64`), the way MaxMind DB
+/// stores timestamps (e.g. `build_epoch`). Serde writes a bare `SystemTime` as a map readers
+/// don't understand, so wrap timestamp fields in it:
+///
+/// ```
+/// # use std::time::SystemTime;
+/// # use maxminddb_writer::value::Epoch;
+/// #[derive(serde::Serialize)]
+/// struct Record {
+///     updated_at: Epoch,
+/// }
+///
+/// let record = Record {
+///     updated_at: Epoch(SystemTime::now()),
+/// };
+/// ```
+///
+/// Times before the epoch fail to serialize.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Epoch(pub SystemTime);
+
+#[cfg(feature = "std")]
+impl From<SystemTime> for Epoch {
+    fn from(time: SystemTime) -> Self {
+        Self(time)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Serialize for Epoch {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let since_epoch = self
+            .0
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|_| serde::ser::Error::custom("time before the Unix epoch"))?;
+        serializer.serialize_u64(since_epoch.as_secs())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{paths::IpAddrWithMask, Database};
+
+    #[test]
+    fn test_data_value_round_trip() {
+        let value = DataValue::Map(vec![
+            ("string".to_string(), DataValue::String("foo".to_string())),
+            ("double".to_string(), DataValue::Double(1.5)),
+            ("bytes".to_string(), DataValue::Bytes(vec![1, 2, 3])),
+            ("uint16".to_string(), DataValue::Uint16(1)),
+            ("uint32".to_string(), DataValue::Uint32(1)),
+            ("int32".to_string(), DataValue::Int32(-1)),
+            ("uint64".to_string(), DataValue::Uint64(1)),
+            ("uint128".to_string(), DataValue::Uint128(u128::MAX)),
+            (
+                "array".to_string(),
+                DataValue::Array(vec![DataValue::Boolean(true), DataValue::Float(0.5)]),
+            ),
+            ("empty".to_string(), DataValue::Map(vec![])),
+        ]);
+
+        let mut db = Database::default();
+        let data = db.insert_value(&value).unwrap();
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let read: DataValue = reader.lookup([1, 0, 0, 0].into()).unwrap();
+        assert_eq!(read, value);
+
+        // writing what was read gives the same bytes
+        let mut copy = Database::default();
+        let copied = copy.insert_value(&read).unwrap();
+        copy.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), copied);
+        assert_eq!(copy.to_vec().unwrap(), raw_db);
+    }
+
+    #[test]
+    fn test_epoch() {
+        #[derive(Serialize)]
+        struct Record {
+            name: &'static str,
+            updated_at: Epoch,
+        }
+        #[derive(Deserialize)]
+        struct ReadRecord {
+            name: String,
+            updated_at: u64,
+        }
+
+        let updated_at =
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_999);
+        let mut db = Database::default();
+        let data = db
+            .insert_value(Record {
+                name: "test",
+                updated_at: Epoch(updated_at),
+            })
+            .unwrap();
+        db.insert_node("1.0.0.0/8".parse::<IpAddrWithMask>().unwrap(), data);
+        let raw_db = db.to_vec().unwrap();
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let record: ReadRecord = reader.lookup([1, 0, 0, 0].into()).unwrap();
+        assert_eq!(record.name, "test");
+        assert_eq!(record.updated_at, 1_700_000_000);
+        let value: DataValue = reader.lookup([1, 0, 0, 0].into()).unwrap();
+        assert!(matches!(
+            &value,
+            DataValue::Map(entries) if entries[1].1 == DataValue::Uint64(1_700_000_000)
+        ));
+
+        let before_epoch = SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1);
+        assert!(db.insert_value(Epoch(before_epoch)).is_err());
+    }
+
+    /// Lengths around the boundaries of the control byte size encodings.
+    const BOUNDARY_LENGTHS: &[usize] = &[28, 29, 30, 284, 285, 286, 65_820, 65_821, 65_822];
+
+    fn data_value() -> impl proptest::strategy::Strategy<Value = DataValue> {
+        use proptest::prelude::*;
+
+        let string = prop_oneof![
+            ".{0,40}",
+            proptest::sample::select(BOUNDARY_LENGTHS).prop_map(|len| "x".repeat(len)),
+        ];
+        let bytes = prop_oneof![
+            proptest::collection::vec(any::<u8>(), 0..40),
+            proptest::sample::select(BOUNDARY_LENGTHS).prop_map(|len| vec![0xab; len]),
+        ];
+        let leaf = prop_oneof![
+            string.prop_map(DataValue::String),
+            any::<f64>()
+                .prop_filter("NaN", |value| !value.is_nan())
+                .prop_map(DataValue::Double),
+            bytes.prop_map(DataValue::Bytes),
+            any::<u16>().prop_map(DataValue::Uint16),
+            any::<u32>().prop_map(DataValue::Uint32),
+            any::<i32>().prop_map(DataValue::Int32),
+            any::<u64>().prop_map(DataValue::Uint64),
+            any::<u128>().prop_map(DataValue::Uint128),
+            any::<bool>().prop_map(DataValue::Boolean),
+            any::<f32>()
+                .prop_filter("NaN", |value| !value.is_nan())
+                .prop_map(DataValue::Float),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                proptest::collection::vec(inner.clone(), 0..8).prop_map(DataValue::Array),
+                proptest::collection::vec((".{0,8}", inner), 0..8).prop_map(DataValue::Map),
+            ]
+        })
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_data_value_round_trip(value in data_value()) {
+            let mut db = Database::default();
+            let data = db.insert_value(&value).unwrap();
+            db.insert_node("0.0.0.0/0".parse::<IpAddrWithMask>().unwrap(), data);
+            let raw_db = db.to_vec().unwrap();
+            let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+            let read: DataValue = reader.lookup([1, 2, 3, 4].into()).unwrap();
+            proptest::prop_assert_eq!(read, value);
+        }
+
+        #[test]
+        fn prop_integer_round_trip(signed in i32::MIN as i64.., unsigned: u64) {
+            let mut db = Database::default();
+            let data = db.insert_value((signed, unsigned)).unwrap();
+            db.insert_node("0.0.0.0/0".parse::<IpAddrWithMask>().unwrap(), data);
+            let raw_db = db.to_vec().unwrap();
+            let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+            let read: (i64, u64) = reader.lookup([1, 2, 3, 4].into()).unwrap();
+            proptest::prop_assert_eq!(read, (signed, unsigned));
+        }
+    }
+}
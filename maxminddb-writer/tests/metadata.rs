@@ -0,0 +1,62 @@
+use std::collections::{BTreeMap, HashMap};
+
+use maxminddb_writer::{metadata::IpVersion, paths::IpAddrWithMask, Database};
+
+fn written(db: &Database) -> Vec<u8> {
+    db.write_to(Vec::new()).unwrap()
+}
+
+#[test]
+fn metadata_round_trip() {
+    for build_epoch in [0, 1_700_000_000, 1 << 40, u64::MAX] {
+        let mut db = Database::new_v6();
+        db.metadata.database_type = "GeoIP2-City".to_string();
+        db.metadata.ip_version = IpVersion::V6;
+        db.metadata.languages = vec!["en".to_string(), "pl".to_string(), "zh-CN".to_string()];
+        db.metadata.description = HashMap::from([
+            ("en".to_string(), "City database".to_string()),
+            ("pl".to_string(), "Baza miast".to_string()),
+            ("zh-CN".to_string(), "城市数据库".to_string()),
+        ]);
+        db.metadata.binary_format_major_version = 2;
+        db.metadata.binary_format_minor_version = 1;
+        db.metadata.build_epoch = build_epoch;
+        let data = db.insert_value("data").unwrap();
+        db.insert_node("1.2.3.0/24".parse::<IpAddrWithMask>().unwrap(), data);
+        db.insert_node("2001:db8::/32".parse::<IpAddrWithMask>().unwrap(), data);
+
+        let raw_db = written(&db);
+        let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+        let metadata = &reader.metadata;
+        assert_eq!(metadata.database_type, "GeoIP2-City");
+        assert_eq!(metadata.ip_version, 6);
+        assert_eq!(metadata.languages, ["en", "pl", "zh-CN"]);
+        assert_eq!(
+            metadata.description,
+            BTreeMap::from([
+                ("en".to_string(), "City database".to_string()),
+                ("pl".to_string(), "Baza miast".to_string()),
+                ("zh-CN".to_string(), "城市数据库".to_string()),
+            ])
+        );
+        assert_eq!(metadata.binary_format_major_version, 2);
+        assert_eq!(metadata.binary_format_minor_version, 1);
+        assert_eq!(metadata.build_epoch, build_epoch);
+        assert_eq!(metadata.node_count as usize, db.stats().unwrap().node_count);
+        assert_eq!(metadata.record_size, 24);
+    }
+}
+
+#[test]
+fn default_metadata_round_trip() {
+    let db = Database::default();
+    let raw_db = written(&db);
+    let reader = maxminddb::Reader::from_source(&raw_db).unwrap();
+    let metadata = &reader.metadata;
+    assert_eq!(metadata.database_type, "");
+    assert_eq!(metadata.ip_version, 4);
+    assert!(metadata.languages.is_empty());
+    assert!(metadata.description.is_empty());
+    assert_eq!(metadata.build_epoch, 0);
+    assert_eq!(metadata.node_count, 1);
+}